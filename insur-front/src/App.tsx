@@ -94,7 +94,13 @@ function App() {
             >
               <option value="Unknown">--Choose Operator--</option>
               <option value="Sum">Sum</option>
+              <option value="Sub">Sub</option>
               <option value="Mul">Mul</option>
+              <option value="Div">Div</option>
+              <option value="ExactDiv">ExactDiv</option>
+              <option value="LessThan">LessThan</option>
+              <option value="GreaterThan">GreaterThan</option>
+              <option value="Eq">Eq</option>
             </select>
             <br></br>
             <select
@@ -1,4 +1,12 @@
-type BinaryOperator = "Add" | "Mul" | "Div" | "Sub";
+type BinaryOperator =
+  | "Sum"
+  | "Mul"
+  | "Div"
+  | "ExactDiv"
+  | "Sub"
+  | "LessThan"
+  | "GreaterThan"
+  | "Eq";
 
 type BinaryOp = {
   left: Expression;
@@ -14,6 +22,8 @@ type Number = {
   number: number;
 };
 
+// Decimals, negation, powers, let bindings and if-then-else are written in the
+// `formula` field instead, the form only builds binary operations
 export type Expression =
   | { number: Number }
   | { binOp: BinaryOp }
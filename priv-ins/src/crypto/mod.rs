@@ -1,3 +1,5 @@
+#![allow(clippy::too_many_arguments)]
+
 #[derive(PrimeField)]
 #[PrimeFieldModulus = "52435875175126190479447740508185965837690552500527637822603658699938581184513"]
 #[PrimeFieldGenerator = "7"]
@@ -5,3 +7,19 @@
 pub struct Fp([u64; 4]);
 
 pub mod shares;
+
+/// Elements are sent as their canonical little endian representation
+impl serde::Serialize for Fp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ff::PrimeField::to_repr(self).0.serialize(serializer)
+    }
+}
+
+/// Rejects representations of numbers not smaller than the modulus
+impl<'de> serde::Deserialize<'de> for Fp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = <[u8; 32]>::deserialize(deserializer)?;
+        Option::from(<Fp as ff::PrimeField>::from_repr(FpRepr(bytes)))
+            .ok_or_else(|| serde::de::Error::custom("non-canonical field element"))
+    }
+}
@@ -3,18 +3,29 @@ use ff::{Field, PrimeField};
 use rand;
 use rand::Rng;
 use sha3::{Digest, Sha3_256};
+use std::cmp::Ordering;
 use std::ops::Sub;
 
+/// Number of bits of statistical security used when masking values that are opened bitwise
+pub const STATISTICAL_SECURITY: u32 = 40;
+
 pub type Elem = Fp;
 pub type Elems = Vec<Elem>;
 pub type Share = (Elem, Elem);
 pub type Shares = Vec<Share>;
 pub type BeaverShare = (Share, Share, Share);
+/// shares of random bits r_0..r_{k-1} together with share of r = 2^k * r' + sum 2^i * r_i
+/// where r' is a random statistical mask
+pub type BitMaskShare = (Shares, Share);
+/// share of random r' < 2^k together with share of r = 2^k * r'' + r'
+/// where r'' is random mask big enough to statistically hide truncated value
+pub type TruncationMaskShare = (Share, Share);
 
 pub type Hash = [u8; 32];
 pub type Salt = Vec<u8>;
 pub type Commitment = Hash;
-pub type CommitmentProof = (Commitment, Elem, Salt);
+/// commitment to elements of all rows of a batch, elements and salt opening it
+pub type CommitmentProof = (Commitment, Elems, Salt);
 
 /// Generates a vector Elems for `secret` for `n_parties`
 pub fn elems_from_secret(secret: &Elem, n_parties: u8) -> Elems {
@@ -67,13 +78,164 @@ pub fn random_beaver(alpha: &Elems, n_parties: u8) -> Vec<BeaverShare> {
         .collect()
 }
 
+/// Generates a random vector of BitMaskShare with given shares of alpha for `n_parties`.
+/// Every party gets shares of the same `n_bits` random bits and of the same mask r built from them.
+pub fn random_bit_mask(alpha: &Elems, n_parties: u8, n_bits: u32) -> Vec<BitMaskShare> {
+    let bits = (0..n_bits)
+        .map(|_| Elem::from(rand::thread_rng().gen::<bool>() as u64))
+        .collect::<Vec<_>>();
+    let high = Elem::from(rand::thread_rng().gen::<u64>() >> (64 - STATISTICAL_SECURITY));
+
+    let r = bits.iter().rev().fold(high, |acc, bit| acc.double() + bit);
+
+    let bit_shares = bits
+        .iter()
+        .map(|bit| shares_from_secret(bit, alpha, n_parties))
+        .collect::<Vec<_>>();
+
+    shares_from_secret(&r, alpha, n_parties)
+        .into_iter()
+        .enumerate()
+        .map(|(i, r_share)| (bit_shares.iter().map(|s| s[i]).collect(), r_share))
+        .collect()
+}
+
+/// Returns random element with `n_bits` bits
+fn random_bits(n_bits: u32) -> Elem {
+    (0..n_bits).step_by(64).fold(Elem::zero(), |acc, i| {
+        let chunk = (n_bits - i).min(64);
+        let r = rand::thread_rng().gen::<u64>() >> (64 - chunk);
+        acc * pow2(chunk) + Elem::from(r)
+    })
+}
+
+/// Generates a random vector of TruncationMaskShare with given shares of alpha for `n_parties`.
+/// Mask hides values of `value_bits` bits that are truncated by `n_bits` bits.
+pub fn random_truncation_mask(
+    alpha: &Elems,
+    n_parties: u8,
+    n_bits: u32,
+    value_bits: u32,
+) -> Vec<TruncationMaskShare> {
+    let low = random_bits(n_bits);
+    let high = random_bits(value_bits + STATISTICAL_SECURITY - n_bits);
+    let r = high * pow2(n_bits) + low;
+
+    shares_from_secret(&low, alpha, n_parties)
+        .into_iter()
+        .zip(shares_from_secret(&r, alpha, n_parties))
+        .collect()
+}
+
+/// Returns `n_bits` least significant bits of `elem`, least significant first
+pub fn elem_bits(elem: &Elem, n_bits: u32) -> Vec<bool> {
+    let repr = elem.to_repr();
+    (0..n_bits as usize)
+        .map(|i| (repr.0[i / 8] >> (i % 8)) & 1 == 1)
+        .collect()
+}
+
+/// Returns `elem` mod 2^n_bits
+pub fn low_bits(elem: &Elem, n_bits: u32) -> Elem {
+    elem_bits(elem, n_bits)
+        .iter()
+        .rev()
+        .fold(Elem::zero(), |acc, bit| {
+            acc.double() + Elem::from(*bit as u64)
+        })
+}
+
+/// Divides `elem` treated as signed integer (see `is_negative`) by 2^n_bits, rounding towards zero
+pub fn truncate(elem: &Elem, n_bits: u32) -> Elem {
+    if is_negative(elem) {
+        return -truncate(&-*elem, n_bits);
+    }
+
+    elem_bits(elem, Elem::NUM_BITS)
+        .iter()
+        .skip(n_bits as usize)
+        .rev()
+        .fold(Elem::zero(), |acc, bit| {
+            acc.double() + Elem::from(*bit as u64)
+        })
+}
+
+/// Returns element equal to `value`
+pub fn elem_from_u128(value: u128) -> Elem {
+    Elem::from((value >> 64) as u64) * pow2(64) + Elem::from(value as u64)
+}
+
+/// Reduces 256 bit little endian `hash` modulo p
+pub fn elem_from_hash(hash: &Hash) -> Elem {
+    let low = u128::from_le_bytes(hash[..16].try_into().expect("16 bytes"));
+    let high = u128::from_le_bytes(hash[16..].try_into().expect("16 bytes"));
+    elem_from_u128(high) * pow2(128) + elem_from_u128(low)
+}
+
+/// Returns element encoding `value`, negative values are encoded as p - |value|
+pub fn elem_from_i128(value: i128) -> Elem {
+    let magnitude = elem_from_u128(value.unsigned_abs());
+    if value < 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Decodes `elem` as signed integer (see `is_negative`), returns None if it does not fit into i128
+pub fn elem_to_i128(elem: &Elem) -> Option<i128> {
+    if is_negative(elem) {
+        return elem_to_i128(&-*elem).map(|v| -v);
+    }
+
+    let repr = elem.to_repr();
+    if repr.0[16..].iter().any(|byte| *byte != 0) {
+        return None;
+    }
+
+    i128::try_from(u128::from_le_bytes(
+        repr.0[..16].try_into().expect("16 bytes"),
+    ))
+    .ok()
+}
+
+/// Compares two elements as integers in range [0, p)
+pub fn cmp_elems(a: &Elem, b: &Elem) -> Ordering {
+    a.to_repr().0.iter().rev().cmp(b.to_repr().0.iter().rev())
+}
+
+/// Returns 2^exp as an element
+pub fn pow2(exp: u32) -> Elem {
+    Elem::from(2).pow_vartime([exp as u64])
+}
+
+/// Element is treated as negative if it lies in the upper half of the field, i.e. above (p - 1) / 2
+pub fn is_negative(elem: &Elem) -> bool {
+    let half = -Elem::one() * Elem::from(2).invert().unwrap();
+    cmp_elems(elem, &half) == Ordering::Greater
+}
+
 pub fn sum_elems(elems: &Elems) -> Elem {
     elems.iter().fold(Elem::zero(), |a, &b| a + b)
 }
 
-/// Computes commitment Hash which is H(elem || salt)
-pub fn compute_commitment(elem: &Elem, salt: &Salt) -> Hash {
-    hash(&[&elem.to_repr().0[..], salt].concat())
+/// Computes commitment Hash which is H(elem_0 || ... || elem_{n-1} || salt)
+pub fn compute_commitment(elems: &[Elem], salt: &Salt) -> Hash {
+    let mut bytes = elems
+        .iter()
+        .flat_map(|elem| elem.to_repr().0)
+        .collect::<Vec<_>>();
+    bytes.extend_from_slice(salt);
+    hash(&bytes)
+}
+
+/// Hashes shares of all rows, lets nodes compare shares they received from the same node
+pub fn hash_shares(shares: &[Share]) -> Hash {
+    let bytes = shares
+        .iter()
+        .flat_map(|(value, mac)| value.to_repr().0.into_iter().chain(mac.to_repr().0))
+        .collect::<Vec<_>>();
+    hash(&bytes)
 }
 
 pub fn random_salt() -> Salt {
@@ -133,4 +295,78 @@ mod tests {
             Elem::from(420) * sum_elems(&beaver_shares.iter().map(|(_, _, c)| c.1).collect()),
         );
     }
+
+    #[test]
+    fn generate_bit_mask_correctly() {
+        let n_parties = 10;
+        let n_bits = 16;
+        let alpha = elems_from_secret(&Elem::from(420), n_parties);
+        let masks = random_bit_mask(&alpha, n_parties, n_bits);
+
+        assert_eq!(n_parties as usize, masks.len());
+
+        let bits = (0..n_bits as usize)
+            .map(|i| sum_elems(&masks.iter().map(|(bits, _)| bits[i].0).collect()))
+            .collect::<Vec<_>>();
+        let r = sum_elems(&masks.iter().map(|(_, r)| r.0).collect());
+
+        for bit in bits.iter() {
+            assert!(*bit == Elem::zero() || *bit == Elem::one());
+        }
+        let r_bits = elem_bits(&r, n_bits);
+        for (bit, r_bit) in bits.iter().zip(r_bits) {
+            assert_eq!(*bit, Elem::from(r_bit as u64));
+        }
+        assert_eq!(
+            Elem::from(420) * r,
+            sum_elems(&masks.iter().map(|(_, r)| r.1).collect())
+        );
+        assert_eq!(
+            Ordering::Less,
+            cmp_elems(&r, &pow2(n_bits + STATISTICAL_SECURITY))
+        );
+    }
+
+    #[test]
+    fn decides_sign_of_elems() {
+        assert!(!is_negative(&Elem::zero()));
+        assert!(!is_negative(&Elem::from(u64::MAX)));
+        assert!(is_negative(&-Elem::one()));
+        assert!(is_negative(&-Elem::from(u64::MAX)));
+    }
+
+    #[test]
+    fn generate_truncation_mask_correctly() {
+        let n_parties = 3;
+        let alpha = elems_from_secret(&Elem::from(420), n_parties);
+        let masks = random_truncation_mask(&alpha, n_parties, 16, 128);
+
+        let low = sum_elems(&masks.iter().map(|(low, _)| low.0).collect());
+        let r = sum_elems(&masks.iter().map(|(_, r)| r.0).collect());
+
+        assert_eq!(low, low_bits(&low, 16));
+        assert_eq!(low, low_bits(&r, 16));
+        assert_eq!(r, low_bits(&r, 128 + STATISTICAL_SECURITY));
+    }
+
+    #[test]
+    fn truncates_signed_elems() {
+        assert_eq!(Elem::from(3), truncate(&Elem::from(13), 2));
+        assert_eq!(-Elem::from(3), truncate(&-Elem::from(13), 2));
+        assert_eq!(
+            elem_from_u128(u128::MAX >> 27),
+            truncate(&elem_from_u128(u128::MAX), 27)
+        );
+        assert_eq!(Elem::from(5), low_bits(&Elem::from(13), 3));
+    }
+
+    #[test]
+    fn encodes_signed_integers() {
+        for value in [0, 1, -1, 42, -42, i64::MAX as i128, i128::MAX, -i128::MAX] {
+            assert_eq!(Some(value), elem_to_i128(&elem_from_i128(value)));
+        }
+        assert_eq!(-Elem::from(7), elem_from_i128(-7));
+        assert_eq!(None, elem_to_i128(&pow2(127)));
+        assert_eq!(None, elem_to_i128(&-pow2(200)));
+    }
 }
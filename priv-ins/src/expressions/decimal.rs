@@ -0,0 +1,174 @@
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+/// Decimal number `mantissa / 10^scale`, e.g. rate `1.75` is (175, 2).
+/// Always kept without trailing zeros in fractional part so equal numbers compare equal.
+/// Serialized as a string to not lose precision in json.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Decimal {
+    mantissa: i128,
+    scale: u32,
+}
+
+impl Decimal {
+    pub fn new(mut mantissa: i128, mut scale: u32) -> Self {
+        while scale > 0 && mantissa % 10 == 0 {
+            mantissa /= 10;
+            scale -= 1;
+        }
+
+        Self { mantissa, scale }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.scale == 0
+    }
+
+    /// Returns round(self * 2^bits) or None if it does not fit
+    pub fn to_fixed(self, bits: u32) -> Option<i128> {
+        let denominator = 10u128.checked_pow(self.scale)?;
+        let shifted = self
+            .mantissa
+            .unsigned_abs()
+            .checked_mul(1u128.checked_shl(bits)?)?;
+        let rounded =
+            shifted / denominator + (shifted % denominator >= denominator.div_ceil(2)) as u128;
+
+        with_sign(rounded, self.mantissa < 0)
+    }
+
+    /// Decodes fixed point `value / 2^bits`, rounded to as many decimal digits
+    /// as the fixed point value is precise, so that e.g. encoded 0.1 is decoded back to 0.1.
+    /// Returns None if it does not fit.
+    pub fn from_fixed(value: i128, bits: u32) -> Option<Self> {
+        let digits = (0..)
+            .take_while(|d| 10u128.pow(*d) <= 1 << bits)
+            .last()
+            .expect("exists");
+        let unit = 10u128.pow(digits);
+
+        let magnitude = value.unsigned_abs();
+        let integer = magnitude >> bits;
+        let fraction = magnitude & ((1 << bits) - 1);
+        let fraction = (fraction * unit + (1 << bits >> 1)) >> bits;
+
+        let mantissa = integer.checked_mul(unit)?.checked_add(fraction)?;
+        Some(Self::new(with_sign(mantissa, value < 0)?, digits))
+    }
+}
+
+fn with_sign(magnitude: u128, negative: bool) -> Option<i128> {
+    let value = i128::try_from(magnitude).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+impl From<i64> for Decimal {
+    fn from(value: i64) -> Self {
+        Self::new(value as i128, 0)
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.scale == 0 {
+            return write!(f, "{}", self.mantissa);
+        }
+
+        let unit = 10u128.pow(self.scale);
+        let magnitude = self.mantissa.unsigned_abs();
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            if self.mantissa < 0 { "-" } else { "" },
+            magnitude / unit,
+            magnitude % unit,
+            width = self.scale as usize
+        )
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (integer, fraction) = match s.split_once('.') {
+            Some((integer, fraction)) => (integer, fraction),
+            None => (s, ""),
+        };
+
+        let all_digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+        if !all_digits(integer) || (s.contains('.') && !all_digits(fraction)) {
+            return Err(format!("invalid decimal `{}`", s));
+        }
+
+        let mantissa = format!("{}{}", integer, fraction)
+            .parse::<i128>()
+            .map_err(|_| format!("decimal `{}` is too big", s))?;
+
+        Ok(Self::new(mantissa, fraction.len() as u32))
+    }
+}
+
+impl TryFrom<String> for Decimal {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Decimal> for String {
+    fn from(value: Decimal) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_prints() {
+        for (input, output) in [
+            ("12", "12"),
+            ("1.75", "1.75"),
+            ("0.0175", "0.0175"),
+            ("2.50", "2.5"),
+            ("3.000", "3"),
+        ] {
+            assert_eq!(output, input.parse::<Decimal>().unwrap().to_string());
+        }
+
+        for input in ["", ".5", "1.", "1.2.3", "1e5", "-1"] {
+            assert!(input.parse::<Decimal>().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn converts_to_and_from_fixed_point() {
+        let rate = "1.75".parse::<Decimal>().unwrap();
+        assert_eq!(Some(7 << 14), rate.to_fixed(16));
+        assert_eq!(Some(rate), Decimal::from_fixed(7 << 14, 16));
+
+        // 0.1 is not representable, it rounds to the nearest value
+        let tenth = "0.1".parse::<Decimal>().unwrap();
+        assert_eq!(Some(6554), tenth.to_fixed(16));
+        assert_eq!(Some(tenth), Decimal::from_fixed(6554, 16));
+        assert_eq!("0.2", Decimal::from_fixed(13, 6).unwrap().to_string());
+
+        assert_eq!(Some(Decimal::from(42)), Decimal::from_fixed(42 << 8, 8));
+        assert_eq!(None, Decimal::from(i64::MAX).to_fixed(96));
+    }
+
+    #[test]
+    fn handles_negative_numbers() {
+        let refund = Decimal::new(-1250, 3);
+        assert_eq!("-1.25", refund.to_string());
+        assert_eq!("-0.5", Decimal::new(-5, 1).to_string());
+
+        assert_eq!(Some(-(5 << 14)), refund.to_fixed(16));
+        assert_eq!(Some(refund), Decimal::from_fixed(-(5 << 14), 16));
+        assert_eq!(Some(Decimal::from(-3)), Decimal::from_fixed(-3, 0));
+    }
+}
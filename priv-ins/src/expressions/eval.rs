@@ -0,0 +1,484 @@
+use crate::crypto::shares::{self, Elem};
+use crate::expressions::{decimal::Decimal, BinaryOp, Expression, Outputs};
+use crate::protocol::{check_fractional_bits, floor_div, DIVISION_BITS};
+use ff::Field;
+use std::collections::HashMap;
+
+/// Values expressions can be evaluated over in plaintext. Arithmetic wraps around
+/// the modulus, values in its upper half are treated as negative numbers.
+pub trait Plaintext: Copy + PartialEq {
+    fn from_i128(value: i128) -> Self;
+    /// Returns None if value does not fit into i128
+    fn to_i128(&self) -> Option<i128>;
+    fn wrapping_add(self, other: Self) -> Self;
+    fn wrapping_sub(self, other: Self) -> Self;
+    fn wrapping_mul(self, other: Self) -> Self;
+    fn wrapping_neg(self) -> Self;
+    /// Divides integers by nonzero divisor rounding down
+    fn floor_div(self, divisor: Self) -> Result<Self, String> {
+        let dividend = self.to_i128().ok_or("dividend is too big".to_string())?;
+        let divisor = divisor.to_i128().ok_or("divisor is too big".to_string())?;
+        if divisor == 0 {
+            return Err("division by zero".to_string());
+        }
+
+        Ok(Self::from_i128(floor_div(dividend, divisor)))
+    }
+    /// Divides by nonzero divisor, which has to divide value if the modulus is not prime
+    fn exact_div(self, divisor: Self) -> Result<Self, String>;
+    /// Divides by 2^n_bits rounding towards zero
+    fn truncate(self, n_bits: u32) -> Self;
+    fn is_negative(&self) -> bool;
+}
+
+/// Integers modulo 2^64
+impl Plaintext for u64 {
+    fn from_i128(value: i128) -> Self {
+        value as u64
+    }
+
+    fn to_i128(&self) -> Option<i128> {
+        Some(*self as i64 as i128)
+    }
+
+    fn wrapping_add(self, other: Self) -> Self {
+        u64::wrapping_add(self, other)
+    }
+
+    fn wrapping_sub(self, other: Self) -> Self {
+        u64::wrapping_sub(self, other)
+    }
+
+    fn wrapping_mul(self, other: Self) -> Self {
+        u64::wrapping_mul(self, other)
+    }
+
+    fn wrapping_neg(self) -> Self {
+        u64::wrapping_neg(self)
+    }
+
+    fn exact_div(self, divisor: Self) -> Result<Self, String> {
+        let (a, b) = (self as i64, divisor as i64);
+        if b == 0 {
+            return Err("division by zero".to_string());
+        }
+        if a.wrapping_rem(b) != 0 {
+            return Err(format!("{} is not divisible by {}", a, b));
+        }
+
+        Ok(a.wrapping_div(b) as u64)
+    }
+
+    fn truncate(self, n_bits: u32) -> Self {
+        ((self as i64 as i128) / (1i128 << n_bits.min(127))) as u64
+    }
+
+    fn is_negative(&self) -> bool {
+        (*self as i64) < 0
+    }
+}
+
+/// Elements of the field shares live in, computed the same way as by the protocol
+impl Plaintext for Elem {
+    fn from_i128(value: i128) -> Self {
+        shares::elem_from_i128(value)
+    }
+
+    fn to_i128(&self) -> Option<i128> {
+        shares::elem_to_i128(self)
+    }
+
+    fn wrapping_add(self, other: Self) -> Self {
+        self + other
+    }
+
+    fn wrapping_sub(self, other: Self) -> Self {
+        self - other
+    }
+
+    fn wrapping_mul(self, other: Self) -> Self {
+        self * other
+    }
+
+    fn wrapping_neg(self) -> Self {
+        -self
+    }
+
+    fn exact_div(self, divisor: Self) -> Result<Self, String> {
+        Option::<Elem>::from(divisor.invert())
+            .map(|inverse| self * inverse)
+            .ok_or("division by zero".to_string())
+    }
+
+    fn truncate(self, n_bits: u32) -> Self {
+        shares::truncate(&self, n_bits)
+    }
+
+    fn is_negative(&self) -> bool {
+        shares::is_negative(self)
+    }
+}
+
+/// Plaintext value together with information whether it is a fixed point number
+#[derive(Clone, Copy)]
+struct Typed<V> {
+    value: V,
+    fixed: bool,
+}
+
+impl<V: Plaintext> Typed<V> {
+    fn integer(value: V) -> Self {
+        Self {
+            value,
+            fixed: false,
+        }
+    }
+
+    fn bit(bit: bool) -> Self {
+        Self::integer(V::from_i128(bit as i128))
+    }
+}
+
+/// Evaluates expressions in plaintext with the same encoding of fixed point numbers
+/// as the protocol. Truncations round towards zero, while the protocol rounds up or down
+/// at random, so fixed point results may differ from the protocol by a few units in the last place.
+struct Evaluator<'a, V> {
+    variables: &'a [HashMap<String, i64>],
+    fractional_bits: u32,
+    /// values of names bound by enclosing lets, innermost last
+    bindings: Vec<(String, Typed<V>)>,
+}
+
+impl<V: Plaintext> Evaluator<'_, V> {
+    fn one(&self) -> V {
+        pow2(self.fractional_bits)
+    }
+
+    fn variable(&self, name: &str) -> Result<Typed<V>, String> {
+        if let Some((_, value)) = self.bindings.iter().rev().find(|(bound, _)| bound == name) {
+            return Ok(*value);
+        }
+
+        self.variables
+            .iter()
+            .find_map(|values| values.get(name))
+            .map(|value| Typed::integer(V::from_i128(*value as i128)))
+            .ok_or("orphaned variable".to_string())
+    }
+
+    fn to_fixed(&self, x: Typed<V>) -> V {
+        if x.fixed {
+            x.value
+        } else {
+            x.value.wrapping_mul(self.one())
+        }
+    }
+
+    /// Brings operands to the same encoding, returns whether it is fixed point
+    fn aligned(&self, left: Typed<V>, right: Typed<V>) -> (V, V, bool) {
+        if left.fixed || right.fixed {
+            (self.to_fixed(left), self.to_fixed(right), true)
+        } else {
+            (left.value, right.value, false)
+        }
+    }
+
+    fn multiply(&self, left: Typed<V>, right: Typed<V>) -> Typed<V> {
+        let product = left.value.wrapping_mul(right.value);
+        if left.fixed && right.fixed {
+            return Typed {
+                value: product.truncate(self.fractional_bits),
+                fixed: true,
+            };
+        }
+
+        Typed {
+            value: product,
+            fixed: left.fixed || right.fixed,
+        }
+    }
+
+    fn divide(&self, left: Typed<V>, right: Typed<V>) -> Result<Typed<V>, String> {
+        if !left.fixed && !right.fixed {
+            return Ok(Typed::integer(left.value.floor_div(right.value)?));
+        }
+
+        let divisor = right
+            .value
+            .to_i128()
+            .ok_or("divisor is too big".to_string())?;
+        if divisor == 0 {
+            return Err("division by zero".to_string());
+        }
+        let divisor_bits = if right.fixed { self.fractional_bits } else { 0 };
+        let numerator = 1i128 << (DIVISION_BITS + divisor_bits);
+        let reciprocal = (numerator + divisor.abs() / 2) / divisor.abs() * divisor.signum();
+
+        Ok(Typed {
+            value: self
+                .to_fixed(left)
+                .wrapping_mul(V::from_i128(reciprocal))
+                .truncate(DIVISION_BITS),
+            fixed: true,
+        })
+    }
+
+    /// Square and multiply, fixed point products are truncated after each multiplication
+    fn power(&self, base: Typed<V>, exponent: u32) -> Typed<V> {
+        let mut result = None;
+        let mut square = base;
+        for i in 0..(u32::BITS - exponent.leading_zeros()) {
+            if i > 0 {
+                square = self.multiply(square, square);
+            }
+            if (exponent >> i) & 1 == 1 {
+                result = Some(match result {
+                    None => square,
+                    Some(result) => self.multiply(result, square),
+                });
+            }
+        }
+
+        result.unwrap_or(Typed {
+            value: if base.fixed {
+                self.one()
+            } else {
+                V::from_i128(1)
+            },
+            fixed: base.fixed,
+        })
+    }
+
+    fn evaluate(&mut self, expr: &Expression<u64>) -> Result<Typed<V>, String> {
+        match expr {
+            Expression::Number { number } => Ok(Typed::integer(V::from_i128(*number as i128))),
+            Expression::Decimal { decimal } => self.decimal(decimal),
+            Expression::BinOp { left, right, op } => {
+                let left = self.evaluate(left)?;
+                let right = self.evaluate(right)?;
+
+                match op {
+                    BinaryOp::Add | BinaryOp::Sub => {
+                        let (left, right, fixed) = self.aligned(left, right);
+                        let value = match op {
+                            BinaryOp::Add => left.wrapping_add(right),
+                            _ => left.wrapping_sub(right),
+                        };
+                        Ok(Typed { value, fixed })
+                    }
+                    BinaryOp::Mul => Ok(self.multiply(left, right)),
+                    BinaryOp::Div => self.divide(left, right),
+                    BinaryOp::ExactDiv if left.fixed || right.fixed => {
+                        Err("exact division `/!` needs integer operands".to_string())
+                    }
+                    BinaryOp::ExactDiv => Ok(Typed::integer(left.value.exact_div(right.value)?)),
+                    BinaryOp::LessThan | BinaryOp::GreaterThan | BinaryOp::Eq => {
+                        let (left, right, _) = self.aligned(left, right);
+                        Ok(Typed::bit(match op {
+                            BinaryOp::LessThan => left.wrapping_sub(right).is_negative(),
+                            BinaryOp::GreaterThan => right.wrapping_sub(left).is_negative(),
+                            _ => left == right,
+                        }))
+                    }
+                }
+            }
+            Expression::Variable { name } => self.variable(name),
+            Expression::Neg { expr } => {
+                let Typed { value, fixed } = self.evaluate(expr)?;
+                Ok(Typed {
+                    value: value.wrapping_neg(),
+                    fixed,
+                })
+            }
+            Expression::Pow { base, exponent } => {
+                let base = self.evaluate(base)?;
+                Ok(self.power(base, *exponent))
+            }
+            Expression::Let { name, value, body } => {
+                let value = self.evaluate(value)?;
+                self.bindings.push((name.clone(), value));
+                let result = self.evaluate(body);
+                self.bindings.pop();
+
+                result
+            }
+            Expression::Select {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let cond = self.evaluate(cond)?;
+                if cond.fixed {
+                    return Err("condition of select has to be an integer".to_string());
+                }
+
+                if cond.value == V::from_i128(1) {
+                    self.evaluate(then)
+                } else if cond.value == V::from_i128(0) {
+                    self.evaluate(otherwise)
+                } else {
+                    Err("condition of select is neither 0 nor 1".to_string())
+                }
+            }
+        }
+    }
+
+    fn decimal(&self, decimal: &Decimal) -> Result<Typed<V>, String> {
+        if self.fractional_bits == 0 {
+            return Err("decimal numbers need fractional bits".to_string());
+        }
+        let value = decimal
+            .to_fixed(self.fractional_bits)
+            .ok_or(format!("decimal {} is too big", decimal))?;
+
+        Ok(Typed {
+            value: V::from_i128(value),
+            fixed: true,
+        })
+    }
+}
+
+fn pow2<V: Plaintext>(exp: u32) -> V {
+    (0..exp).fold(V::from_i128(1), |acc, _| acc.wrapping_add(acc))
+}
+
+/// Evaluates expression in plaintext with variables of all nodes, `variables` are indexed
+/// by node id as in `run_nodes`. Returns value together with its number of fractional bits,
+/// so that it can be decoded with `Output::decode` like results of the protocol.
+pub fn evaluate<V: Plaintext>(
+    expr: &Expression<u64>,
+    variables: &[HashMap<String, i64>],
+    fractional_bits: u32,
+) -> Result<(V, u32), String> {
+    check_fractional_bits(fractional_bits)?;
+    let mut evaluator = Evaluator {
+        variables,
+        fractional_bits,
+        bindings: vec![],
+    };
+    let Typed { value, fixed } = evaluator.evaluate(expr)?;
+
+    Ok((value, if fixed { fractional_bits } else { 0 }))
+}
+
+/// Evaluates all outputs in plaintext, including private ones
+pub fn evaluate_outputs<V: Plaintext>(
+    outputs: &Outputs<u64>,
+    variables: &[HashMap<String, i64>],
+    fractional_bits: u32,
+) -> Result<HashMap<String, (V, u32)>, String> {
+    outputs
+        .iter()
+        .map(|(name, output)| {
+            evaluate(&output.expression, variables, fractional_bits)
+                .map(|value| (name.clone(), value))
+                .map_err(|e| format!("Can't evaluate output {}: {}", name, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expressions::parser::{parse, parse_outputs};
+    use crate::protocol::{output::Output, DEFAULT_FRACTIONAL_BITS};
+
+    fn variables() -> Vec<HashMap<String, i64>> {
+        vec![
+            [("x".to_string(), 3), ("big".to_string(), 1 << 32)]
+                .into_iter()
+                .collect(),
+            [("y".to_string(), -5)].into_iter().collect(),
+        ]
+    }
+
+    fn eval<V: Plaintext>(formula: &str) -> Result<(V, u32), String> {
+        evaluate(
+            &parse(formula).unwrap(),
+            &variables(),
+            DEFAULT_FRACTIONAL_BITS,
+        )
+    }
+
+    fn decimal<V: Plaintext>(formula: &str) -> String {
+        let (value, fractional_bits) = eval::<V>(formula).unwrap();
+        let value = value.to_i128().unwrap();
+        Decimal::from_fixed(value, fractional_bits)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn evaluates_integers() {
+        for (formula, expected) in [
+            ("x * y + 2", "-13"),
+            ("x - y < 0", "0"),
+            ("y < x", "1"),
+            ("x > y == 1", "1"),
+            ("(x + y) ^ 3", "-8"),
+            ("-y ^ 0", "-1"),
+            ("y / 5", "-1"),
+            ("x / 2", "1"),
+            ("y / 2", "-3"),
+            ("y / (0 - 2)", "2"),
+            ("y /! 5", "-1"),
+            ("(x + 1) /! (0 - 2)", "-2"),
+            ("if x < y then x else y", "-5"),
+            ("let x = x * 2 in let z = x + 1 in x * z", "42"),
+        ] {
+            assert_eq!(expected, decimal::<u64>(formula), "{}", formula);
+            assert_eq!(expected, decimal::<Elem>(formula), "{}", formula);
+        }
+    }
+
+    #[test]
+    fn evaluates_fixed_point() {
+        for (formula, expected) in [
+            ("x * 1.5", "4.5"),
+            ("1.5 * 1.5 + y", "-2.75"),
+            ("x / 2.0", "1.5"),
+            ("(y / 2.0) ^ 2", "6.25"),
+            ("0.5 ^ 0", "1"),
+        ] {
+            assert_eq!(expected, decimal::<u64>(formula), "{}", formula);
+            assert_eq!(expected, decimal::<Elem>(formula), "{}", formula);
+        }
+
+        let (value, fractional_bits) = eval::<Elem>("x * 0.1").unwrap();
+        assert_eq!(
+            Ok(Decimal::new(3, 1)),
+            Decimal::decode(&value, fractional_bits)
+        );
+    }
+
+    #[test]
+    fn wraps_around_modulus() {
+        // 2^64 is 0 modulo 2^64 but not in the field
+        assert_eq!(Ok((0, 0)), eval::<u64>("big * big"));
+        assert_eq!(Ok((shares::pow2(64), 0)), eval::<Elem>("big * big"));
+
+        // exact division multiplies with inverse in the field
+        assert!(eval::<u64>("x /! 2").is_err());
+        let (value, _) = eval::<Elem>("x /! 2").unwrap();
+        assert_eq!(Elem::from(2) * value, Elem::from(3));
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(Err("orphaned variable".to_string()), eval::<Elem>("x + z"));
+        assert!(eval::<Elem>("if x then 1 else 2").is_err());
+        assert!(eval::<Elem>("if 1.0 then 1 else 2").is_err());
+        assert!(eval::<u64>("x / 0").is_err());
+        assert!(eval::<Elem>("x / (y + 5)").is_err());
+    }
+
+    #[test]
+    fn evaluates_all_outputs() {
+        let outputs = parse_outputs("a = x + y; b @ 1 = x * y").unwrap();
+        let values = evaluate_outputs::<Elem>(&outputs, &variables(), 0).unwrap();
+
+        assert_eq!(2, values.len());
+        assert_eq!((-Elem::from(2), 0), values["a"]);
+        assert_eq!((-Elem::from(15), 0), values["b"]);
+    }
+}
@@ -1,12 +1,48 @@
+pub mod decimal;
+pub mod eval;
+pub mod parser;
+
+use decimal::Decimal;
 use num_traits::Num;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 type BExpression<T> = Box<Expression<T>>;
 
+/// Named outputs of a circuit evaluated in one session.
+/// Ordered by name so that every node assigns the same circuit ids.
+pub type Outputs<T> = BTreeMap<String, OutputExpression<T>>;
+
+/// Output of a circuit revealed to everyone or only to `recipient` node
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OutputExpression<T: Num> {
+    pub expression: Expression<T>,
+    #[serde(default)]
+    pub recipient: Option<u64>,
+}
+
+impl<T: Num> OutputExpression<T> {
+    pub fn public(expression: Expression<T>) -> Self {
+        Self {
+            expression,
+            recipient: None,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum BinaryOp {
     Add,
+    Sub,
     Mul,
+    /// division by a constant rounding down, or in fixed point if any operand is a decimal,
+    /// see `decorate_expression` for exact rules
+    Div,
+    /// division of integer by a constant that divides it, computed with inverse of the constant
+    ExactDiv,
+    LessThan,
+    GreaterThan,
+    Eq,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -15,6 +51,10 @@ pub enum Expression<T: Num> {
     Number {
         number: T,
     },
+    /// fixed point number, see `Provider::fractional_bits`
+    Decimal {
+        decimal: Decimal,
+    },
     BinOp {
         left: BExpression<T>,
         right: BExpression<T>,
@@ -23,4 +63,25 @@ pub enum Expression<T: Num> {
     Variable {
         name: String,
     },
+    Neg {
+        expr: BExpression<T>,
+    },
+    /// `base` raised to public `exponent`
+    Pow {
+        base: BExpression<T>,
+        exponent: u32,
+    },
+    /// `body` in which variable `name` refers to `value`, computed only once
+    Let {
+        name: String,
+        value: BExpression<T>,
+        body: BExpression<T>,
+    },
+    /// `then` if `cond` is 1, `else` if `cond` is 0, without revealing which one
+    Select {
+        cond: BExpression<T>,
+        then: BExpression<T>,
+        #[serde(rename = "else")]
+        otherwise: BExpression<T>,
+    },
 }
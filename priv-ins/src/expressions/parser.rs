@@ -0,0 +1,775 @@
+use crate::expressions::{decimal::Decimal, BinaryOp, Expression, OutputExpression, Outputs};
+use num_traits::Num;
+use std::{fmt, iter::Peekable, str::CharIndices, str::FromStr};
+
+/// Error returned by `parse` pointing at the place (1-based line and column) where parsing failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(String),
+    Ident(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    SlashBang,
+    Caret,
+    Less,
+    Greater,
+    EqEq,
+    Assign,
+    Semicolon,
+    At,
+    If,
+    Then,
+    Else,
+    Let,
+    In,
+    LParen,
+    RParen,
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "number `{}`", n),
+            Token::Ident(i) => write!(f, "variable `{}`", i),
+            Token::Plus => write!(f, "`+`"),
+            Token::Minus => write!(f, "`-`"),
+            Token::Star => write!(f, "`*`"),
+            Token::Slash => write!(f, "`/`"),
+            Token::SlashBang => write!(f, "`/!`"),
+            Token::Caret => write!(f, "`^`"),
+            Token::Less => write!(f, "`<`"),
+            Token::Greater => write!(f, "`>`"),
+            Token::EqEq => write!(f, "`==`"),
+            Token::Assign => write!(f, "`=`"),
+            Token::Semicolon => write!(f, "`;`"),
+            Token::At => write!(f, "`@`"),
+            Token::If => write!(f, "`if`"),
+            Token::Then => write!(f, "`then`"),
+            Token::Else => write!(f, "`else`"),
+            Token::Let => write!(f, "`let`"),
+            Token::In => write!(f, "`in`"),
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+            Token::End => write!(f, "end of input"),
+        }
+    }
+}
+
+/// Token together with its (line, column) position
+type Spanned = (Token, usize, usize);
+
+struct Lexer<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    input: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            chars: input.char_indices().peekable(),
+            input,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn bump(&mut self) -> Option<(usize, char)> {
+        let next = self.chars.next();
+        if let Some((_, c)) = next {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        next
+    }
+
+    /// consumes characters while `pred` holds and returns the consumed slice
+    fn take_while(&mut self, start: usize, pred: impl Fn(char) -> bool) -> &'a str {
+        let mut end = self.input.len();
+        while let Some(&(i, c)) = self.chars.peek() {
+            if !pred(c) {
+                end = i;
+                break;
+            }
+            self.bump();
+        }
+        &self.input[start..end]
+    }
+
+    fn tokenize(mut self) -> Result<Vec<Spanned>, ParseError> {
+        let mut tokens = vec![];
+
+        while let Some(&(start, c)) = self.chars.peek() {
+            let (line, column) = (self.line, self.column);
+
+            let token = match c {
+                c if c.is_whitespace() => {
+                    self.bump();
+                    continue;
+                }
+                '+' => Token::Plus,
+                '-' => Token::Minus,
+                '*' => Token::Star,
+                '/' => {
+                    self.bump();
+                    match self.chars.peek() {
+                        Some((_, '!')) => Token::SlashBang,
+                        _ => {
+                            tokens.push((Token::Slash, line, column));
+                            continue;
+                        }
+                    }
+                }
+                '^' => Token::Caret,
+                '<' => Token::Less,
+                '>' => Token::Greater,
+                '=' => {
+                    self.bump();
+                    match self.chars.peek() {
+                        Some((_, '=')) => Token::EqEq,
+                        _ => {
+                            tokens.push((Token::Assign, line, column));
+                            continue;
+                        }
+                    }
+                }
+                ';' => Token::Semicolon,
+                '@' => Token::At,
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                c if c.is_ascii_digit() => {
+                    let number = self.take_while(start, |c| c.is_ascii_alphanumeric() || c == '.');
+                    tokens.push((Token::Number(number.to_string()), line, column));
+                    continue;
+                }
+                c if c.is_alphabetic() || c == '_' => {
+                    let name = self.take_while(start, |c| c.is_alphanumeric() || c == '_');
+                    let token = match name {
+                        "if" => Token::If,
+                        "then" => Token::Then,
+                        "else" => Token::Else,
+                        "let" => Token::Let,
+                        "in" => Token::In,
+                        _ => Token::Ident(name.to_string()),
+                    };
+                    tokens.push((token, line, column));
+                    continue;
+                }
+                c => {
+                    return Err(ParseError {
+                        line,
+                        column,
+                        message: format!("unexpected character `{}`", c),
+                    })
+                }
+            };
+
+            self.bump();
+            tokens.push((token, line, column));
+        }
+
+        tokens.push((Token::End, self.line, self.column));
+        Ok(tokens)
+    }
+}
+
+/// Recursive descent parser with following grammar:
+///
+/// outputs := output (';' output)* ';'?
+/// output  := variable ('@' integer)? '=' cmp
+/// cmp     := expr (('<' | '>' | '==') expr)*
+/// expr    := term (('+' | '-') term)*
+/// term    := unary (('*' | '/' | '/!') unary)*
+/// unary   := '-' unary | power
+/// power   := primary ('^' integer)?
+/// primary := number | variable | '(' cmp ')' | 'if' cmp 'then' cmp 'else' cmp
+///            | 'let' variable '=' cmp 'in' cmp
+struct Parser {
+    tokens: Vec<Spanned>,
+    position: usize,
+    depth: usize,
+}
+
+/// Deepest nesting of parentheses, `if`, `let` and `-` accepted by `parse`,
+/// deeper formulas would overflow the stack of recursive passes over the expression
+pub const MAX_NESTING: usize = 256;
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].0
+    }
+
+    fn next(&mut self) -> Spanned {
+        let token = self.tokens[self.position].clone();
+        if token.0 != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn error_at(&self, (token, line, column): &Spanned, expected: &str) -> ParseError {
+        ParseError {
+            line: *line,
+            column: *column,
+            message: format!("expected {}, found {}", expected, token),
+        }
+    }
+
+    /// Runs `parse` one level deeper than `opening`, fails if it nests more than `MAX_NESTING` levels
+    fn nested<R>(
+        &mut self,
+        (_, line, column): &Spanned,
+        parse: impl FnOnce(&mut Self) -> Result<R, ParseError>,
+    ) -> Result<R, ParseError> {
+        if self.depth == MAX_NESTING {
+            return Err(ParseError {
+                line: *line,
+                column: *column,
+                message: format!("nesting deeper than {} levels", MAX_NESTING),
+            });
+        }
+        self.depth += 1;
+        let result = parse(self)?;
+        self.depth -= 1;
+        Ok(result)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        let spanned = self.next();
+        if spanned.0 != expected {
+            return Err(self.error_at(&spanned, &expected.to_string()));
+        }
+        Ok(())
+    }
+
+    fn outputs<T>(&mut self) -> Result<Outputs<T>, ParseError>
+    where
+        T: Num + FromStr,
+    {
+        let mut outputs = Outputs::new();
+
+        while *self.peek() != Token::End {
+            let spanned = self.next();
+            let name = match &spanned.0 {
+                Token::Ident(name) => name.clone(),
+                _ => return Err(self.error_at(&spanned, "output name")),
+            };
+            let recipient = self.recipient()?;
+            self.expect(Token::Assign)?;
+
+            let output = OutputExpression {
+                expression: self.cmp()?,
+                recipient,
+            };
+            if outputs.insert(name.clone(), output).is_some() {
+                return Err(ParseError {
+                    line: spanned.1,
+                    column: spanned.2,
+                    message: format!("output `{}` defined twice", name),
+                });
+            }
+
+            if *self.peek() != Token::End {
+                self.expect(Token::Semicolon)?;
+            }
+        }
+
+        Ok(outputs)
+    }
+
+    /// parses optional `@ party` after output name
+    fn recipient(&mut self) -> Result<Option<u64>, ParseError> {
+        if *self.peek() != Token::At {
+            return Ok(None);
+        }
+        self.next();
+
+        let spanned = self.next();
+        match &spanned.0 {
+            Token::Number(n) => match n.parse::<u64>() {
+                Ok(recipient) => Ok(Some(recipient)),
+                Err(_) => Err(self.error_at(&spanned, "party id")),
+            },
+            _ => Err(self.error_at(&spanned, "party id")),
+        }
+    }
+
+    fn cmp<T>(&mut self) -> Result<Expression<T>, ParseError>
+    where
+        T: Num + FromStr,
+    {
+        let mut left = self.expr()?;
+
+        loop {
+            let op = match self.peek() {
+                Token::Less => BinaryOp::LessThan,
+                Token::Greater => BinaryOp::GreaterThan,
+                Token::EqEq => BinaryOp::Eq,
+                _ => return Ok(left),
+            };
+            self.next();
+
+            left = Expression::BinOp {
+                left: Box::new(left),
+                right: Box::new(self.expr()?),
+                op,
+            };
+        }
+    }
+
+    fn expr<T>(&mut self) -> Result<Expression<T>, ParseError>
+    where
+        T: Num + FromStr,
+    {
+        let mut left = self.term()?;
+
+        loop {
+            let op = match self.peek() {
+                Token::Plus => BinaryOp::Add,
+                Token::Minus => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.next();
+
+            left = Expression::BinOp {
+                left: Box::new(left),
+                right: Box::new(self.term()?),
+                op,
+            };
+        }
+    }
+
+    fn term<T>(&mut self) -> Result<Expression<T>, ParseError>
+    where
+        T: Num + FromStr,
+    {
+        let mut left = self.unary()?;
+
+        loop {
+            let op = match self.peek() {
+                Token::Star => BinaryOp::Mul,
+                Token::Slash => BinaryOp::Div,
+                Token::SlashBang => BinaryOp::ExactDiv,
+                _ => return Ok(left),
+            };
+            self.next();
+
+            left = Expression::BinOp {
+                left: Box::new(left),
+                right: Box::new(self.unary()?),
+                op,
+            };
+        }
+    }
+
+    fn unary<T>(&mut self) -> Result<Expression<T>, ParseError>
+    where
+        T: Num + FromStr,
+    {
+        if *self.peek() == Token::Minus {
+            let minus = self.next();
+            let expr = self.nested(&minus, |parser| parser.unary())?;
+
+            return Ok(Expression::Neg {
+                expr: Box::new(expr),
+            });
+        }
+
+        self.power()
+    }
+
+    fn power<T>(&mut self) -> Result<Expression<T>, ParseError>
+    where
+        T: Num + FromStr,
+    {
+        let base = self.primary()?;
+        if *self.peek() != Token::Caret {
+            return Ok(base);
+        }
+        self.next();
+
+        let spanned = self.next();
+        let exponent = match &spanned.0 {
+            Token::Number(n) => n.parse::<u32>().ok(),
+            _ => None,
+        };
+
+        match exponent {
+            Some(exponent) => Ok(Expression::Pow {
+                base: Box::new(base),
+                exponent,
+            }),
+            None => Err(self.error_at(&spanned, "integer exponent")),
+        }
+    }
+
+    fn primary<T>(&mut self) -> Result<Expression<T>, ParseError>
+    where
+        T: Num + FromStr,
+    {
+        let spanned = self.next();
+
+        match &spanned.0 {
+            Token::Number(n) if n.contains('.') => match n.parse::<Decimal>() {
+                Ok(decimal) => Ok(Expression::Decimal { decimal }),
+                Err(message) => Err(ParseError {
+                    line: spanned.1,
+                    column: spanned.2,
+                    message,
+                }),
+            },
+            Token::Number(n) => match n.parse::<T>() {
+                Ok(number) => Ok(Expression::Number { number }),
+                Err(_) => Err(ParseError {
+                    line: spanned.1,
+                    column: spanned.2,
+                    message: format!("invalid number `{}`", n),
+                }),
+            },
+            Token::Ident(name) => Ok(Expression::Variable { name: name.clone() }),
+            Token::LParen => self.nested(&spanned, |parser| {
+                let inner = parser.cmp()?;
+                parser.expect(Token::RParen)?;
+                Ok(inner)
+            }),
+            Token::If => self.nested(&spanned, |parser| {
+                let cond = parser.cmp()?;
+                parser.expect(Token::Then)?;
+                let then = parser.cmp()?;
+                parser.expect(Token::Else)?;
+                let otherwise = parser.cmp()?;
+
+                Ok(Expression::Select {
+                    cond: Box::new(cond),
+                    then: Box::new(then),
+                    otherwise: Box::new(otherwise),
+                })
+            }),
+            Token::Let => self.nested(&spanned, |parser| {
+                let spanned = parser.next();
+                let name = match &spanned.0 {
+                    Token::Ident(name) => name.clone(),
+                    _ => return Err(parser.error_at(&spanned, "variable")),
+                };
+                parser.expect(Token::Assign)?;
+                let value = parser.cmp()?;
+                parser.expect(Token::In)?;
+                let body = parser.cmp()?;
+
+                Ok(Expression::Let {
+                    name,
+                    value: Box::new(value),
+                    body: Box::new(body),
+                })
+            }),
+            _ => Err(self.error_at(&spanned, "number, variable, `(`, `if` or `let`")),
+        }
+    }
+}
+
+/// Parses infix formula like `(age * 3 + claims * 120) * risk + 500` into an `Expression`.
+/// `*`, `/` and `/!` bind stronger than `+` and `-` which bind stronger than comparisons `<`, `>` and `==`,
+/// binary operators are left associative
+/// and `-` in front of an operand negates it.
+pub fn parse<T>(input: &str) -> Result<Expression<T>, ParseError>
+where
+    T: Num + FromStr,
+{
+    let mut parser = Parser {
+        tokens: Lexer::new(input).tokenize()?,
+        position: 0,
+        depth: 0,
+    };
+
+    let expression = parser.cmp()?;
+
+    let rest = parser.next();
+    if rest.0 != Token::End {
+        return Err(parser.error_at(&rest, "operator or end of input"));
+    }
+
+    Ok(expression)
+}
+
+/// Name of the only output of formula parsed with `parse_outputs`
+pub const RESULT: &str = "result";
+
+/// Parses circuit with named outputs like `premium = age * 3 + 500; payout @ 1 = claims * 120`,
+/// where `@ 1` reveals the output only to party 1.
+/// A formula not starting with output name is a single public output named `result`.
+pub fn parse_outputs<T>(input: &str) -> Result<Outputs<T>, ParseError>
+where
+    T: Num + FromStr,
+{
+    let tokens = Lexer::new(input).tokenize()?;
+    let named = matches!(
+        (&tokens[0].0, &tokens.get(1).map(|(token, _, _)| token)),
+        (Token::Ident(_), Some(Token::Assign | Token::At))
+    );
+    if !named {
+        let output = OutputExpression::public(parse(input)?);
+        return Ok(Outputs::from([(RESULT.to_string(), output)]));
+    }
+
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        depth: 0,
+    };
+
+    parser.outputs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// renders expression fully parenthesized so the structure is easy to compare
+    fn show(expr: &Expression<u64>) -> String {
+        match expr {
+            Expression::Number { number } => number.to_string(),
+            Expression::Decimal { decimal } => decimal.to_string(),
+            Expression::Variable { name } => name.clone(),
+            Expression::Neg { expr } => format!("(-{})", show(expr)),
+            Expression::Pow { base, exponent } => format!("({} ^ {})", show(base), exponent),
+            Expression::Let { name, value, body } => {
+                format!("(let {} = {} in {})", name, show(value), show(body))
+            }
+            Expression::Select {
+                cond,
+                then,
+                otherwise,
+            } => format!(
+                "(if {} then {} else {})",
+                show(cond),
+                show(then),
+                show(otherwise)
+            ),
+            Expression::BinOp { left, right, op } => {
+                let op = match op {
+                    BinaryOp::Add => "+",
+                    BinaryOp::Sub => "-",
+                    BinaryOp::Mul => "*",
+                    BinaryOp::Div => "/",
+                    BinaryOp::ExactDiv => "/!",
+                    BinaryOp::LessThan => "<",
+                    BinaryOp::GreaterThan => ">",
+                    BinaryOp::Eq => "==",
+                };
+                format!("({} {} {})", show(left), op, show(right))
+            }
+        }
+    }
+
+    fn parsed(input: &str) -> String {
+        show(&parse::<u64>(input).expect("should parse"))
+    }
+
+    #[test]
+    fn respects_precedence_and_parentheses() {
+        assert_eq!(
+            "((((age * 3) + (claims * 120)) * risk) + 500)",
+            parsed("(age * 3 + claims * 120) * risk + 500")
+        );
+        assert_eq!("(a + (b * c))", parsed("a + b * c"));
+        assert_eq!("((a + b) * c)", parsed("(a + b) * c"));
+    }
+
+    #[test]
+    fn comparisons_bind_weakest() {
+        assert_eq!("((age + 1) > (65 * x))", parsed("age + 1 > 65 * x"));
+        assert_eq!("((category == 3) * 10)", parsed("(category==3) * 10"));
+        assert_eq!(
+            "(((claims < 3) * 100) + 50)",
+            parsed("(claims < 3) * 100 + 50")
+        );
+    }
+
+    #[test]
+    fn parses_named_outputs() {
+        let outputs =
+            parse_outputs::<u64>("premium = age * 3 + 500;\npayout @ 1 = claims == 0;").unwrap();
+        assert_eq!(
+            vec![
+                ("payout".to_string(), "(claims == 0)".to_string(), Some(1)),
+                ("premium".to_string(), "((age * 3) + 500)".to_string(), None)
+            ],
+            outputs
+                .iter()
+                .map(|(name, output)| (name.clone(), show(&output.expression), output.recipient))
+                .collect::<Vec<_>>()
+        );
+
+        let single = parse_outputs::<u64>("age * 3").unwrap();
+        assert_eq!(vec![RESULT], single.keys().collect::<Vec<_>>());
+
+        for (input, error) in [
+            ("a = 1; a = 2", "1:8: output `a` defined twice"),
+            ("a = 1 b = 2", "1:7: expected `;`, found variable `b`"),
+            (
+                "a = 1; 2 = b",
+                "1:8: expected output name, found number `2`",
+            ),
+            (
+                "a + 1 = 2",
+                "1:7: expected operator or end of input, found `=`",
+            ),
+            ("a @ b = 2", "1:5: expected party id, found variable `b`"),
+        ] {
+            assert_eq!(
+                error,
+                parse_outputs::<u64>(input).unwrap_err().to_string(),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn parses_select() {
+        assert_eq!(
+            "((if (age > 65) then (base * 2) else (base + 10)) + 1)",
+            parsed("(if age > 65 then base * 2 else base + 10) + 1")
+        );
+        assert_eq!(
+            "(if smoker then 3 else (if (age < 30) then 1 else 2))",
+            parsed("if smoker then 3 else if age < 30 then 1 else 2")
+        );
+
+        let err = parse::<u64>("if a then b").unwrap_err();
+        assert_eq!("1:12: expected `else`, found end of input", err.to_string());
+    }
+
+    #[test]
+    fn operators_are_left_associative() {
+        assert_eq!("((a - b) - c)", parsed("a - b - c"));
+        assert_eq!("((a * b) * c)", parsed("a*b*c"));
+        assert_eq!("(((a / 12) * b) / 2)", parsed("a / 12 * b / 2"));
+        assert_eq!("(a + (b / 2))", parsed("a + b / 2"));
+        assert_eq!("((a /! 12) / 2)", parsed("a /! 12 / 2"));
+    }
+
+    #[test]
+    fn parses_unary_minus() {
+        assert_eq!("((-a) * (-(-3)))", parsed("-a * --3"));
+        assert_eq!("(base - (-deductible))", parsed("base - -deductible"));
+    }
+
+    #[test]
+    fn parses_powers() {
+        assert_eq!("((-(risk ^ 3)) * 2)", parsed("-risk^3 * 2"));
+        assert_eq!(
+            "(((a + 1) ^ 2) + (1.5 ^ 0))",
+            parsed("(a + 1) ^ 2 + 1.5 ^ 0")
+        );
+
+        let err = parse::<u64>("a ^ b").unwrap_err();
+        assert_eq!(
+            "1:5: expected integer exponent, found variable `b`",
+            err.to_string()
+        );
+        assert!(parse::<u64>("a ^ 2 ^ 3").is_err());
+        assert!(parse::<u64>("a ^ 1.5").is_err());
+    }
+
+    #[test]
+    fn parses_let() {
+        assert_eq!(
+            "(let r = (risk * risk) in ((r * 3) + r))",
+            parsed("let r = risk * risk in r * 3 + r")
+        );
+        assert_eq!(
+            "((let a = 1 in (let b = (a + 1) in (a * b))) + 1)",
+            parsed("(let a = 1 in let b = a + 1 in a * b) + 1")
+        );
+
+        let outputs = parse_outputs::<u64>("let r = a * a in r + 1").unwrap();
+        assert_eq!(vec![RESULT], outputs.keys().collect::<Vec<_>>());
+
+        let err = parse::<u64>("let 1 = a in 2").unwrap_err();
+        assert_eq!("1:5: expected variable, found number `1`", err.to_string());
+        let err = parse::<u64>("let x = a x").unwrap_err();
+        assert_eq!("1:11: expected `in`, found variable `x`", err.to_string());
+    }
+
+    #[test]
+    fn parses_decimals() {
+        assert_eq!("((base * 1.75) + 0.5)", parsed("base * 1.75 + 0.50"));
+
+        let err = parse::<u64>("a * 1.2.3").unwrap_err();
+        assert_eq!("1:5: invalid decimal `1.2.3`", err.to_string());
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        let err = parse::<u64>("a +\n  * b").unwrap_err();
+        assert_eq!((2, 3), (err.line, err.column));
+
+        let err = parse::<u64>("(a + b").unwrap_err();
+        assert_eq!((1, 7), (err.line, err.column));
+        assert_eq!("1:7: expected `)`, found end of input", err.to_string());
+
+        let err = parse::<u64>("a # b").unwrap_err();
+        assert_eq!((1, 3), (err.line, err.column));
+
+        let err = parse::<u64>("a = b").unwrap_err();
+        assert_eq!((1, 3), (err.line, err.column));
+
+        let err = parse::<u64>("a b").unwrap_err();
+        assert_eq!((1, 3), (err.line, err.column));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |open: &str, close: &str, levels| {
+            format!("{}a{}", open.repeat(levels), close.repeat(levels))
+        };
+        assert!(parse::<u64>(&nested("(", ")", MAX_NESTING)).is_ok());
+        assert!(parse::<u64>(&nested("-", "", MAX_NESTING)).is_ok());
+
+        let err = parse::<u64>(&nested("(", ")", MAX_NESTING + 1)).unwrap_err();
+        assert_eq!(
+            format!("1:{}: nesting deeper than 256 levels", MAX_NESTING + 1),
+            err.to_string()
+        );
+
+        let err = parse::<u64>(&nested("(", "", 100_000)).unwrap_err();
+        assert_eq!((1, MAX_NESTING + 1), (err.line, err.column));
+        let err = parse::<u64>(&nested("-", "", 100_000)).unwrap_err();
+        assert_eq!((1, MAX_NESTING + 1), (err.line, err.column));
+        let err = parse::<u64>(&"if 1 then 2 else ".repeat(100_000)).unwrap_err();
+        assert_eq!((1, 17 * MAX_NESTING + 1), (err.line, err.column));
+        assert!(parse_outputs::<u64>(&format!("x = {}", nested("(", ")", 100_000))).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_numbers() {
+        let err = parse::<u64>("1 + 12ab").unwrap_err();
+        assert_eq!((1, 5), (err.line, err.column));
+
+        assert!(parse::<u64>("99999999999999999999999").is_err());
+    }
+}
@@ -13,7 +13,7 @@ mod expressions;
 mod protocol;
 mod rest;
 
-use crate::rest::expression;
+use crate::rest::{cost, dry_run, expression};
 
 fn get_cors() -> CorsMiddleware {
     CorsMiddleware::new()
@@ -28,6 +28,8 @@ async fn main() -> tide::Result<()> {
     let mut app = tide::new();
 
     app.at("/exp").post(expression);
+    app.at("/cost").post(cost);
+    app.at("/dry-run").post(dry_run);
 
     app.with(get_cors());
     app.listen("127.0.0.1:8080").await?;
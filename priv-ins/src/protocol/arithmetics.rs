@@ -68,26 +68,53 @@ impl Calculator {
         (share.0 * constant, share.1 * constant)
     }
 
-    /// Generates commitment (hash, salt) pair for `elem` such that hash = H(elem || salt)
-    pub fn generate_commitment(elem: &Elem) -> (Hash, Salt) {
+    /// Generates commitment (hash, salt) pair for `elems` such that hash = H(elems || salt)
+    pub fn generate_commitment(elems: &[Elem]) -> (Hash, Salt) {
         let salt = random_salt();
-        (compute_commitment(elem, &salt), salt)
+        (compute_commitment(elems, &salt), salt)
     }
 
     /// Generates element that we want to commit for partial opening d_i = a_i * x' - m(x)_i
-    pub fn generate_commitment_share(&self, opened: Elem, share: Share) -> Elem {
-        self.alpha_share * opened - share.1
+    pub fn generate_commitment_share(&self, opened: Elem, mac: Elem) -> Elem {
+        self.alpha_share * opened - mac
     }
 }
 
-/// Verifies whether vector of CommitmentProof is correct and whether Elem's sum to 0. Returns true if correct
-pub fn verify_commitments(commitments: &Vec<CommitmentProof>) -> bool {
-    for (hash, elem, salt) in commitments.iter() {
-        if *hash != shares::compute_commitment(elem, salt) {
+/// Combines opened values x' with our shares of their macs m(x)_i into a single pair
+/// by random linear combination sum r_j * x'_j, sum r_j * m(x_j)_i. Coefficients r_j are derived
+/// from `seed`, see `combination_coefficient`, so all nodes get shares of mac of the same combined value.
+pub fn combine_openings(openings: &[(Elem, Elem)], seed: Hash) -> (Elem, Elem) {
+    openings.iter().enumerate().fold(
+        (Elem::zero(), Elem::zero()),
+        |(value, mac), (index, (opened, mac_share))| {
+            let r = combination_coefficient(&seed, index as u64);
+            (value + r * opened, mac + r * mac_share)
+        },
+    )
+}
+
+/// Returns r_j = H(seed || j) reduced modulo p, with j encoded as 8 little endian bytes
+pub fn combination_coefficient(seed: &Hash, index: u64) -> Elem {
+    let mut bytes = seed.to_vec();
+    bytes.extend_from_slice(&index.to_le_bytes());
+    shares::elem_from_hash(&shares::hash(&bytes))
+}
+
+/// Verifies whether vector of CommitmentProof is correct and whether Elem's of each row sum to 0.
+/// Returns true if correct
+pub fn verify_commitments(commitments: &[CommitmentProof]) -> bool {
+    let rows = match commitments.first() {
+        Some((_, elems, _)) => elems.len(),
+        None => return true,
+    };
+    for (hash, elems, salt) in commitments.iter() {
+        if elems.len() != rows || *hash != shares::compute_commitment(elems, salt) {
             return false;
         }
     }
-    shares::sum_elems(&commitments.iter().map(|c| c.1).collect()) == Elem::zero()
+    (0..rows).all(|row| {
+        shares::sum_elems(&commitments.iter().map(|c| c.1[row]).collect()) == Elem::zero()
+    })
 }
 
 #[cfg(test)]
@@ -286,4 +313,81 @@ mod tests {
         assert_eq!(shared, a * b);
         assert_eq!(shared_alpha, alpha * a * b);
     }
+
+    #[test]
+    fn combination_coefficients_are_pinned() {
+        let coefficients: Vec<_> = (0..3)
+            .map(|index| format!("{:?}", combination_coefficient(&[1; 32], index)))
+            .collect();
+        // sha3_256([1; 32] || index as u64 le) mod p
+        assert_eq!(
+            coefficients,
+            vec![
+                "Fp(0x129e44d0896cbe377313f45fc243bc500f76e189f93c41b4cd65680cfa3aae4a)",
+                "Fp(0x2170e65369e7a211c0b5681c081ca21d79403d34c6e55c89fca2bf4cf4b44b1f)",
+                "Fp(0x435edd59cd8e1f8eb3ba98f4d68b9f489ec379dfe605c233be4a6d0cab890bf7)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_combined_mac_check() {
+        let alpha = Elem::from(69);
+        let n_parties = 3;
+        let alpha_shares = shares::elems_from_secret(&alpha, n_parties);
+        let values = [Elem::from(2137), Elem::from(420), Elem::from(7)];
+        let value_shares: Vec<_> = values
+            .iter()
+            .map(|v| shares::shares_from_secret(v, &alpha_shares, n_parties))
+            .collect();
+
+        let proofs = |opened: &[Elem]| {
+            (0..n_parties as usize)
+                .map(|id| {
+                    let calculator = Calculator::new(id as u64, alpha_shares[id]);
+                    let openings: Vec<_> = opened
+                        .iter()
+                        .zip(value_shares.iter())
+                        .map(|(opened, shares)| (*opened, shares[id].1))
+                        .collect();
+                    let (value, mac) = combine_openings(&openings, [1; 32]);
+                    let sigma = calculator.generate_commitment_share(value, mac);
+                    let (hash, salt) = Calculator::generate_commitment(&[sigma]);
+                    (hash, vec![sigma], salt)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert!(verify_commitments(&proofs(&values)));
+
+        let mut tampered = values;
+        tampered[1] += Elem::one();
+        assert!(!verify_commitments(&proofs(&tampered)));
+    }
+
+    #[test]
+    fn test_verify_commitments_of_all_rows() {
+        let proof = |elems: Vec<Elem>| {
+            let (hash, salt) = Calculator::generate_commitment(&elems);
+            (hash, elems, salt)
+        };
+        let a = Elem::from(2137);
+        let b = Elem::from(420);
+
+        assert!(verify_commitments(&[
+            proof(vec![a, b]),
+            proof(vec![-a, -b])
+        ]));
+        assert!(!verify_commitments(&[
+            proof(vec![a, b]),
+            proof(vec![-a, b])
+        ]));
+        assert!(!verify_commitments(&[proof(vec![a, b]), proof(vec![-a])]));
+
+        let (hash, _, salt) = proof(vec![a, b]);
+        assert!(!verify_commitments(&[
+            (hash, vec![b, a], salt),
+            proof(vec![-b, -a])
+        ]));
+    }
 }
@@ -0,0 +1,232 @@
+use crate::expressions::Outputs;
+use crate::protocol::{decorate_outputs, sub_id, CirId, DecoratedOutput, NodeId, Provider};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Size of field element in bytes
+const ELEM_BYTES: usize = 32;
+
+/// Size of share, i.e. value and its mac
+const SHARE_BYTES: usize = 2 * ELEM_BYTES;
+
+/// Size of hash committing to a value, salt is of the same size
+const COMMITMENT_BYTES: usize = 32;
+
+/// Size of proof opening a commitment: hash, committed element and salt
+const PROOF_BYTES: usize = 2 * COMMITMENT_BYTES + ELEM_BYTES;
+
+/// Id of the longest check of macs, see `Node::check_macs`
+const MAC_CHECK_ID: &str = "mac-check-circuit-coin";
+
+/// Estimated cost of evaluating outputs in one session
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Cost {
+    /// beaver triples requested from the dealer
+    pub multiplications: usize,
+    /// multiplicative depth of the deepest output
+    pub depth: usize,
+    /// communication rounds of the deepest output, not counting final checks and opening
+    pub rounds: usize,
+    /// values opened to all parties, their macs are checked together at the end
+    pub openings: usize,
+    /// values secret shared by their owners, including keys of private outputs
+    pub inputs: usize,
+    /// messages sent to other parties by the party sending the most
+    pub messages_per_party: usize,
+    /// bytes of messages sent to other parties by the party sending the most,
+    /// without the overhead of network transport
+    pub bytes_per_party: usize,
+}
+
+/// Messages sent to other parties by a single party
+#[derive(Debug, Clone, Copy, Default)]
+struct Traffic {
+    messages: usize,
+    bytes: usize,
+}
+
+impl Traffic {
+    fn send(&mut self, cir_id: &CirId, payload: usize, receivers: usize) {
+        self.messages += receivers;
+        self.bytes += receivers * (cir_id.len() + payload);
+    }
+}
+
+/// Estimates cost of evaluating decorated outputs by `n_parties`, the same way as `Node` does
+pub fn estimate(outputs: &[DecoratedOutput], n_parties: u64) -> Cost {
+    let peers = n_parties.saturating_sub(1) as usize;
+    let mut cost = Cost::default();
+    let mut traffic = vec![Traffic::default(); n_parties as usize];
+    // values opened while evaluating the circuit and public outputs opened after it
+    let mut opened = vec![];
+    let mut opened_outputs = vec![];
+
+    for output in outputs {
+        let expr = &output.expression;
+        if expr.is_constant() {
+            continue;
+        }
+
+        cost.depth = cost.depth.max(expr.depth());
+        cost.rounds = cost.rounds.max(expr.rounds());
+
+        let mul_ids = expr.mul_ids();
+        cost.multiplications += mul_ids.len();
+        for mul_id in mul_ids {
+            opened.push(sub_id(&mul_id, &"e".to_string()));
+            opened.push(sub_id(&mul_id, &"f".to_string()));
+        }
+        for (cir_id, _) in expr.bit_mask_ids() {
+            opened.push(sub_id(&cir_id, &"c".to_string()));
+        }
+        for (cir_id, _) in expr.truncation_mask_ids() {
+            opened.push(sub_id(&cir_id, &"c".to_string()));
+        }
+        for cir_id in expr.random_ids() {
+            opened.push(sub_id(&cir_id, &"check".to_string()));
+        }
+
+        // owner broadcasts its input masked by dealer's random value
+        for (node_id, party) in traffic.iter_mut().enumerate() {
+            for (cir_id, _) in expr.self_var_ids(Some(node_id as NodeId)) {
+                cost.inputs += 1;
+                party.send(&cir_id, ELEM_BYTES, peers);
+            }
+        }
+
+        let output_id = expr.cir_id();
+        match output.recipient {
+            None => opened_outputs.push(output_id),
+            Some(recipient) => {
+                let key_mul_id = sub_id(&output_id, &"key-mul".to_string());
+                cost.multiplications += 1;
+                opened.push(sub_id(&key_mul_id, &"e".to_string()));
+                opened.push(sub_id(&key_mul_id, &"f".to_string()));
+
+                let private_id = sub_id(&output_id, &"private".to_string());
+                for (node_id, party) in traffic.iter_mut().enumerate() {
+                    if node_id as NodeId == recipient {
+                        cost.inputs += 1;
+                        party.send(&sub_id(&output_id, &"key".to_string()), ELEM_BYTES, peers);
+                    } else {
+                        party.send(&private_id, SHARE_BYTES, 1);
+                    }
+                }
+            }
+        }
+    }
+
+    // as in `Node::check_macs`, each check is skipped if nothing was opened since the previous one
+    let mac_checks = usize::from(!opened.is_empty()) + usize::from(!opened_outputs.is_empty());
+    opened.extend(opened_outputs);
+
+    // every party broadcasts its share of each opened value
+    cost.openings = opened.len();
+    let check_id = MAC_CHECK_ID.to_string();
+    for party in traffic.iter_mut() {
+        for cir_id in opened.iter() {
+            party.send(cir_id, SHARE_BYTES, peers);
+        }
+        // digests of received shares are compared, coin is tossed and partial opening of
+        // combined mac checked in each mac check
+        for _ in 0..mac_checks {
+            party.send(&check_id, n_parties as usize * COMMITMENT_BYTES, peers);
+            for _ in 0..2 {
+                party.send(&check_id, COMMITMENT_BYTES, peers);
+                party.send(&check_id, PROOF_BYTES, peers);
+            }
+            party.send(&check_id, 0, peers);
+        }
+    }
+
+    let busiest = traffic
+        .into_iter()
+        .max_by_key(|party| (party.bytes, party.messages))
+        .unwrap_or_default();
+    cost.messages_per_party = busiest.messages;
+    cost.bytes_per_party = busiest.bytes;
+
+    cost
+}
+
+/// Decorates outputs as every node would and estimates cost of their evaluation
+pub fn estimate_outputs(
+    outputs: Outputs<u64>,
+    variables: HashMap<String, NodeId>,
+    n_parties: u64,
+    fractional_bits: u32,
+) -> Result<Cost, String> {
+    let mut provider = Provider::new(0, variables).with_fractional_bits(fractional_bits);
+    let decorated = decorate_outputs(outputs, &mut provider)?;
+
+    Ok(estimate(&decorated, n_parties))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expressions::parser::parse_outputs;
+    use crate::protocol::{COMPARISON_BITS, DEFAULT_FRACTIONAL_BITS};
+
+    fn cost(formula: &str) -> Cost {
+        let variables = [("x".to_string(), 0), ("y".to_string(), 1)]
+            .into_iter()
+            .collect();
+        estimate_outputs(
+            parse_outputs(formula).unwrap(),
+            variables,
+            2,
+            DEFAULT_FRACTIONAL_BITS,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn estimates_multiplication() {
+        let cost = cost("x * y");
+
+        assert_eq!(1, cost.multiplications);
+        assert_eq!(1, cost.depth);
+        assert_eq!(2, cost.rounds);
+        // e and f of the multiplication and the result
+        assert_eq!(3, cost.openings);
+        assert_eq!(2, cost.inputs);
+        // one input, one message per opening and six per each of two mac checks
+        assert_eq!(16, cost.messages_per_party);
+    }
+
+    #[test]
+    fn skips_circuit_mac_check_without_openings() {
+        let cost = cost("x + y");
+
+        assert_eq!(0, cost.multiplications);
+        assert_eq!(1, cost.openings);
+        // one input, one message for the opening and six for the mac check of outputs
+        assert_eq!(8, cost.messages_per_party);
+    }
+
+    #[test]
+    fn estimates_comparisons_and_private_outputs() {
+        // both have a public output, so both check macs of opened outputs
+        let public = cost("a = x < y; b = x + y");
+        // prefix OR joins half of the bits with more significant ones on each level
+        let levels = COMPARISON_BITS.ilog2() as usize;
+        assert_eq!(
+            COMPARISON_BITS as usize / 2 * levels,
+            public.multiplications
+        );
+        assert_eq!(levels, public.depth);
+        assert_eq!(2 * public.multiplications + 3, public.openings);
+
+        let private = cost("a @ 1 = x < y; b = x + y");
+        assert_eq!(public.multiplications + 1, private.multiplications);
+        assert_eq!(public.openings + 1, private.openings);
+        assert_eq!(public.inputs + 1, private.inputs);
+        assert!(private.bytes_per_party > public.bytes_per_party);
+    }
+
+    #[test]
+    fn constant_outputs_are_free() {
+        assert_eq!(Cost::default(), cost("a = 2 * 3; b = x * 0"));
+    }
+}
@@ -1,14 +1,33 @@
-use crate::crypto::shares::{self, BeaverShare, Elem};
-use crate::protocol::{CirId, DealerCommands, DealerEvents, NodeId, VarId};
+use crate::crypto::shares::{self, BeaverShare, BitMaskShare, Elem, Share, TruncationMaskShare};
+use crate::protocol::{
+    error::ProtocolError, CirId, DealerCommands, DealerEvents, NodeId, VarId, TRUNCATION_BITS,
+};
 use ff::Field;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
+/// Generates `batch_size` rows of values secret shared by `generate` and groups them by party
+fn rows<T>(n_parties: u8, batch_size: usize, mut generate: impl FnMut() -> Vec<T>) -> Vec<Vec<T>> {
+    let mut per_party = (0..n_parties).map(|_| vec![]).collect::<Vec<_>>();
+    for _ in 0..batch_size {
+        for (party, share) in per_party.iter_mut().zip(generate()) {
+            party.push(share);
+        }
+    }
+    per_party
+}
+
+/// Dealer of preprocessed values for sessions evaluating circuit over `batch_size` rows,
+/// every value is generated independently for each row
 pub struct TrustedDealer {
     n_parties: u8,
+    batch_size: usize,
     alpha: (Elem, Vec<Elem>),
-    beavers: HashMap<CirId, Vec<BeaverShare>>,
+    beavers: HashMap<CirId, Vec<Vec<BeaverShare>>>,
+    bit_masks: HashMap<CirId, Vec<Vec<BitMaskShare>>>,
+    randoms: HashMap<CirId, Vec<Vec<Share>>>,
+    truncation_masks: HashMap<CirId, Vec<Vec<TruncationMaskShare>>>,
     variables_owned: HashSet<VarId>,
     senders: HashMap<NodeId, UnboundedSender<DealerEvents>>,
     receiver: UnboundedReceiver<(NodeId, DealerCommands)>,
@@ -17,78 +36,140 @@ pub struct TrustedDealer {
 impl TrustedDealer {
     pub fn new(
         n_parties: u8,
+        batch_size: usize,
         senders: HashMap<NodeId, UnboundedSender<DealerEvents>>,
         receiver: UnboundedReceiver<(NodeId, DealerCommands)>,
     ) -> Self {
         let a = Elem::random(rand::thread_rng());
         Self {
             n_parties,
+            batch_size,
             alpha: (a, shares::elems_from_secret(&a, n_parties)),
             beavers: HashMap::new(),
+            bit_masks: HashMap::new(),
+            randoms: HashMap::new(),
+            truncation_masks: HashMap::new(),
             variables_owned: HashSet::new(),
             senders,
             receiver,
         }
     }
 
-    pub async fn run(mut self) {
-        loop {
-            match self.receiver.recv().await {
-                Some((node_id, DealerCommands::BeaverFor(cir_id))) => {
-                    let shares = match self.beavers.entry(cir_id.clone()) {
-                        Entry::Occupied(o) => o.into_mut(),
-                        Entry::Vacant(v) => {
-                            v.insert(shares::random_beaver(&self.alpha.1, self.n_parties))
-                        }
-                    };
+    /// Serves commands until all nodes finish the session, fails on a command no honest node sends
+    pub async fn run(mut self) -> Result<(), ProtocolError> {
+        // all nodes finished the session
+        while let Some((node_id, command)) = self.receiver.recv().await {
+            self.handle(node_id, command)?;
+        }
+        Ok(())
+    }
 
-                    if let Some(sender) = self.senders.get(&node_id) {
-                        sender
-                            .send(DealerEvents::BeaverSharesFor(
-                                cir_id,
-                                *shares
-                                    .get(node_id as usize)
-                                    .expect("Dealer shoud have share"),
-                            ))
-                            .expect("Dealer shoud have sender");
-                    }
-                }
-                Some((node_id, DealerCommands::NeedAlpha)) => {
-                    if let Some(sender) = self.senders.get(&node_id) {
-                        sender
-                            .send(DealerEvents::Alpha(self.alpha.1[node_id as usize]))
-                            .expect("Dealer shoud have sender");
-                    }
+    fn handle(&mut self, node_id: NodeId, command: DealerCommands) -> Result<(), ProtocolError> {
+        if node_id >= self.n_parties as NodeId {
+            return Err(ProtocolError::ProtocolViolation(
+                node_id,
+                "unknown node".to_string(),
+            ));
+        }
+        let party = node_id as usize;
+        match command {
+            DealerCommands::BeaverFor(cir_id) => {
+                let shares = match self.beavers.entry(cir_id.clone()) {
+                    Entry::Occupied(o) => o.into_mut(),
+                    Entry::Vacant(v) => v.insert(rows(self.n_parties, self.batch_size, || {
+                        shares::random_beaver(&self.alpha.1, self.n_parties)
+                    })),
+                };
+                let event = DealerEvents::BeaverSharesFor(cir_id, shares[party].clone());
+                self.send(node_id, event);
+            }
+            DealerCommands::BitMaskFor(cir_id, n_bits) => {
+                Self::check_mask_bits(node_id, &cir_id, n_bits)?;
+                let masks = match self.bit_masks.entry(cir_id.clone()) {
+                    Entry::Occupied(o) => o.into_mut(),
+                    Entry::Vacant(v) => v.insert(rows(self.n_parties, self.batch_size, || {
+                        shares::random_bit_mask(&self.alpha.1, self.n_parties, n_bits)
+                    })),
+                };
+                let event = DealerEvents::BitMaskSharesFor(cir_id, masks[party].clone());
+                self.send(node_id, event);
+            }
+            DealerCommands::RandomFor(cir_id) => {
+                let shares = match self.randoms.entry(cir_id.clone()) {
+                    Entry::Occupied(o) => o.into_mut(),
+                    Entry::Vacant(v) => v.insert(rows(self.n_parties, self.batch_size, || {
+                        shares::shares_from_secret(
+                            &Elem::random(rand::thread_rng()),
+                            &self.alpha.1,
+                            self.n_parties,
+                        )
+                    })),
+                };
+                let event = DealerEvents::RandomShareFor(cir_id, shares[party].clone());
+                self.send(node_id, event);
+            }
+            DealerCommands::TruncationMaskFor(cir_id, n_bits) => {
+                Self::check_mask_bits(node_id, &cir_id, n_bits)?;
+                let masks = match self.truncation_masks.entry(cir_id.clone()) {
+                    Entry::Occupied(o) => o.into_mut(),
+                    Entry::Vacant(v) => v.insert(rows(self.n_parties, self.batch_size, || {
+                        shares::random_truncation_mask(
+                            &self.alpha.1,
+                            self.n_parties,
+                            n_bits,
+                            TRUNCATION_BITS,
+                        )
+                    })),
+                };
+                let event = DealerEvents::TruncationMaskSharesFor(cir_id, masks[party].clone());
+                self.send(node_id, event);
+            }
+            DealerCommands::NeedAlpha => {
+                self.send(node_id, DealerEvents::Alpha(self.alpha.1[party]));
+            }
+            DealerCommands::NodeOpenSelfInput(cir_id) => {
+                if !self.variables_owned.insert(cir_id.clone()) {
+                    return Ok(());
                 }
-                Some((node_id, DealerCommands::NodeOpenSelfInput(cir_id))) => {
-                    if self.variables_owned.insert(cir_id.clone()) {
-                        let r = Elem::random(rand::thread_rng());
-                        let shares = shares::shares_from_secret(&r, &self.alpha.1, self.n_parties);
-                        for (i, share) in shares.iter().enumerate() {
-                            if i != (node_id as usize) {
-                                if let Some(sender) = self.senders.get(&(i as u64)) {
-                                    sender
-                                        .send(DealerEvents::NodeVariableShared(
-                                            cir_id.clone(),
-                                            *share,
-                                        ))
-                                        .expect("Dealer shoud have sender");
-                                }
-                            }
-                        }
-                        if let Some(sender) = self.senders.get(&node_id) {
-                            sender
-                                .send(DealerEvents::NodeSelfVariable(
-                                    cir_id.clone(),
-                                    r,
-                                    shares[node_id as usize],
-                                ))
-                                .expect("Dealer shoud have sender");
-                        }
+                let r = (0..self.batch_size)
+                    .map(|_| Elem::random(rand::thread_rng()))
+                    .collect::<Vec<_>>();
+                let mut masks = r.iter();
+                let shares = rows(self.n_parties, self.batch_size, || {
+                    // `rows` asks for exactly one row per mask
+                    let r = masks.next().unwrap();
+                    shares::shares_from_secret(r, &self.alpha.1, self.n_parties)
+                });
+                for (i, share) in shares.iter().enumerate() {
+                    if i != party {
+                        let event = DealerEvents::NodeVariableShared(cir_id.clone(), share.clone());
+                        self.send(i as NodeId, event);
                     }
                 }
-                None => {}
-            };
+                let event = DealerEvents::NodeSelfVariable(cir_id, r, shares[party].clone());
+                self.send(node_id, event);
+            }
+        }
+        Ok(())
+    }
+
+    /// Masks wider than values the nodes compute on are never requested by honest nodes
+    fn check_mask_bits(node_id: NodeId, cir_id: &CirId, n_bits: u32) -> Result<(), ProtocolError> {
+        if n_bits > TRUNCATION_BITS {
+            return Err(ProtocolError::ProtocolViolation(
+                node_id,
+                format!("asked for mask of {} bits for {}", n_bits, cir_id),
+            ));
+        }
+        Ok(())
+    }
+
+    fn send(&self, node_id: NodeId, event: DealerEvents) {
+        if let Some(sender) = self.senders.get(&node_id) {
+            // node that finished or aborted doesn't need our values anymore
+            if sender.send(event).is_err() {
+                log::debug!("node {} is gone", node_id);
+            }
         }
     }
 }
@@ -0,0 +1,100 @@
+use crate::protocol::{CirId, NodeId, Phase};
+use std::fmt;
+
+/// Misbehaviour of a node detected during the session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Offence {
+    /// opened other commitment for cir_id than the one it committed to
+    CommitmentMismatch(CirId),
+    /// sent different shares to different nodes among values checked under cir_id
+    Equivocation(CirId),
+    /// sent its message for cir_id more than once
+    DoubleSend(CirId),
+    /// reported other digests of our shares than the ones we sent before check of cir_id
+    ForgedDigests(CirId),
+}
+
+impl fmt::Display for Offence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Offence::CommitmentMismatch(cir_id) => {
+                write!(f, "opened wrong commitment for {}", cir_id)
+            }
+            Offence::Equivocation(cir_id) => {
+                write!(
+                    f,
+                    "sent different shares to different nodes before {}",
+                    cir_id
+                )
+            }
+            Offence::DoubleSend(cir_id) => write!(f, "sent message for {} twice", cir_id),
+            Offence::ForgedDigests(cir_id) => {
+                write!(f, "reported forged digests before {}", cir_id)
+            }
+        }
+    }
+}
+
+/// Nodes caught cheating with their offences, lets operators exclude them from next sessions
+pub type BlameReport = Vec<(NodeId, Offence)>;
+
+/// Reason why a node aborted the session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// partial openings of macs of values checked under cir_id don't sum to zero
+    MacCheckFailed(CirId),
+    /// nodes were caught cheating
+    Cheating(BlameReport),
+    /// value of cir_id node that should be a bit is not a bit
+    BitCheckFailed(CirId),
+    /// other node aborted after its check of cir_id failed
+    AbortedByPeer(CirId),
+    /// node sent a message that breaks the protocol
+    ProtocolViolation(NodeId, String),
+    /// given channel closed before the session finished
+    ChannelClosed(String),
+    /// given phase did not finish in time, with nodes that did not deliver messages for cir_ids
+    /// that others already sent
+    Timeout(Phase, Vec<(NodeId, CirId)>),
+    /// outputs or variables of the session are invalid
+    InvalidInput(String),
+    /// revealed output can't be decoded into requested type
+    InvalidOutput(String),
+    /// task running the node failed
+    Crashed(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::MacCheckFailed(cir_id) => write!(f, "mac check of {} failed", cir_id),
+            ProtocolError::Cheating(report) => {
+                write!(f, "caught cheating")?;
+                for (node_id, offence) in report {
+                    write!(f, ", node {} {}", node_id, offence)?;
+                }
+                Ok(())
+            }
+            ProtocolError::BitCheckFailed(cir_id) => write!(f, "value of {} is not a bit", cir_id),
+            ProtocolError::AbortedByPeer(cir_id) => {
+                write!(f, "other node aborted after check of {}", cir_id)
+            }
+            ProtocolError::ProtocolViolation(node_id, reason) => {
+                write!(f, "node {} violated the protocol: {}", node_id, reason)
+            }
+            ProtocolError::ChannelClosed(channel) => write!(f, "{} channel closed", channel),
+            ProtocolError::Timeout(phase, missing) => {
+                write!(f, "timed out in {:?} phase", phase)?;
+                for (node_id, cir_id) in missing {
+                    write!(f, ", node {} did not send {}", node_id, cir_id)?;
+                }
+                Ok(())
+            }
+            ProtocolError::InvalidInput(reason) => write!(f, "invalid input: {}", reason),
+            ProtocolError::InvalidOutput(reason) => write!(f, "invalid output: {}", reason),
+            ProtocolError::Crashed(reason) => write!(f, "node crashed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
@@ -1,6 +1,12 @@
-use crate::crypto::shares::Elem;
+use crate::crypto::shares::{self, Elem};
 use crate::expressions::{BinaryOp, Expression};
-use crate::protocol::{CirId, NodeId, Provider, VarId};
+use ff::Field;
+use std::collections::HashMap;
+
+use crate::protocol::{
+    check_fractional_bits, floor_div, sub_id, CirId, NodeId, Provider, VarId, COMPARISON_BITS,
+    DIVISION_BITS,
+};
 
 type BExpression = Box<DecoratedExpression>;
 
@@ -11,15 +17,36 @@ pub enum DecoratedExpression {
     AddConstant(Elem, BExpression, CirId),
     /// addition of two nonconst expressions
     Add(BExpression, BExpression, CirId),
+    /// subtraction of two nonconst expressions
+    Sub(BExpression, BExpression, CirId),
+    /// negation of nonconst expression
+    Neg(BExpression, CirId),
+    /// shared bit equal to 1 iff nonconst expression is negative
+    LessThanZero(BExpression, CirId),
+    /// shared bit equal to 1 iff nonconst expression is zero
+    EqualsZero(BExpression, CirId),
+    /// nonconst expression checked at runtime to be 0 or 1, aborts otherwise
+    AssertBit(BExpression, CirId),
+    /// nonconst expression divided by 2^bits, rounded up or down at random
+    Truncate(BExpression, u32, CirId),
+    /// nonconst expression raised to exponent >= 2, see `power_steps`.
+    /// Products are truncated by given number of bits (0 for integers).
+    Pow(BExpression, u32, u32, CirId),
     /// multiplication of two nonconst expression
     Mul(BExpression, BExpression, CirId),
     /// multiplication of constant to nonconst expression
     MulConstant(Elem, BExpression, CirId),
+    /// exact division of nonconst expression by nonzero constant
+    DivConstant(Elem, BExpression, CirId),
     /// node representing variable belonging to node
     Var(NodeId, VarId, CirId),
     /// constant node, only used during preprocessing phase(unless whole raw expression was constant)
     /// or constant equivalent (without any variables).
     Constant(Elem, CirId),
+    /// value evaluated once before body, which uses it through `Ref`
+    Let(BExpression, BExpression),
+    /// reference to value of node bound by enclosing `Let`
+    Ref(CirId),
 }
 
 /// Expression repr after processing, all non const expressions are removed and substituted for ids
@@ -27,34 +54,313 @@ pub enum DecoratedExpression {
 pub enum MidEvalExpression {
     AddConstant(Elem, CirId, CirId),
     Add(CirId, CirId, CirId),
+    Sub(CirId, CirId, CirId),
+    Neg(CirId, CirId),
+    LessThanZero(CirId, CirId),
+    EqualsZero(CirId, CirId),
+    AssertBit(CirId, CirId),
+    Truncate(CirId, u32, CirId),
+    Pow(CirId, u32, u32, CirId),
     MulConstant(Elem, CirId, CirId),
+    DivConstant(Elem, CirId, CirId),
     Mul(CirId, CirId, CirId),
     Var(CirId),
 }
 
+/// Equality test needs one bit more than comparison, see `Node::equals_zero`
+const EQUALITY_BITS: u32 = COMPARISON_BITS + 1;
+
+/// Ids of multiplications computing x * (x - 1) and its product with random value
+/// in bit check of node `cir_id`
+pub fn bit_check_ids(cir_id: &CirId) -> Vec<CirId> {
+    vec![
+        sub_id(cir_id, &"square".to_string()),
+        sub_id(cir_id, &"randomize".to_string()),
+    ]
+}
+
+/// Levels of ORs computing f_i = d_i or d_{i+1} or ... or d_{n-1} for all `n_bits` bits,
+/// as pairs (i, j) setting f_i to f_i or f_j. Counting bits from the most significant one,
+/// level l joins each block of 2^l bits with the last bit of the block before it, so after
+/// ceil(log2(n_bits)) levels every bit is joined with all more significant ones.
+/// ORs of one level are independent.
+pub fn prefix_or_steps(n_bits: u32) -> Vec<Vec<(usize, usize)>> {
+    let n = n_bits as usize;
+    let mut levels = vec![];
+    let mut block = 1;
+    while block < n {
+        // position from the most significant bit
+        let level = (0..n)
+            .filter(|from_top| from_top & block != 0)
+            .map(|from_top| (n - 1 - from_top, n - (from_top & !(block - 1))))
+            .collect();
+        levels.push(level);
+        block *= 2;
+    }
+
+    levels
+}
+
+/// Ids of multiplications computing prefix OR over `n_bits` bits of node `cir_id`,
+/// in order of `prefix_or_steps`
+pub fn prefix_or_ids(cir_id: &CirId, n_bits: u32) -> Vec<CirId> {
+    prefix_or_steps(n_bits)
+        .into_iter()
+        .enumerate()
+        .flat_map(|(level, ors)| {
+            ors.into_iter()
+                .map(move |(i, _)| sub_id(cir_id, &format!("or-{}-{}", level, i)))
+        })
+        .collect()
+}
+
+/// Multiplications computing x^exponent by square-and-multiply, as pairs of indices of factors.
+/// Factor 0 is x and i-th multiplication produces factor i + 1, the last one is the result.
+/// Powers x^(2^i) are computed by repeated squaring and the ones needed for exponent are
+/// multiplied always picking two of the least depth, which gives minimal multiplicative depth
+/// ceil(log2(exponent)).
+pub fn power_steps(exponent: u32) -> Vec<(usize, usize)> {
+    let n_bits = u32::BITS - exponent.leading_zeros();
+    let mut steps = vec![];
+
+    // (factor, depth) of x^(2^i)
+    let mut squares = vec![(0, 0)];
+    for depth in 1..n_bits {
+        let (previous, _) = squares[squares.len() - 1];
+        steps.push((previous, previous));
+        squares.push((steps.len(), depth));
+    }
+
+    let mut terms = (0..n_bits)
+        .filter(|i| (exponent >> i) & 1 == 1)
+        .map(|i| squares[i as usize])
+        .collect::<Vec<_>>();
+    while terms.len() > 1 {
+        terms.sort_by_key(|(_, depth)| std::cmp::Reverse(*depth));
+        let (a, a_depth) = terms.pop().expect("at least two");
+        let (b, b_depth) = terms.pop().expect("at least two");
+        steps.push((a, b));
+        terms.push((steps.len(), a_depth.max(b_depth) + 1));
+    }
+
+    steps
+}
+
+/// Computes constant power with the same multiplications and truncations as `Node`
+pub fn fold_power(base: Elem, exponent: u32, n_bits: u32) -> Elem {
+    let mut factors = vec![base];
+    for (a, b) in power_steps(exponent) {
+        factors.push(shares::truncate(&(factors[a] * factors[b]), n_bits));
+    }
+
+    factors.pop().expect("at least base")
+}
+
+/// Multiplicative depth of x^exponent computed by `power_steps`
+fn power_depth(exponent: u32) -> usize {
+    (u32::BITS - exponent.saturating_sub(1).leading_zeros()) as usize
+}
+
+/// Ids of multiplications computing power of node `cir_id`, in order of `power_steps`
+pub fn power_ids(cir_id: &CirId, exponent: u32) -> Vec<CirId> {
+    (0..power_steps(exponent).len())
+        .map(|i| sub_id(cir_id, &format!("pow-{}", i)))
+        .collect()
+}
+
 impl MidEvalExpression {
+    /// returns ids of nodes whose values are used to evaluate this node
+    pub fn operand_ids(&self) -> Vec<&CirId> {
+        match self {
+            MidEvalExpression::Add(e1, e2, _)
+            | MidEvalExpression::Sub(e1, e2, _)
+            | MidEvalExpression::Mul(e1, e2, _) => vec![e1, e2],
+            MidEvalExpression::AddConstant(_, e, _)
+            | MidEvalExpression::Neg(e, _)
+            | MidEvalExpression::LessThanZero(e, _)
+            | MidEvalExpression::EqualsZero(e, _)
+            | MidEvalExpression::AssertBit(e, _)
+            | MidEvalExpression::Truncate(e, _, _)
+            | MidEvalExpression::Pow(e, _, _, _)
+            | MidEvalExpression::MulConstant(_, e, _)
+            | MidEvalExpression::DivConstant(_, e, _) => vec![e],
+            MidEvalExpression::Var(_) => vec![],
+        }
+    }
+
+    /// returns true if evaluating the node needs communication with other nodes
+    pub fn is_interactive(&self) -> bool {
+        matches!(
+            self,
+            MidEvalExpression::Mul(_, _, _)
+                | MidEvalExpression::LessThanZero(_, _)
+                | MidEvalExpression::EqualsZero(_, _)
+                | MidEvalExpression::AssertBit(_, _)
+                | MidEvalExpression::Truncate(_, _, _)
+                | MidEvalExpression::Pow(_, _, _, _)
+        )
+    }
+
     /// returns circuit node id
     pub fn cir_id(&self) -> CirId {
         match self {
             MidEvalExpression::AddConstant(_, _, id) => id.clone(),
             MidEvalExpression::Add(_, _, id) => id.clone(),
+            MidEvalExpression::Sub(_, _, id) => id.clone(),
+            MidEvalExpression::Neg(_, id) => id.clone(),
+            MidEvalExpression::LessThanZero(_, id) => id.clone(),
+            MidEvalExpression::EqualsZero(_, id) => id.clone(),
+            MidEvalExpression::AssertBit(_, id) => id.clone(),
+            MidEvalExpression::Truncate(_, _, id) => id.clone(),
+            MidEvalExpression::Pow(_, _, _, id) => id.clone(),
             MidEvalExpression::Mul(_, _, id) => id.clone(),
             MidEvalExpression::MulConstant(_, _, id) => id.clone(),
+            MidEvalExpression::DivConstant(_, _, id) => id.clone(),
             MidEvalExpression::Var(id) => id.clone(),
         }
     }
 }
 
+/// Splits nodes in evaluation order (see `DecoratedExpression::into_ordered`) into layers.
+/// Node is in layer l if interactive nodes it depends on are all in earlier layers,
+/// so interactive nodes of one layer are independent and can be evaluated in the same round.
+/// There are at most as many layers as one more than multiplicative depth of the circuit,
+/// counting comparisons and powers as single multiplications. Order of nodes in a layer is kept.
+pub fn into_layers(ordered: Vec<MidEvalExpression>) -> Vec<Vec<MidEvalExpression>> {
+    // layer after which value of node is known
+    let mut known: HashMap<CirId, usize> = HashMap::new();
+    let mut layers: Vec<Vec<MidEvalExpression>> = vec![];
+
+    for node in ordered {
+        let layer = node
+            .operand_ids()
+            .into_iter()
+            .map(|id| *known.get(id).unwrap_or(&0))
+            .max()
+            .unwrap_or(0);
+        known.insert(node.cir_id(), layer + node.is_interactive() as usize);
+
+        if layers.len() <= layer {
+            layers.resize_with(layer + 1, Vec::new);
+        }
+        layers[layer].push(node);
+    }
+
+    layers
+}
+
 impl DecoratedExpression {
+    pub fn is_constant(&self) -> bool {
+        matches!(self, DecoratedExpression::Constant(_, _))
+    }
+
     /// returns circuit node id
     pub fn cir_id(&self) -> CirId {
         match self {
             DecoratedExpression::AddConstant(_, _, id) => id.clone(),
             DecoratedExpression::Add(_, _, id) => id.clone(),
+            DecoratedExpression::Sub(_, _, id) => id.clone(),
+            DecoratedExpression::Neg(_, id) => id.clone(),
+            DecoratedExpression::LessThanZero(_, id) => id.clone(),
+            DecoratedExpression::EqualsZero(_, id) => id.clone(),
+            DecoratedExpression::AssertBit(_, id) => id.clone(),
+            DecoratedExpression::Truncate(_, _, id) => id.clone(),
+            DecoratedExpression::Pow(_, _, _, id) => id.clone(),
             DecoratedExpression::Mul(_, _, id) => id.clone(),
             DecoratedExpression::MulConstant(_, _, id) => id.clone(),
+            DecoratedExpression::DivConstant(_, _, id) => id.clone(),
             DecoratedExpression::Var(_, _, id) => id.clone(),
             DecoratedExpression::Constant(_, id) => id.clone(),
+            DecoratedExpression::Let(_, body) => body.cir_id(),
+            DecoratedExpression::Ref(id) => id.clone(),
+        }
+    }
+
+    /// returns direct subexpressions, value of `Let` first
+    pub fn children(&self) -> Vec<&DecoratedExpression> {
+        match self {
+            DecoratedExpression::Add(e1, e2, _)
+            | DecoratedExpression::Sub(e1, e2, _)
+            | DecoratedExpression::Mul(e1, e2, _)
+            | DecoratedExpression::Let(e1, e2) => vec![e1, e2],
+            DecoratedExpression::AddConstant(_, e, _)
+            | DecoratedExpression::Neg(e, _)
+            | DecoratedExpression::LessThanZero(e, _)
+            | DecoratedExpression::EqualsZero(e, _)
+            | DecoratedExpression::AssertBit(e, _)
+            | DecoratedExpression::Truncate(e, _, _)
+            | DecoratedExpression::Pow(e, _, _, _)
+            | DecoratedExpression::MulConstant(_, e, _)
+            | DecoratedExpression::DivConstant(_, e, _) => vec![e],
+            DecoratedExpression::Var(_, _, _)
+            | DecoratedExpression::Constant(_, _)
+            | DecoratedExpression::Ref(_) => vec![],
+        }
+    }
+
+    /// returns number of sequential communication rounds needed to evaluate expression
+    /// if independent nodes were evaluated in parallel. Sharing an input, multiplication
+    /// and opening of a masked value take one round each.
+    pub fn rounds(&self) -> usize {
+        self.rounds_with(&mut HashMap::new())
+    }
+
+    /// same as `rounds`, rounds of values referenced by `Ref` are taken from `bound`
+    pub fn rounds_with(&self, bound: &mut HashMap<CirId, usize>) -> usize {
+        self.critical_path(bound, &|expr| match expr {
+            DecoratedExpression::Var(_, _, _) => 1,
+            DecoratedExpression::Mul(_, _, _) => 1,
+            // opening of masked value and prefix OR over its bits
+            DecoratedExpression::LessThanZero(_, _) => 1 + prefix_or_steps(COMPARISON_BITS).len(),
+            DecoratedExpression::EqualsZero(_, _) => 1 + prefix_or_steps(EQUALITY_BITS).len(),
+            // two multiplications and opening
+            DecoratedExpression::AssertBit(_, _) => 3,
+            DecoratedExpression::Truncate(_, _, _) => 1,
+            DecoratedExpression::Pow(_, exponent, n_bits, _) => {
+                let truncation = (*n_bits > 0) as usize;
+                power_depth(*exponent) * (1 + truncation)
+            }
+            _ => 0,
+        })
+    }
+
+    /// returns multiplicative depth of expression, i.e. the largest number of
+    /// multiplications that have to be done one after another
+    pub fn depth(&self) -> usize {
+        self.critical_path(&mut HashMap::new(), &|expr| match expr {
+            DecoratedExpression::Mul(_, _, _) => 1,
+            DecoratedExpression::LessThanZero(_, _) => prefix_or_steps(COMPARISON_BITS).len(),
+            DecoratedExpression::EqualsZero(_, _) => prefix_or_steps(EQUALITY_BITS).len(),
+            DecoratedExpression::AssertBit(_, _) => 2,
+            DecoratedExpression::Pow(_, exponent, _, _) => power_depth(*exponent),
+            _ => 0,
+        })
+    }
+
+    /// returns the largest sum of `cost` of nodes on a path from a leaf to the root,
+    /// values referenced by `Ref` are counted once with costs taken from `bound`
+    fn critical_path(
+        &self,
+        bound: &mut HashMap<CirId, usize>,
+        cost: &dyn Fn(&DecoratedExpression) -> usize,
+    ) -> usize {
+        match self {
+            DecoratedExpression::Let(value, body) => {
+                let value_cost = value.critical_path(bound, cost);
+                bound.insert(value.cir_id(), value_cost);
+                body.critical_path(bound, cost)
+            }
+            DecoratedExpression::Ref(cir_id) => *bound.get(cir_id).unwrap_or(&0),
+            _ => {
+                let operands = self
+                    .children()
+                    .iter()
+                    .map(|e| e.critical_path(&mut bound.clone(), cost))
+                    .max()
+                    .unwrap_or(0);
+
+                operands + cost(self)
+            }
         }
     }
 
@@ -62,18 +368,144 @@ impl DecoratedExpression {
     pub fn mul_ids(&self) -> Vec<CirId> {
         match self {
             DecoratedExpression::AddConstant(_, e, _) => e.mul_ids(),
-            DecoratedExpression::Add(e1, e2, _) => {
+            DecoratedExpression::Add(e1, e2, _) | DecoratedExpression::Sub(e1, e2, _) => {
                 let mut x = e1.mul_ids();
                 x.extend(e2.mul_ids());
                 x
             }
+            DecoratedExpression::Neg(e, _) => e.mul_ids(),
+            DecoratedExpression::LessThanZero(e, cir_id) => {
+                let mut x = e.mul_ids();
+                x.extend(prefix_or_ids(cir_id, COMPARISON_BITS));
+                x
+            }
+            DecoratedExpression::EqualsZero(e, cir_id) => {
+                let mut x = e.mul_ids();
+                x.extend(prefix_or_ids(cir_id, EQUALITY_BITS));
+                x
+            }
+            DecoratedExpression::AssertBit(e, cir_id) => {
+                let mut x = e.mul_ids();
+                x.extend(bit_check_ids(cir_id));
+                x
+            }
             DecoratedExpression::Mul(e1, e2, cir_id) => {
                 let mut x = e1.mul_ids();
                 x.extend(e2.mul_ids());
                 x.push(cir_id.clone());
                 x
             }
-            DecoratedExpression::MulConstant(_, e, _) => e.mul_ids(),
+            DecoratedExpression::Pow(e, exponent, _, cir_id) => {
+                let mut x = e.mul_ids();
+                x.extend(power_ids(cir_id, *exponent));
+                x
+            }
+            DecoratedExpression::MulConstant(_, e, _)
+            | DecoratedExpression::DivConstant(_, e, _)
+            | DecoratedExpression::Truncate(e, _, _) => e.mul_ids(),
+            DecoratedExpression::Let(value, body) => {
+                let mut x = value.mul_ids();
+                x.extend(body.mul_ids());
+                x
+            }
+            _ => vec![],
+        }
+    }
+
+    /// returns all ids of nodes that need a mask of random bits together with number of bits
+    pub fn bit_mask_ids(&self) -> Vec<(CirId, u32)> {
+        match self {
+            DecoratedExpression::AddConstant(_, e, _)
+            | DecoratedExpression::MulConstant(_, e, _)
+            | DecoratedExpression::DivConstant(_, e, _)
+            | DecoratedExpression::Neg(e, _)
+            | DecoratedExpression::AssertBit(e, _)
+            | DecoratedExpression::Truncate(e, _, _)
+            | DecoratedExpression::Pow(e, _, _, _) => e.bit_mask_ids(),
+            DecoratedExpression::Add(e1, e2, _)
+            | DecoratedExpression::Sub(e1, e2, _)
+            | DecoratedExpression::Mul(e1, e2, _)
+            | DecoratedExpression::Let(e1, e2) => {
+                let mut x = e1.bit_mask_ids();
+                x.extend(e2.bit_mask_ids());
+                x
+            }
+            DecoratedExpression::LessThanZero(e, cir_id) => {
+                let mut x = e.bit_mask_ids();
+                x.push((cir_id.clone(), COMPARISON_BITS));
+                x
+            }
+            DecoratedExpression::EqualsZero(e, cir_id) => {
+                let mut x = e.bit_mask_ids();
+                x.push((cir_id.clone(), EQUALITY_BITS));
+                x
+            }
+            _ => vec![],
+        }
+    }
+
+    /// returns all ids of nodes that need a random shared value
+    pub fn random_ids(&self) -> Vec<CirId> {
+        match self {
+            DecoratedExpression::AddConstant(_, e, _)
+            | DecoratedExpression::MulConstant(_, e, _)
+            | DecoratedExpression::DivConstant(_, e, _)
+            | DecoratedExpression::Neg(e, _)
+            | DecoratedExpression::LessThanZero(e, _)
+            | DecoratedExpression::EqualsZero(e, _)
+            | DecoratedExpression::Truncate(e, _, _)
+            | DecoratedExpression::Pow(e, _, _, _) => e.random_ids(),
+            DecoratedExpression::Add(e1, e2, _)
+            | DecoratedExpression::Sub(e1, e2, _)
+            | DecoratedExpression::Mul(e1, e2, _)
+            | DecoratedExpression::Let(e1, e2) => {
+                let mut x = e1.random_ids();
+                x.extend(e2.random_ids());
+                x
+            }
+            DecoratedExpression::AssertBit(e, cir_id) => {
+                let mut x = e.random_ids();
+                x.push(cir_id.clone());
+                x
+            }
+            _ => vec![],
+        }
+    }
+
+    /// returns all ids of nodes that need a truncation mask together with number of truncated bits
+    pub fn truncation_mask_ids(&self) -> Vec<(CirId, u32)> {
+        match self {
+            DecoratedExpression::AddConstant(_, e, _)
+            | DecoratedExpression::MulConstant(_, e, _)
+            | DecoratedExpression::DivConstant(_, e, _)
+            | DecoratedExpression::Neg(e, _)
+            | DecoratedExpression::LessThanZero(e, _)
+            | DecoratedExpression::EqualsZero(e, _)
+            | DecoratedExpression::AssertBit(e, _) => e.truncation_mask_ids(),
+            DecoratedExpression::Add(e1, e2, _)
+            | DecoratedExpression::Sub(e1, e2, _)
+            | DecoratedExpression::Mul(e1, e2, _)
+            | DecoratedExpression::Let(e1, e2) => {
+                let mut x = e1.truncation_mask_ids();
+                x.extend(e2.truncation_mask_ids());
+                x
+            }
+            DecoratedExpression::Truncate(e, n_bits, cir_id) => {
+                let mut x = e.truncation_mask_ids();
+                x.push((cir_id.clone(), *n_bits));
+                x
+            }
+            DecoratedExpression::Pow(e, exponent, n_bits, cir_id) => {
+                let mut x = e.truncation_mask_ids();
+                if *n_bits > 0 {
+                    x.extend(
+                        power_ids(cir_id, *exponent)
+                            .into_iter()
+                            .map(|id| (id, *n_bits)),
+                    );
+                }
+                x
+            }
             _ => vec![],
         }
     }
@@ -82,17 +514,24 @@ impl DecoratedExpression {
     pub fn self_var_ids(&self, node_id: Option<NodeId>) -> Vec<(CirId, VarId)> {
         match self {
             DecoratedExpression::AddConstant(_, e, _) => e.self_var_ids(node_id),
-            DecoratedExpression::Add(e1, e2, _) => {
+            DecoratedExpression::Add(e1, e2, _) | DecoratedExpression::Sub(e1, e2, _) => {
                 let mut x = e1.self_var_ids(node_id);
                 x.extend(e2.self_var_ids(node_id));
                 x
             }
-            DecoratedExpression::Mul(e1, e2, _) => {
+            DecoratedExpression::Neg(e, _)
+            | DecoratedExpression::LessThanZero(e, _)
+            | DecoratedExpression::EqualsZero(e, _)
+            | DecoratedExpression::AssertBit(e, _)
+            | DecoratedExpression::Truncate(e, _, _)
+            | DecoratedExpression::Pow(e, _, _, _) => e.self_var_ids(node_id),
+            DecoratedExpression::Mul(e1, e2, _) | DecoratedExpression::Let(e1, e2) => {
                 let mut x = e1.self_var_ids(node_id);
                 x.extend(e2.self_var_ids(node_id));
                 x
             }
-            DecoratedExpression::MulConstant(_, e, _) => e.self_var_ids(node_id),
+            DecoratedExpression::MulConstant(_, e, _)
+            | DecoratedExpression::DivConstant(_, e, _) => e.self_var_ids(node_id),
             DecoratedExpression::Var(other, var_id, cir_id) => {
                 if node_id.is_none() || node_id.expect("not none") == *other {
                     vec![(cir_id.clone(), var_id.clone())]
@@ -100,7 +539,7 @@ impl DecoratedExpression {
                     vec![]
                 }
             }
-            DecoratedExpression::Constant(_, _) => {
+            DecoratedExpression::Constant(_, _) | DecoratedExpression::Ref(_) => {
                 vec![]
             }
         }
@@ -128,6 +567,64 @@ impl DecoratedExpression {
 
                 ord
             }
+            DecoratedExpression::Sub(e1, e2, cir_id) => {
+                let e1_id = e1.cir_id();
+                let e2_id = e2.cir_id();
+
+                let mut ord = e1.into_ordered();
+                ord.extend(e2.into_ordered());
+                ord.push(MidEvalExpression::Sub(e1_id, e2_id, cir_id));
+
+                ord
+            }
+            DecoratedExpression::Neg(e, cir_id) => {
+                let e_cir_id = e.cir_id();
+
+                let mut ord = e.into_ordered();
+                ord.push(MidEvalExpression::Neg(e_cir_id, cir_id));
+
+                ord
+            }
+            DecoratedExpression::LessThanZero(e, cir_id) => {
+                let e_cir_id = e.cir_id();
+
+                let mut ord = e.into_ordered();
+                ord.push(MidEvalExpression::LessThanZero(e_cir_id, cir_id));
+
+                ord
+            }
+            DecoratedExpression::EqualsZero(e, cir_id) => {
+                let e_cir_id = e.cir_id();
+
+                let mut ord = e.into_ordered();
+                ord.push(MidEvalExpression::EqualsZero(e_cir_id, cir_id));
+
+                ord
+            }
+            DecoratedExpression::AssertBit(e, cir_id) => {
+                let e_cir_id = e.cir_id();
+
+                let mut ord = e.into_ordered();
+                ord.push(MidEvalExpression::AssertBit(e_cir_id, cir_id));
+
+                ord
+            }
+            DecoratedExpression::Truncate(e, n_bits, cir_id) => {
+                let e_cir_id = e.cir_id();
+
+                let mut ord = e.into_ordered();
+                ord.push(MidEvalExpression::Truncate(e_cir_id, n_bits, cir_id));
+
+                ord
+            }
+            DecoratedExpression::Pow(e, exponent, n_bits, cir_id) => {
+                let e_cir_id = e.cir_id();
+
+                let mut ord = e.into_ordered();
+                ord.push(MidEvalExpression::Pow(e_cir_id, exponent, n_bits, cir_id));
+
+                ord
+            }
             DecoratedExpression::Mul(e1, e2, cir_id) => {
                 let e1_id = e1.cir_id();
                 let e2_id = e2.cir_id();
@@ -146,6 +643,14 @@ impl DecoratedExpression {
 
                 ord
             }
+            DecoratedExpression::DivConstant(s, e, cir_id) => {
+                let e_cir_id = e.cir_id();
+
+                let mut ord = e.into_ordered();
+                ord.push(MidEvalExpression::DivConstant(s, e_cir_id, cir_id));
+
+                ord
+            }
             DecoratedExpression::Var(_, _, cir_id) => {
                 vec![MidEvalExpression::Var(cir_id)]
             }
@@ -153,73 +658,610 @@ impl DecoratedExpression {
                 // In the decorated expression all constants are only temporary
                 vec![]
             }
+            DecoratedExpression::Let(value, body) => {
+                let mut ord = value.into_ordered();
+                ord.extend(body.into_ordered());
+
+                ord
+            }
+            DecoratedExpression::Ref(_) => {
+                // value is emitted once by `Let` binding it
+                vec![]
+            }
         }
     }
 }
 
-/// Transform raw expression into
-pub fn decorate_expression(
-    expr: Expression<u64>,
+/// Lowers `left + right`, constants are folded or moved into `AddConstant`
+fn add(
+    left: DecoratedExpression,
+    right: DecoratedExpression,
+    id_provider: &mut Provider,
+) -> DecoratedExpression {
+    match (left, right) {
+        (DecoratedExpression::Constant(s1, _), DecoratedExpression::Constant(s2, _)) => {
+            DecoratedExpression::Constant(s1 + s2, id_provider.next())
+        }
+        (DecoratedExpression::Constant(s1, _), x) | (x, DecoratedExpression::Constant(s1, _)) => {
+            DecoratedExpression::AddConstant(s1, Box::new(x), id_provider.next())
+        }
+        (x, y) => DecoratedExpression::Add(Box::new(x), Box::new(y), id_provider.next()),
+    }
+}
+
+/// Lowers `left * right`, constants are folded or moved into `MulConstant`
+fn multiply(
+    left: DecoratedExpression,
+    right: DecoratedExpression,
+    id_provider: &mut Provider,
+) -> DecoratedExpression {
+    match (left, right) {
+        (DecoratedExpression::Constant(s1, _), DecoratedExpression::Constant(s2, _)) => {
+            DecoratedExpression::Constant(s1 * s2, id_provider.next())
+        }
+        (DecoratedExpression::Constant(s1, _), x) | (x, DecoratedExpression::Constant(s1, _)) => {
+            DecoratedExpression::MulConstant(s1, Box::new(x), id_provider.next())
+        }
+        (x, y) => {
+            let id = id_provider.next();
+            DecoratedExpression::Mul(Box::new(x), Box::new(y), id)
+        }
+    }
+}
+
+/// Lowers `left - right`, constants are folded or moved into `AddConstant`
+fn subtract(
+    left: DecoratedExpression,
+    right: DecoratedExpression,
+    id_provider: &mut Provider,
+) -> DecoratedExpression {
+    match (left, right) {
+        (DecoratedExpression::Constant(s1, _), DecoratedExpression::Constant(s2, _)) => {
+            DecoratedExpression::Constant(s1 - s2, id_provider.next())
+        }
+        (DecoratedExpression::Constant(s1, _), x) => {
+            // c - x is computed as c + (-x)
+            let negated = DecoratedExpression::Neg(Box::new(x), id_provider.next());
+            DecoratedExpression::AddConstant(s1, Box::new(negated), id_provider.next())
+        }
+        (x, DecoratedExpression::Constant(s1, _)) => {
+            DecoratedExpression::AddConstant(-s1, Box::new(x), id_provider.next())
+        }
+        (x, y) => DecoratedExpression::Sub(Box::new(x), Box::new(y), id_provider.next()),
+    }
+}
+
+/// Lowers `left < right` as `(left - right) < 0`
+fn less_than(
+    left: DecoratedExpression,
+    right: DecoratedExpression,
+    id_provider: &mut Provider,
+) -> DecoratedExpression {
+    match subtract(left, right, id_provider) {
+        DecoratedExpression::Constant(s, _) => DecoratedExpression::Constant(
+            Elem::from(shares::is_negative(&s) as u64),
+            id_provider.next(),
+        ),
+        x => DecoratedExpression::LessThanZero(Box::new(x), id_provider.next()),
+    }
+}
+
+/// Lowers `left == right` as `(left - right) == 0`
+fn equals(
+    left: DecoratedExpression,
+    right: DecoratedExpression,
+    id_provider: &mut Provider,
+) -> DecoratedExpression {
+    match subtract(left, right, id_provider) {
+        DecoratedExpression::Constant(s, _) => DecoratedExpression::Constant(
+            Elem::from(bool::from(s.is_zero()) as u64),
+            id_provider.next(),
+        ),
+        x => DecoratedExpression::EqualsZero(Box::new(x), id_provider.next()),
+    }
+}
+
+/// Returns true if expression is known to evaluate to 0 or 1 without checking it at runtime
+pub fn is_bit(expr: &DecoratedExpression) -> bool {
+    match expr {
+        DecoratedExpression::Constant(s, _) => s.is_zero().into() || *s == Elem::one(),
+        DecoratedExpression::LessThanZero(_, _)
+        | DecoratedExpression::EqualsZero(_, _)
+        | DecoratedExpression::AssertBit(_, _) => true,
+        DecoratedExpression::Mul(e1, e2, _) => is_bit(e1) && is_bit(e2),
+        // 1 - x
+        DecoratedExpression::AddConstant(s, e, _) => match e.as_ref() {
+            DecoratedExpression::Neg(x, _) => *s == Elem::one() && is_bit(x),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Decorated expression together with information whether its value is a fixed point number
+/// with `Provider::fractional_bits` fractional bits or an integer
+struct Lowered {
+    expr: DecoratedExpression,
+    fixed: bool,
+}
+
+impl Lowered {
+    fn integer(expr: DecoratedExpression) -> Self {
+        Self { expr, fixed: false }
+    }
+}
+
+/// Value of name bound by `let`, constants are substituted and other values referenced
+pub struct Binding {
+    pub name: String,
+    constant: Option<Elem>,
+    cir_id: CirId,
+    fixed: bool,
+    used: bool,
+}
+
+impl Binding {
+    /// Binds value to name, returns also the value to evaluate before its uses unless it is constant
+    fn new(name: String, value: Lowered) -> (Self, Option<DecoratedExpression>) {
+        let binding = Self {
+            name,
+            constant: match value.expr {
+                DecoratedExpression::Constant(s, _) => Some(s),
+                _ => None,
+            },
+            cir_id: value.expr.cir_id(),
+            fixed: value.fixed,
+            used: false,
+        };
+        let value = match binding.constant {
+            Some(_) => None,
+            None => Some(value.expr),
+        };
+
+        (binding, value)
+    }
+
+    /// Lowers use of bound name, substituted constant gets given id
+    fn reference(&mut self, id: CirId) -> Lowered {
+        self.used = true;
+        let expr = match self.constant {
+            Some(s) => DecoratedExpression::Constant(s, id),
+            None => DecoratedExpression::Ref(self.cir_id.clone()),
+        };
+
+        Lowered {
+            expr,
+            fixed: self.fixed,
+        }
+    }
+}
+
+/// Wraps body in `Let` evaluating bound value first
+fn with_value(value: Option<DecoratedExpression>, body: Lowered) -> Lowered {
+    match value {
+        Some(value) if !body.expr.is_constant() => Lowered {
+            expr: DecoratedExpression::Let(Box::new(value), Box::new(body.expr)),
+            fixed: body.fixed,
+        },
+        _ => body,
+    }
+}
+
+/// Lowers `let name = value in body`, value is bound only if body uses it
+fn bind(
+    name: String,
+    value: Expression<u64>,
+    body: Expression<u64>,
+    id_provider: &mut Provider,
+) -> Result<Lowered, String> {
+    let (binding, value) = Binding::new(name, lower(value, id_provider)?);
+
+    id_provider.bind(binding);
+    let body = lower(body, id_provider);
+    let binding = id_provider.unbind();
+    let body = body?;
+
+    Ok(if binding.used {
+        with_value(value, body)
+    } else {
+        body
+    })
+}
+
+/// Encodes integer expression as a fixed point one
+fn to_fixed(expr: DecoratedExpression, id_provider: &mut Provider) -> DecoratedExpression {
+    let one = shares::pow2(id_provider.fractional_bits());
+
+    match expr {
+        DecoratedExpression::Constant(s, _) => {
+            DecoratedExpression::Constant(s * one, id_provider.next())
+        }
+        x => DecoratedExpression::MulConstant(one, Box::new(x), id_provider.next()),
+    }
+}
+
+/// Lowers `op` applied to operands brought to the same encoding,
+/// result is fixed point if any of operands is
+fn aligned(
+    op: fn(DecoratedExpression, DecoratedExpression, &mut Provider) -> DecoratedExpression,
+    left: Lowered,
+    right: Lowered,
+    id_provider: &mut Provider,
+) -> Lowered {
+    let fixed = left.fixed || right.fixed;
+    let (left, right) = match (left.fixed, right.fixed) {
+        (true, false) => (left.expr, to_fixed(right.expr, id_provider)),
+        (false, true) => (to_fixed(left.expr, id_provider), right.expr),
+        _ => (left.expr, right.expr),
+    };
+
+    Lowered {
+        expr: op(left, right, id_provider),
+        fixed,
+    }
+}
+
+/// Lowers `left * right`, product of two fixed point numbers has twice as many
+/// fractional bits so it is truncated back
+fn multiply_fixed(left: Lowered, right: Lowered, id_provider: &mut Provider) -> Lowered {
+    if !(left.fixed && right.fixed) {
+        return Lowered {
+            expr: multiply(left.expr, right.expr, id_provider),
+            fixed: left.fixed || right.fixed,
+        };
+    }
+
+    let product = multiply(left.expr, right.expr, id_provider);
+
+    Lowered {
+        expr: truncate(product, id_provider.fractional_bits(), id_provider),
+        fixed: true,
+    }
+}
+
+/// Lowers division by 2^n_bits, constants are truncated right away
+fn truncate(
+    expr: DecoratedExpression,
+    n_bits: u32,
+    id_provider: &mut Provider,
+) -> DecoratedExpression {
+    match expr {
+        DecoratedExpression::Constant(s, _) => {
+            DecoratedExpression::Constant(shares::truncate(&s, n_bits), id_provider.next())
+        }
+        x => DecoratedExpression::Truncate(Box::new(x), n_bits, id_provider.next()),
+    }
+}
+
+/// Lowers `left / right` where `right` has to be a nonzero constant.
+/// Integers are divided rounding down, see `floor_divide`. If any of operands is fixed point
+/// the result is fixed point, computed as product with reciprocal of divisor
+/// with DIVISION_BITS more bits, truncated back.
+fn divide(left: Lowered, right: Lowered, id_provider: &mut Provider) -> Result<Lowered, String> {
+    let divisor = match right.expr {
+        DecoratedExpression::Constant(s, _) => s,
+        _ => return Err("divisor has to be a constant".to_string()),
+    };
+    if divisor.is_zero().into() {
+        return Err("division by zero".to_string());
+    }
+
+    if !left.fixed && !right.fixed {
+        let divisor = shares::elem_to_i128(&divisor).ok_or("divisor is too big".to_string())?;
+        let expr = match left.expr {
+            DecoratedExpression::Constant(s, _) => {
+                let dividend = shares::elem_to_i128(&s).ok_or("dividend is too big".to_string())?;
+                constant(floor_div(dividend, divisor), id_provider)
+            }
+            x => floor_divide(x, divisor, id_provider),
+        };
+
+        return Ok(Lowered::integer(expr));
+    }
+
+    let dividend = if left.fixed {
+        left.expr
+    } else {
+        to_fixed(left.expr, id_provider)
+    };
+
+    // encoding of fixed point divisor c is c * 2^f so its reciprocal needs f more bits
+    let divisor_bits = if right.fixed {
+        id_provider.fractional_bits()
+    } else {
+        0
+    };
+    let divisor = shares::elem_to_i128(&divisor).ok_or("divisor is too big".to_string())?;
+    let numerator = 1i128 << (DIVISION_BITS + divisor_bits);
+    let reciprocal = (numerator + divisor.abs() / 2) / divisor.abs() * divisor.signum();
+    let reciprocal = shares::elem_from_i128(reciprocal);
+
+    let product = match dividend {
+        DecoratedExpression::Constant(s, _) => {
+            DecoratedExpression::Constant(s * reciprocal, id_provider.next())
+        }
+        x => DecoratedExpression::MulConstant(reciprocal, Box::new(x), id_provider.next()),
+    };
+
+    Ok(Lowered {
+        expr: truncate(product, DIVISION_BITS, id_provider),
+        fixed: true,
+    })
+}
+
+/// Lowers `left /! right` of integers where `right` has to be a nonzero constant dividing `left`.
+/// It is a product with inverse of divisor in the field, computed locally by every node,
+/// for other dividends the result is some unrelated element.
+fn divide_exactly(
+    left: Lowered,
+    right: Lowered,
+    id_provider: &mut Provider,
+) -> Result<Lowered, String> {
+    if left.fixed || right.fixed {
+        return Err("exact division `/!` needs integer operands".to_string());
+    }
+    let divisor = match right.expr {
+        DecoratedExpression::Constant(s, _) => s,
+        _ => return Err("divisor has to be a constant".to_string()),
+    };
+    let inverse = Option::<Elem>::from(divisor.invert()).ok_or("division by zero".to_string())?;
+
+    let expr = match left.expr {
+        DecoratedExpression::Constant(s, _) => {
+            if let (Some(a), Some(b)) = (shares::elem_to_i128(&s), shares::elem_to_i128(&divisor)) {
+                if a % b != 0 {
+                    return Err(format!("{} is not divisible by {}", a, b));
+                }
+            }
+            DecoratedExpression::Constant(s * inverse, id_provider.next())
+        }
+        x => DecoratedExpression::DivConstant(divisor, Box::new(x), id_provider.next()),
+    };
+
+    Ok(Lowered::integer(expr))
+}
+
+fn constant(value: i128, id_provider: &mut Provider) -> DecoratedExpression {
+    DecoratedExpression::Constant(shares::elem_from_i128(value), id_provider.next())
+}
+
+/// Lowers `dividend / divisor` of integers rounded down. Quotient q is estimated as product
+/// with reciprocal of divisor with COMPARISON_BITS bits truncated back, which is off by at most
+/// one for dividends in comparison range. Remainder r = dividend - divisor * q then corrects it
+/// to q - [r < 0] + 1 - [r < divisor]. Both comparisons run in the same round.
+fn floor_divide(
+    dividend: DecoratedExpression,
+    divisor: i128,
     id_provider: &mut Provider,
-) -> Result<DecoratedExpression, String> {
+) -> DecoratedExpression {
+    // x / d = -x / -d, so that divisor is positive
+    let x = if divisor < 0 {
+        DecoratedExpression::Neg(Box::new(dividend), id_provider.next())
+    } else {
+        dividend
+    };
+    let divisor = divisor.abs();
+    if divisor == 1 {
+        return x;
+    }
+
+    let x_id = x.cir_id();
+    let reciprocal = ((1i128 << COMPARISON_BITS) + divisor / 2) / divisor;
+    let product = multiply(
+        constant(reciprocal, id_provider),
+        DecoratedExpression::Ref(x_id.clone()),
+        id_provider,
+    );
+    let q = truncate(product, COMPARISON_BITS, id_provider);
+    let q_id = q.cir_id();
+
+    let multiple = multiply(
+        constant(divisor, id_provider),
+        DecoratedExpression::Ref(q_id.clone()),
+        id_provider,
+    );
+    let r = subtract(DecoratedExpression::Ref(x_id), multiple, id_provider);
+    let r_id = r.cir_id();
+
+    let below = less_than(
+        DecoratedExpression::Ref(r_id.clone()),
+        constant(0, id_provider),
+        id_provider,
+    );
+    let not_above = less_than(
+        DecoratedExpression::Ref(r_id),
+        constant(divisor, id_provider),
+        id_provider,
+    );
+    let corrected = subtract(
+        subtract(DecoratedExpression::Ref(q_id), below, id_provider),
+        not_above,
+        id_provider,
+    );
+    let quotient = add(constant(1, id_provider), corrected, id_provider);
+
+    DecoratedExpression::Let(
+        Box::new(x),
+        Box::new(DecoratedExpression::Let(
+            Box::new(q),
+            Box::new(DecoratedExpression::Let(Box::new(r), Box::new(quotient))),
+        )),
+    )
+}
+
+/// Lowers `base^exponent`, constant base is folded with the same multiplications
+/// as computed by `Node`, including truncations of fixed point products
+fn power(base: Lowered, exponent: u32, id_provider: &mut Provider) -> Lowered {
+    let n_bits = if base.fixed {
+        id_provider.fractional_bits()
+    } else {
+        0
+    };
+
+    let expr = match (base.expr, exponent) {
+        (_, 0) => DecoratedExpression::Constant(shares::pow2(n_bits), id_provider.next()),
+        (x, 1) => x,
+        (DecoratedExpression::Constant(s, _), _) => {
+            DecoratedExpression::Constant(fold_power(s, exponent, n_bits), id_provider.next())
+        }
+        (x, _) => DecoratedExpression::Pow(Box::new(x), exponent, n_bits, id_provider.next()),
+    };
+
+    Lowered {
+        expr,
+        fixed: base.fixed,
+    }
+}
+
+/// Lowers `cond ? then : otherwise` as `cond * (then - otherwise) + otherwise`.
+/// If `cond` can't be statically shown to be a bit it is checked with dealer's help at runtime.
+fn select(
+    cond: Expression<u64>,
+    then: Expression<u64>,
+    otherwise: Expression<u64>,
+    id_provider: &mut Provider,
+) -> Result<Lowered, String> {
+    let cond = lower(cond, id_provider)?;
+    if cond.fixed {
+        return Err("condition of select has to be an integer".to_string());
+    }
+
+    let cond = match cond.expr {
+        DecoratedExpression::Constant(s, _) if s == Elem::one() => return lower(then, id_provider),
+        DecoratedExpression::Constant(s, _) if s.is_zero().into() => {
+            return lower(otherwise, id_provider)
+        }
+        DecoratedExpression::Constant(_, _) => {
+            return Err("condition of select is neither 0 nor 1".to_string())
+        }
+        x if is_bit(&x) => x,
+        x => DecoratedExpression::AssertBit(Box::new(x), id_provider.next()),
+    };
+
+    // `otherwise` is used twice so it is evaluated once and referenced
+    let then = lower(then, id_provider)?;
+    let (mut otherwise, value) = Binding::new(String::new(), lower(otherwise, id_provider)?);
+
+    let difference = aligned(
+        subtract,
+        then,
+        otherwise.reference(id_provider.next()),
+        id_provider,
+    );
+    let product = multiply_fixed(Lowered::integer(cond), difference, id_provider);
+    let result = aligned(
+        add,
+        product,
+        otherwise.reference(id_provider.next()),
+        id_provider,
+    );
+
+    Ok(with_value(value, result))
+}
+
+fn lower(expr: Expression<u64>, id_provider: &mut Provider) -> Result<Lowered, String> {
     match expr {
-        Expression::Number { number } => Ok(DecoratedExpression::Constant(
+        Expression::Number { number } => Ok(Lowered::integer(DecoratedExpression::Constant(
             Elem::from(number),
             id_provider.next(),
-        )),
+        ))),
+        Expression::Decimal { decimal } => {
+            let n_bits = id_provider.fractional_bits();
+            if n_bits == 0 {
+                return Err("decimal numbers need fractional bits".to_string());
+            }
+            let value = decimal
+                .to_fixed(n_bits)
+                .ok_or(format!("decimal {} is too big", decimal))?;
+
+            Ok(Lowered {
+                expr: DecoratedExpression::Constant(
+                    shares::elem_from_i128(value),
+                    id_provider.next(),
+                ),
+                fixed: true,
+            })
+        }
         Expression::BinOp { left, right, op } => {
-            let left = decorate_expression(*left, id_provider)?;
-            let right = decorate_expression(*right, id_provider)?;
+            let left = lower(*left, id_provider)?;
+            let right = lower(*right, id_provider)?;
 
             match op {
-                BinaryOp::Add => match (left, right) {
-                    (
-                        DecoratedExpression::Constant(s1, _),
-                        DecoratedExpression::Constant(s2, _),
-                    ) => Ok(DecoratedExpression::Constant(s1 + s2, id_provider.next())),
-                    (DecoratedExpression::Constant(s1, _), x) => Ok(
-                        DecoratedExpression::AddConstant(s1, Box::new(x), id_provider.next()),
-                    ),
-                    (x, DecoratedExpression::Constant(s1, _)) => Ok(
-                        DecoratedExpression::AddConstant(s1, Box::new(x), id_provider.next()),
-                    ),
-                    (x, y) => Ok(DecoratedExpression::Add(
-                        Box::new(x),
-                        Box::new(y),
-                        id_provider.next(),
-                    )),
-                },
-                BinaryOp::Mul => match (left, right) {
-                    (
-                        DecoratedExpression::Constant(s1, _),
-                        DecoratedExpression::Constant(s2, _),
-                    ) => Ok(DecoratedExpression::Constant(s1 + s2, id_provider.next())),
-                    (DecoratedExpression::Constant(s1, _), x) => Ok(
-                        DecoratedExpression::MulConstant(s1, Box::new(x), id_provider.next()),
-                    ),
-                    (x, DecoratedExpression::Constant(s1, _)) => Ok(
-                        DecoratedExpression::MulConstant(s1, Box::new(x), id_provider.next()),
-                    ),
-                    (x, y) => {
-                        let id = id_provider.next();
-                        Ok(DecoratedExpression::Mul(Box::new(x), Box::new(y), id))
-                    }
-                },
+                BinaryOp::Add => Ok(aligned(add, left, right, id_provider)),
+                BinaryOp::Sub => Ok(aligned(subtract, left, right, id_provider)),
+                BinaryOp::Mul => Ok(multiply_fixed(left, right, id_provider)),
+                BinaryOp::Div => divide(left, right, id_provider),
+                BinaryOp::ExactDiv => divide_exactly(left, right, id_provider),
+                BinaryOp::LessThan => Ok(Lowered::integer(
+                    aligned(less_than, left, right, id_provider).expr,
+                )),
+                BinaryOp::GreaterThan => Ok(Lowered::integer(
+                    aligned(less_than, right, left, id_provider).expr,
+                )),
+                BinaryOp::Eq => Ok(Lowered::integer(
+                    aligned(equals, left, right, id_provider).expr,
+                )),
             }
         }
         Expression::Variable { name } => {
+            let next = id_provider.next();
+            if let Some(binding) = id_provider.binding(&name) {
+                return Ok(binding.reference(next));
+            }
+
             let node_id = id_provider
                 .var_to_node(name.clone())
-                .ok_or(format!("orphaned variable"))?;
-            Ok(DecoratedExpression::Var(node_id, name, id_provider.next()))
+                .ok_or("orphaned variable".to_string())?;
+            Ok(Lowered::integer(DecoratedExpression::Var(
+                node_id, name, next,
+            )))
+        }
+        Expression::Neg { expr } => {
+            let Lowered { expr, fixed } = lower(*expr, id_provider)?;
+            let expr = match expr {
+                DecoratedExpression::Constant(s, _) => {
+                    DecoratedExpression::Constant(-s, id_provider.next())
+                }
+                x => DecoratedExpression::Neg(Box::new(x), id_provider.next()),
+            };
+
+            Ok(Lowered { expr, fixed })
+        }
+        Expression::Let { name, value, body } => bind(name, *value, *body, id_provider),
+        Expression::Pow { base, exponent } => {
+            Ok(power(lower(*base, id_provider)?, exponent, id_provider))
         }
+        Expression::Select {
+            cond,
+            then,
+            otherwise,
+        } => select(*cond, *then, *otherwise, id_provider),
     }
 }
 
+/// Transform raw expression into decorated one.
+/// Returns also number of fractional bits of its value, 0 if it is an integer.
+pub fn decorate_expression(
+    expr: Expression<u64>,
+    id_provider: &mut Provider,
+) -> Result<(DecoratedExpression, u32), String> {
+    check_fractional_bits(id_provider.fractional_bits())?;
+
+    let Lowered { expr, fixed } = lower(expr, id_provider)?;
+    let fractional_bits = if fixed {
+        id_provider.fractional_bits()
+    } else {
+        0
+    };
+
+    Ok((expr, fractional_bits))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ff::Field;
+    use crate::expressions::parser::parse;
+    use std::collections::HashSet;
 
     fn dummy() -> Elem {
         Elem::zero()
@@ -299,4 +1341,525 @@ mod tests {
 
         assert_eq!(vec!["1", "2", "3", "4", "5", "6", "7", "8"], ordered_ids);
     }
+
+    #[test]
+    fn splits_independent_multiplications_into_one_layer() {
+        let layers = into_layers(test_expr().into_ordered())
+            .into_iter()
+            .map(|layer| layer.iter().map(|e| e.cir_id()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![vec!["1", "2", "3", "5", "6", "7"], vec!["4", "8"]],
+            layers
+        );
+
+        let x = || Expression::Variable {
+            name: "x".to_string(),
+        };
+        let mul = |left, right| Expression::BinOp {
+            left: Box::new(left),
+            right: Box::new(right),
+            op: BinaryOp::Mul,
+        };
+        let chain = decorate(mul(mul(mul(x(), x()), x()), x())).unwrap();
+        let depth = chain.depth();
+        let layers = into_layers(chain.into_ordered());
+
+        assert_eq!(3, depth);
+        assert_eq!(depth, layers.len());
+        assert!(layers
+            .iter()
+            .all(|layer| layer.iter().filter(|e| e.is_interactive()).count() == 1));
+    }
+
+    fn provider() -> Provider {
+        Provider::new(0, [("x".to_string(), 0)].into_iter().collect())
+    }
+
+    fn decorate(expr: Expression<u64>) -> Result<DecoratedExpression, String> {
+        decorate_expression(expr, &mut provider()).map(|(expr, _)| expr)
+    }
+
+    fn sub(left: Expression<u64>, right: Expression<u64>) -> Expression<u64> {
+        Expression::BinOp {
+            left: Box::new(left),
+            right: Box::new(right),
+            op: BinaryOp::Sub,
+        }
+    }
+
+    #[test]
+    fn folds_constant_sub_and_neg() {
+        let expr = Expression::Neg {
+            expr: Box::new(sub(
+                Expression::Number { number: 3 },
+                Expression::Number { number: 10 },
+            )),
+        };
+
+        match decorate(expr) {
+            Ok(DecoratedExpression::Constant(s, _)) => assert_eq!(Elem::from(7), s),
+            _ => panic!("expected constant"),
+        }
+    }
+
+    #[test]
+    fn folds_constant_products() {
+        match decorate(parse("2 * 3 * 7").unwrap()) {
+            Ok(DecoratedExpression::Constant(s, _)) => assert_eq!(Elem::from(42), s),
+            _ => panic!("expected constant"),
+        }
+    }
+
+    #[test]
+    fn lowers_sub_with_constant_on_either_side() {
+        let x = || Expression::Variable {
+            name: "x".to_string(),
+        };
+
+        match decorate(sub(x(), Expression::Number { number: 4 })) {
+            Ok(DecoratedExpression::AddConstant(s, e, _)) => {
+                assert_eq!(-Elem::from(4), s);
+                assert!(matches!(*e, DecoratedExpression::Var(..)));
+            }
+            _ => panic!("expected x + (-4)"),
+        }
+
+        match decorate(sub(Expression::Number { number: 4 }, x())) {
+            Ok(DecoratedExpression::AddConstant(s, e, _)) => {
+                assert_eq!(Elem::from(4), s);
+                assert!(matches!(*e, DecoratedExpression::Neg(..)));
+            }
+            _ => panic!("expected 4 + (-x)"),
+        }
+
+        assert!(matches!(
+            decorate(sub(x(), x())),
+            Ok(DecoratedExpression::Sub(..))
+        ));
+    }
+
+    #[test]
+    fn lowers_comparisons() {
+        let cmp = |left: u64, right: Expression<u64>, op: BinaryOp| Expression::BinOp {
+            left: Box::new(Expression::Number { number: left }),
+            right: Box::new(right),
+            op,
+        };
+
+        match decorate(cmp(
+            3,
+            Expression::Number { number: 10 },
+            BinaryOp::LessThan,
+        )) {
+            Ok(DecoratedExpression::Constant(s, _)) => assert_eq!(Elem::one(), s),
+            _ => panic!("expected constant"),
+        }
+        match decorate(cmp(
+            3,
+            Expression::Number { number: 10 },
+            BinaryOp::GreaterThan,
+        )) {
+            Ok(DecoratedExpression::Constant(s, _)) => assert_eq!(Elem::zero(), s),
+            _ => panic!("expected constant"),
+        }
+        match decorate(cmp(10, Expression::Number { number: 10 }, BinaryOp::Eq)) {
+            Ok(DecoratedExpression::Constant(s, _)) => assert_eq!(Elem::one(), s),
+            _ => panic!("expected constant"),
+        }
+
+        let x = Expression::Variable {
+            name: "x".to_string(),
+        };
+        let decorated = decorate(cmp(3, x, BinaryOp::LessThan));
+        match decorated {
+            Ok(DecoratedExpression::LessThanZero(e, cir_id)) => {
+                assert!(matches!(*e, DecoratedExpression::AddConstant(..)));
+                let d = DecoratedExpression::LessThanZero(e, cir_id.clone());
+                assert_eq!(vec![(cir_id.clone(), COMPARISON_BITS)], d.bit_mask_ids());
+                assert_eq!(prefix_or_ids(&cir_id, COMPARISON_BITS), d.mul_ids());
+            }
+            _ => panic!("expected (3 - x) < 0"),
+        }
+    }
+
+    #[test]
+    fn checks_select_condition() {
+        let x = || Expression::Variable {
+            name: "x".to_string(),
+        };
+        let select = |cond: Expression<u64>| Expression::Select {
+            cond: Box::new(cond),
+            then: Box::new(x()),
+            otherwise: Box::new(Expression::Number { number: 5 }),
+        };
+
+        // x * (x - 5) + 5 where x is checked to be a bit
+        match decorate(select(x())) {
+            Ok(DecoratedExpression::AddConstant(_, e, _)) => match *e {
+                DecoratedExpression::Mul(cond, _, _) => {
+                    assert!(matches!(*cond, DecoratedExpression::AssertBit(..)))
+                }
+                _ => panic!("expected multiplication"),
+            },
+            _ => panic!("expected select"),
+        }
+
+        // comparison is always a bit
+        let cond = Expression::BinOp {
+            left: Box::new(x()),
+            right: Box::new(Expression::Number { number: 3 }),
+            op: BinaryOp::LessThan,
+        };
+        let decorated = decorate(select(cond)).unwrap();
+        assert!(decorated.random_ids().is_empty());
+
+        assert!(matches!(
+            decorate(select(Expression::Number { number: 0 })),
+            Ok(DecoratedExpression::Constant(..))
+        ));
+        assert!(matches!(
+            decorate(select(Expression::Number { number: 1 })),
+            Ok(DecoratedExpression::Var(..))
+        ));
+        assert!(decorate(select(Expression::Number { number: 2 })).is_err());
+    }
+
+    #[test]
+    fn lowers_fixed_point() {
+        let x = || Expression::Variable {
+            name: "x".to_string(),
+        };
+        let decimal = |s: &str| Expression::Decimal {
+            decimal: s.parse().unwrap(),
+        };
+        let mul = |left: Expression<u64>, right: Expression<u64>| Expression::BinOp {
+            left: Box::new(left),
+            right: Box::new(right),
+            op: BinaryOp::Mul,
+        };
+
+        match decorate_expression(mul(x(), decimal("1.75")), &mut provider()) {
+            Ok((DecoratedExpression::MulConstant(s, e, _), 16)) => {
+                assert_eq!(Elem::from(7 << 14), s);
+                assert!(matches!(*e, DecoratedExpression::Var(..)));
+            }
+            _ => panic!("expected x * 1.75 without truncation"),
+        }
+
+        match decorate_expression(mul(decimal("1.5"), decimal("2.5")), &mut provider()) {
+            Ok((DecoratedExpression::Constant(s, _), 16)) => {
+                assert_eq!(Elem::from(15 << 14), s)
+            }
+            _ => panic!("expected constant"),
+        }
+
+        let product = mul(mul(x(), decimal("0.5")), mul(x(), decimal("0.5")));
+        match decorate_expression(product, &mut provider()) {
+            Ok((DecoratedExpression::Truncate(e, 16, cir_id), 16)) => {
+                assert!(matches!(*e, DecoratedExpression::Mul(..)));
+                let d = DecoratedExpression::Truncate(e, 16, cir_id.clone());
+                assert_eq!(vec![(cir_id, 16)], d.truncation_mask_ids());
+            }
+            _ => panic!("expected truncated product"),
+        }
+
+        // integer operand of a comparison with fixed point one is encoded as well
+        match decorate_expression(
+            cmp_expr(x(), decimal("0.5"), BinaryOp::LessThan),
+            &mut provider(),
+        ) {
+            Ok((DecoratedExpression::LessThanZero(e, _), 0)) => match *e {
+                DecoratedExpression::AddConstant(s, e, _) => {
+                    assert_eq!(-Elem::from(1 << 15), s);
+                    assert!(matches!(*e, DecoratedExpression::MulConstant(..)));
+                }
+                _ => panic!("expected x * 2^16 - 0.5"),
+            },
+            _ => panic!("expected comparison"),
+        }
+
+        match decorate_expression(decimal("1.75"), &mut provider().with_fractional_bits(8)) {
+            Ok((DecoratedExpression::Constant(s, _), 8)) => assert_eq!(Elem::from(448), s),
+            _ => panic!("expected constant"),
+        }
+        assert!(
+            decorate_expression(decimal("1.75"), &mut provider().with_fractional_bits(0)).is_err()
+        );
+        assert!(decorate_expression(x(), &mut provider().with_fractional_bits(64)).is_err());
+    }
+
+    #[test]
+    fn lowers_division() {
+        let x = || Expression::Variable {
+            name: "x".to_string(),
+        };
+        let number = |number: u64| Expression::Number { number };
+        let div = |left: Expression<u64>, right: Expression<u64>| Expression::BinOp {
+            left: Box::new(left),
+            right: Box::new(right),
+            op: BinaryOp::Div,
+        };
+
+        // quotient estimated with truncation is corrected by two comparisons of remainder
+        let quotient = decorate(div(x(), number(4))).unwrap();
+        assert_eq!(1, quotient.truncation_mask_ids().len());
+        assert_eq!(2, quotient.bit_mask_ids().len());
+        // dividend is evaluated once and referenced
+        assert_eq!(1, quotient.self_var_ids(None).len());
+        assert!(matches!(
+            decorate(div(x(), number(1))),
+            Ok(DecoratedExpression::Var(..))
+        ));
+
+        for (dividend, divisor, quotient) in [(12, 4, 3), (7, 2, 3), (-7, 2, -4), (7, -2, -4)] {
+            let dividend = match dividend {
+                d if d < 0 => sub(number(0), number(-d as u64)),
+                d => number(d as u64),
+            };
+            let divisor = match divisor {
+                d if d < 0 => sub(number(0), number(-d as u64)),
+                d => number(d as u64),
+            };
+            match decorate(div(dividend, divisor)) {
+                Ok(DecoratedExpression::Constant(s, _)) => {
+                    assert_eq!(shares::elem_from_i128(quotient), s)
+                }
+                _ => panic!("expected constant"),
+            }
+        }
+
+        assert_eq!(
+            Err("division by zero".to_string()),
+            decorate(div(x(), sub(number(3), number(3)))).map(|_| ())
+        );
+        assert!(decorate(div(x(), x())).is_err());
+
+        // x / 1.5 = x * 2^16 * round(2^48 / (1.5 * 2^16)) / 2^32
+        let decimal = Expression::Decimal {
+            decimal: "1.5".parse().unwrap(),
+        };
+        match decorate_expression(div(x(), decimal), &mut provider()) {
+            Ok((DecoratedExpression::Truncate(e, 32, _), 16)) => match *e {
+                DecoratedExpression::MulConstant(s, _, _) => {
+                    assert_eq!(Elem::from(((1 << 33) + 1) / 3), s)
+                }
+                _ => panic!("expected product with reciprocal"),
+            },
+            _ => panic!("expected truncated product"),
+        }
+    }
+
+    #[test]
+    fn lowers_exact_division() {
+        let x = || Expression::Variable {
+            name: "x".to_string(),
+        };
+        let number = |number: u64| Expression::Number { number };
+        let div = |left: Expression<u64>, right: Expression<u64>| Expression::BinOp {
+            left: Box::new(left),
+            right: Box::new(right),
+            op: BinaryOp::ExactDiv,
+        };
+
+        // local product with inverse, no preprocessing needed
+        match decorate(div(x(), number(12))) {
+            Ok(DecoratedExpression::DivConstant(s, e, _)) => {
+                assert_eq!(Elem::from(12), s);
+                assert!(e.mul_ids().is_empty() && e.truncation_mask_ids().is_empty());
+                assert!(matches!(*e, DecoratedExpression::Var(..)));
+            }
+            _ => panic!("expected division by constant"),
+        }
+
+        match decorate(div(number(12), number(4))) {
+            Ok(DecoratedExpression::Constant(s, _)) => assert_eq!(Elem::from(3), s),
+            _ => panic!("expected constant"),
+        }
+        assert_eq!(
+            Err("7 is not divisible by 2".to_string()),
+            decorate(div(number(7), number(2))).map(|_| ())
+        );
+        assert_eq!(
+            Err("division by zero".to_string()),
+            decorate(div(x(), number(0))).map(|_| ())
+        );
+        assert!(decorate(div(x(), x())).is_err());
+
+        let decimal = Expression::Decimal {
+            decimal: "1.5".parse().unwrap(),
+        };
+        assert!(decorate_expression(div(x(), decimal), &mut provider()).is_err());
+    }
+
+    fn depth(steps: &[(usize, usize)]) -> usize {
+        let mut depths = vec![0];
+        for (a, b) in steps {
+            depths.push(depths[*a].max(depths[*b]) + 1);
+        }
+        depths[depths.len() - 1]
+    }
+
+    #[test]
+    fn plans_powers_with_minimal_depth() {
+        assert_eq!(vec![(0, 0), (1, 1), (2, 2)], power_steps(8));
+        // x^6 = x^2 * x^4
+        assert_eq!(vec![(0, 0), (1, 1), (1, 2)], power_steps(6));
+
+        for exponent in 2..200u32 {
+            let steps = power_steps(exponent);
+            let min_depth = 32 - (exponent - 1).leading_zeros();
+            assert_eq!(min_depth as usize, depth(&steps), "{}", exponent);
+
+            let mut powers = vec![1u32];
+            for (a, b) in steps {
+                powers.push(powers[a] + powers[b]);
+            }
+            assert_eq!(exponent, powers[powers.len() - 1]);
+        }
+    }
+
+    #[test]
+    fn lowers_powers() {
+        let pow = |base: Expression<u64>, exponent| Expression::Pow {
+            base: Box::new(base),
+            exponent,
+        };
+        let x = || Expression::Variable {
+            name: "x".to_string(),
+        };
+
+        assert!(matches!(
+            decorate(pow(x(), 5)),
+            Ok(DecoratedExpression::Pow(_, 5, 0, _))
+        ));
+        assert!(matches!(
+            decorate(pow(x(), 1)),
+            Ok(DecoratedExpression::Var(..))
+        ));
+        match decorate(pow(x(), 0)) {
+            Ok(DecoratedExpression::Constant(s, _)) => assert_eq!(Elem::one(), s),
+            _ => panic!("expected constant"),
+        }
+        match decorate(pow(Expression::Number { number: 3 }, 4)) {
+            Ok(DecoratedExpression::Constant(s, _)) => assert_eq!(Elem::from(81), s),
+            _ => panic!("expected constant"),
+        }
+
+        let fixed = pow(
+            Expression::Decimal {
+                decimal: "1.5".parse().unwrap(),
+            },
+            2,
+        );
+        match decorate_expression(pow(fixed, 2), &mut provider()) {
+            Ok((DecoratedExpression::Constant(s, _), 16)) => {
+                assert_eq!(Elem::from(81 << 12), s)
+            }
+            _ => panic!("expected fixed point constant"),
+        }
+
+        let fixed_x = Expression::BinOp {
+            left: Box::new(x()),
+            right: Box::new(Expression::Decimal {
+                decimal: "0.5".parse().unwrap(),
+            }),
+            op: BinaryOp::Mul,
+        };
+        let decorated = decorate(pow(fixed_x, 3)).unwrap();
+        assert!(matches!(decorated, DecoratedExpression::Pow(_, 3, 16, _)));
+        assert_eq!(2, decorated.mul_ids().len());
+        assert_eq!(2, decorated.truncation_mask_ids().len());
+    }
+
+    fn ordered_ids(expr: DecoratedExpression) -> Vec<CirId> {
+        expr.into_ordered().iter().map(|e| e.cir_id()).collect()
+    }
+
+    #[test]
+    fn lowers_let_to_shared_nodes() {
+        let parsed = |formula| decorate(parse(formula).unwrap()).unwrap();
+
+        let shared = parsed("let r = x * x in r * r + r");
+        assert_eq!(2, shared.mul_ids().len());
+        assert_eq!(2, shared.self_var_ids(None).len());
+        let ids = ordered_ids(shared);
+        assert_eq!(
+            ids.len(),
+            ids.iter().collect::<std::collections::HashSet<_>>().len()
+        );
+        assert_eq!(4, parsed("(x * x) * (x * x) + x * x").mul_ids().len());
+
+        // unused values are not computed and constants are substituted
+        assert!(matches!(
+            parsed("let r = x * x in x"),
+            DecoratedExpression::Var(..)
+        ));
+        assert!(matches!(
+            parsed("let c = 2 + 3 in x * c"),
+            DecoratedExpression::MulConstant(..)
+        ));
+        match parsed("let x = 3 in let y = x + 1 in x + y") {
+            DecoratedExpression::Constant(s, _) => assert_eq!(Elem::from(7), s),
+            _ => panic!("expected constant"),
+        }
+
+        // let shadows inputs and outer bindings
+        match parsed("let x = x * 2 in let x = x + 1 in x") {
+            DecoratedExpression::Let(value, body) => {
+                assert!(matches!(*value, DecoratedExpression::MulConstant(..)));
+                match *body {
+                    DecoratedExpression::Let(value, body) => {
+                        assert!(matches!(*value, DecoratedExpression::AddConstant(..)));
+                        assert!(matches!(*body, DecoratedExpression::Ref(..)));
+                    }
+                    _ => panic!("expected inner let"),
+                }
+            }
+            _ => panic!("expected let"),
+        }
+
+        // select uses `otherwise` twice but evaluates it once
+        let select = parsed("if x == 1 then 5 else x * x").into_ordered();
+        let muls = select
+            .iter()
+            .filter(|e| matches!(e, MidEvalExpression::Mul(..)))
+            .count();
+        // x * x and product with condition
+        assert_eq!(2, muls);
+    }
+
+    #[test]
+    fn prefix_or_joins_every_bit_with_more_significant_ones_in_log_depth() {
+        for n_bits in [1, 2, 5, 8, COMPARISON_BITS, EQUALITY_BITS] {
+            // set of bits joined into each f_i
+            let mut f = (0..n_bits as usize)
+                .map(|i| HashSet::from([i]))
+                .collect::<Vec<_>>();
+            let levels = prefix_or_steps(n_bits);
+            for level in levels.iter() {
+                let before = f.clone();
+                for (i, j) in level {
+                    assert!(!level.iter().any(|(target, _)| target == j));
+                    f[*i].extend(before[*j].iter());
+                }
+            }
+
+            for (i, joined) in f.iter().enumerate() {
+                assert_eq!((i..n_bits as usize).collect::<HashSet<_>>(), *joined);
+            }
+            assert_eq!((n_bits as f64).log2().ceil() as usize, levels.len());
+            let n_ors = levels.iter().map(|level| level.len()).sum::<usize>();
+            assert_eq!(n_ors, prefix_or_ids(&"x".to_string(), n_bits).len());
+        }
+    }
+
+    fn cmp_expr(left: Expression<u64>, right: Expression<u64>, op: BinaryOp) -> Expression<u64> {
+        Expression::BinOp {
+            left: Box::new(left),
+            right: Box::new(right),
+            op,
+        }
+    }
 }
@@ -1,21 +1,38 @@
 mod arithmetics;
+pub mod cost;
 pub mod dealer;
+pub mod error;
 mod expression;
 pub mod network;
 pub mod node;
+mod optimizer;
+pub mod output;
 pub mod party;
+mod subprotocols;
+#[cfg(test)]
+mod tampering;
+pub mod tcp;
 #[cfg(test)]
 mod test;
+pub mod wire;
 
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::Duration;
 
-use crate::crypto::shares::{BeaverShare, Commitment, CommitmentProof, Elem, Share, Shares};
-use crate::expressions::Expression;
+use crate::crypto::shares::{
+    self, BeaverShare, BitMaskShare, Commitment, CommitmentProof, Elem, Elems, Shares,
+    TruncationMaskShare,
+};
+use crate::expressions::Outputs;
 use crate::protocol::{
     dealer::TrustedDealer,
-    expression::decorate_expression,
+    error::{BlameReport, ProtocolError},
+    expression::{decorate_expression, Binding, DecoratedExpression},
     network::{setup_network, Network},
     node::Node,
+    optimizer::optimize,
+    output::Output,
     party::Party,
 };
 
@@ -29,43 +46,98 @@ pub type VarId = String;
 /// Id of node in the circuit
 pub type CirId = String;
 
+/// Comparison gates are correct for operands whose difference lies in [-2^COMPARISON_BITS, 2^COMPARISON_BITS)
+pub const COMPARISON_BITS: u32 = 64;
+
+/// Truncation of fixed point values is correct for values in [-2^(TRUNCATION_BITS-1), 2^(TRUNCATION_BITS-1))
+pub const TRUNCATION_BITS: u32 = 2 * COMPARISON_BITS;
+
+/// Number of bits of precision of reciprocal of divisor in division of fixed point numbers
+pub const DIVISION_BITS: u32 = 32;
+
+/// Number of fractional bits of fixed point values used unless configured otherwise
+pub const DEFAULT_FRACTIONAL_BITS: u32 = 16;
+
+/// Upper bound on number of fractional bits of fixed point values
+pub const MAX_FRACTIONAL_BITS: u32 = 32;
+
+/// Checks that fixed point values with `fractional_bits` fractional bits are supported
+pub fn check_fractional_bits(fractional_bits: u32) -> Result<(), String> {
+    if fractional_bits > MAX_FRACTIONAL_BITS {
+        return Err(format!(
+            "at most {} fractional bits are supported",
+            MAX_FRACTIONAL_BITS
+        ));
+    }
+    Ok(())
+}
+
+/// Quotient of integers rounded down, as computed by division of integers in circuits
+pub fn floor_div(dividend: i128, divisor: i128) -> i128 {
+    if divisor > 0 {
+        dividend.div_euclid(divisor)
+    } else {
+        (-dividend).div_euclid(-divisor)
+    }
+}
+
 pub fn sub_id(id: &CirId, name: &CirId) -> CirId {
     format!("{}-{}", id, name)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DealerEvents {
-    /// sends r and [r] for sharing secret value `varid` of node
+    /// sends r and [r] of each row for sharing secret value `varid` of node
     /// node receiving this message should own the variable
-    NodeSelfVariable(CirId, Elem, Share),
-    /// sends share [r] for secret value `varid`
-    NodeVariableShared(CirId, Share),
-    /// sends beaver shares for cirid for this node.
-    BeaverSharesFor(CirId, BeaverShare),
+    NodeSelfVariable(CirId, Elems, Shares),
+    /// sends share [r] of each row for secret value `varid`
+    NodeVariableShared(CirId, Shares),
+    /// sends beaver shares of each row for cirid for this node.
+    BeaverSharesFor(CirId, Vec<BeaverShare>),
+    /// sends shares of random bits and mask built from them of each row for cirid for this node.
+    BitMaskSharesFor(CirId, Vec<BitMaskShare>),
+    /// sends share of random value of each row for cirid for this node.
+    RandomShareFor(CirId, Shares),
+    /// sends shares of random mask for truncation of each row for cirid for this node.
+    TruncationMaskSharesFor(CirId, Vec<TruncationMaskShare>),
     /// gives alpha_i, and vec of (var_id, alpha_i * x_j)
     Alpha(Elem),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DealerCommands {
     /// Node wants to secretly share its variable
     NodeOpenSelfInput(CirId),
     /// Node needs beaver for cir_id
     BeaverFor(CirId),
+    /// Node needs mask of given number of random bits for cir_id
+    BitMaskFor(CirId, u32),
+    /// Node needs random shared value for cir_id
+    RandomFor(CirId),
+    /// Node needs mask for truncation by given number of bits for cir_id
+    TruncationMaskFor(CirId, u32),
     /// Node needs its alpha
     NeedAlpha,
 }
 
 #[derive(Debug)]
 pub enum NodeCommands {
-    /// Node opens its share for CirId
-    OpenShare(Share, CirId),
-    /// Node opens its (share - r) for CirId
-    OpenSelfShare(Elem, CirId),
+    /// Node opens its shares of all rows for CirId
+    OpenShare(Shares, CirId),
+    /// Node opens its shares of all rows for CirId only to given node
+    OpenShareTo(Shares, CirId, NodeId),
+    /// Node opens its (share - r) of all rows for CirId
+    OpenSelfShare(Elems, CirId),
     /// Node wants to secretly share its variable
     OpenSelfInput(CirId),
     /// Node needs beaver for cir_id
     NeedBeaver(CirId),
+    /// Node needs mask of given number of random bits for cir_id
+    NeedBitMask(CirId, u32),
+    /// Node needs random shared value for cir_id
+    NeedRandom(CirId),
+    /// Node needs mask for truncation by given number of bits for cir_id
+    NeedTruncationMask(CirId, u32),
     /// Node needs its alpha
     NeedAlpha,
     /// Broadcast commitment for cir_id
@@ -76,20 +148,30 @@ pub enum NodeCommands {
     ProofVerified(CirId),
     /// broadcast that proof was invalid
     ProofInvalid(CirId),
+    /// Node timed out and asks which nodes did not deliver their messages
+    ReportMissing,
+    /// Broadcast digests of shares of given cir_ids received from each node before check of cir_id
+    CompareDigests(CirId, Vec<CirId>),
 }
 
 #[derive(Debug)]
 pub enum NodeEvents {
-    /// cir is ready with shares from all of nodes
-    CirReady(CirId, Shares),
-    /// parts for sharing variable `var_id` are ready (r, [r])
-    SelfVariableReady(CirId, Elem, Share),
-    /// (x - share) for var_id is ready
-    NodeVariableReady(CirId, Elem),
-    /// [share] for var_id is ready
-    NodeVariableShareReady(CirId, Share),
-    /// beaver for node in circuit is ready
-    BeaverFor(CirId, BeaverShare),
+    /// cir is ready with shares of all rows from all of nodes
+    CirReady(CirId, Vec<Shares>),
+    /// parts for sharing variable `var_id` are ready (r, [r]) for each row
+    SelfVariableReady(CirId, Elems, Shares),
+    /// (x - share) of each row for var_id is ready
+    NodeVariableReady(CirId, Elems),
+    /// [share] of each row for var_id is ready
+    NodeVariableShareReady(CirId, Shares),
+    /// beavers of each row for node in circuit are ready
+    BeaverFor(CirId, Vec<BeaverShare>),
+    /// bit masks of each row for node in circuit are ready
+    BitMaskFor(CirId, Vec<BitMaskShare>),
+    /// random shared values of each row for node in circuit are ready
+    RandomFor(CirId, Shares),
+    /// truncation masks of each row for node in circuit are ready
+    TruncationMaskFor(CirId, Vec<TruncationMaskShare>),
     /// got all commitments for cir_id
     CommitmentsFor(CirId, Vec<(NodeId, Commitment)>),
     /// got all proofs for cir_id
@@ -98,6 +180,11 @@ pub enum NodeEvents {
     ProofValid(CirId),
     /// broadcast that proof was invalid
     ProofInvalid(CirId),
+    /// nodes that did not deliver their messages for partially collected cir_ids
+    Missing(Vec<(NodeId, CirId)>),
+    /// digests from all nodes were compared before check of cir_id,
+    /// with nodes whose reports contradict what we sent or received
+    DigestsCompared(CirId, BlameReport),
 }
 
 #[derive(Debug)]
@@ -106,11 +193,29 @@ pub struct Alpha(Elem);
 pub struct Provider {
     id: u64,
     var_to_node: HashMap<String, NodeId>,
+    fractional_bits: u32,
+    /// names bound by enclosing lets, innermost last
+    bindings: Vec<Binding>,
 }
 
 impl Provider {
     pub fn new(id: u64, var_to_node: HashMap<String, NodeId>) -> Self {
-        Self { id, var_to_node }
+        Self {
+            id,
+            var_to_node,
+            fractional_bits: DEFAULT_FRACTIONAL_BITS,
+            bindings: vec![],
+        }
+    }
+
+    /// Sets number of fractional bits of fixed point values
+    pub fn with_fractional_bits(mut self, fractional_bits: u32) -> Self {
+        self.fractional_bits = fractional_bits;
+        self
+    }
+
+    pub fn fractional_bits(&self) -> u32 {
+        self.fractional_bits
     }
 
     pub fn next(&mut self) -> CirId {
@@ -122,6 +227,139 @@ impl Provider {
     pub fn var_to_node(&self, name: String) -> Option<NodeId> {
         self.var_to_node.get(&name).cloned()
     }
+
+    pub fn bind(&mut self, binding: Binding) {
+        self.bindings.push(binding);
+    }
+
+    /// removes innermost binding
+    pub fn unbind(&mut self) -> Binding {
+        self.bindings.pop().expect("unbind called after bind")
+    }
+
+    /// returns innermost binding of `name`
+    pub fn binding(&mut self, name: &str) -> Option<&mut Binding> {
+        self.bindings
+            .iter_mut()
+            .rev()
+            .find(|binding| binding.name == name)
+    }
+}
+
+/// Output lowered to an optimized circuit
+pub struct DecoratedOutput {
+    pub name: String,
+    pub expression: DecoratedExpression,
+    pub recipient: Option<NodeId>,
+    pub fractional_bits: u32,
+}
+
+/// Decorates and optimizes outputs in order of their names with shared `provider`,
+/// so all nodes assign the same ids
+pub fn decorate_outputs(
+    outputs: Outputs<u64>,
+    provider: &mut Provider,
+) -> Result<Vec<DecoratedOutput>, String> {
+    let mut decorated = vec![];
+    for (name, output) in outputs {
+        let (expression, fractional_bits) = decorate_expression(output.expression, provider)
+            .map_err(|e| format!("Can't decorate output {}: {}", name, e))?;
+        let (expression, report) = optimize(expression, provider)
+            .map_err(|e| format!("Can't optimize output {}: {}", name, e))?;
+        log::info!("optimized output {}: {}", name, report);
+
+        decorated.push(DecoratedOutput {
+            name,
+            expression,
+            recipient: output.recipient,
+            fractional_bits,
+        });
+    }
+
+    Ok(decorated)
+}
+
+/// Maps each variable to the node owning it, `variable_values` are indexed by node id
+pub fn variable_owners<V>(variable_values: &[HashMap<String, V>]) -> HashMap<String, NodeId> {
+    variable_values
+        .iter()
+        .enumerate()
+        .flat_map(|(id, values)| values.keys().map(move |name| (name.clone(), id as NodeId)))
+        .collect()
+}
+
+/// Maps ids of values secret shared by a single node to that node: its variables
+/// and keys of its private outputs
+fn input_owners(
+    exps: &[(DecoratedExpression, Option<NodeId>)],
+    n_parties: u8,
+) -> HashMap<CirId, NodeId> {
+    let mut owners = HashMap::new();
+    for node_id in 0..n_parties as NodeId {
+        for (exp, _) in exps {
+            for (cir_id, _) in exp.self_var_ids(Some(node_id)) {
+                owners.insert(cir_id, node_id);
+            }
+        }
+    }
+    for (exp, recipient) in exps {
+        if let (Some(recipient), false) = (recipient, exp.is_constant()) {
+            owners.insert(sub_id(&exp.cir_id(), &"key".to_string()), *recipient);
+        }
+    }
+
+    owners
+}
+
+/// Phases of a session, each with its own time limit in `Timeouts`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// sharing of inputs and dealer's preprocessing
+    Inputs,
+    /// single round of multiplications or openings
+    Round,
+    /// joint check of macs of opened values
+    MacCheck,
+    /// opening of outputs
+    Outputs,
+}
+
+/// Time limits of waiting for messages of each phase, node aborts once one is exceeded.
+/// Liveness is enforced only by `Node`: `Party` has no deadlines of its own and stops
+/// once its node finishes, so a stalled party or peer ends up as a timeout of the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub inputs: Duration,
+    pub round: Duration,
+    pub mac_check: Duration,
+    pub outputs: Duration,
+}
+
+impl Timeouts {
+    /// Same limit for all phases
+    pub fn uniform(limit: Duration) -> Self {
+        Self {
+            inputs: limit,
+            round: limit,
+            mac_check: limit,
+            outputs: limit,
+        }
+    }
+
+    pub fn of(&self, phase: Phase) -> Duration {
+        match phase {
+            Phase::Inputs => self.inputs,
+            Phase::Round => self.round,
+            Phase::MacCheck => self.mac_check,
+            Phase::Outputs => self.outputs,
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self::uniform(Duration::from_secs(30))
+    }
 }
 
 pub struct NodeConfig<N: Network> {
@@ -129,36 +367,81 @@ pub struct NodeConfig<N: Network> {
     pub n_parties: u8,
     pub network: N,
     pub dealer: (Sender<(NodeId, DealerCommands)>, Receiver<DealerEvents>),
-    pub expression: Expression<u64>,
+    pub outputs: Outputs<u64>,
     pub variables: HashMap<String, NodeId>,
-    pub our_variables: HashMap<String, u64>,
+    /// values of our variables in each row of the batch
+    pub our_variables: HashMap<String, Vec<i64>>,
+    /// number of rows evaluated in the session
+    pub batch_size: usize,
+    pub fractional_bits: u32,
+    pub timeouts: Timeouts,
 }
 
-pub async fn run_node<N: Network + 'static + Send>(config: NodeConfig<N>) -> u64 {
+/// Runs node and decodes outputs of all rows revealed to it as `T`
+pub async fn run_node<N: Network + 'static + Send, T: Output>(
+    config: NodeConfig<N>,
+) -> Result<HashMap<String, Vec<T>>, ProtocolError> {
     let NodeConfig {
         id,
         n_parties,
         network,
         dealer,
-        expression,
+        outputs,
         variables,
         our_variables,
+        batch_size,
+        fractional_bits,
+        timeouts,
     } = config;
 
     let (node_cmd_tx, node_cmd_rx) = unbounded_channel();
     let (node_events_tx, node_events_rx) = unbounded_channel();
     let (alpha_tx, alpha_rx) = unbounded_channel();
 
-    let mut provider = Provider::new(0, variables);
+    let mut provider = Provider::new(0, variables).with_fractional_bits(fractional_bits);
 
-    let decorated = decorate_expression(expression, &mut provider).expect("");
+    let mut names = vec![];
+    let mut decorated = vec![];
+    let mut output_fractional_bits = vec![];
+    for output in decorate_outputs(outputs, &mut provider).map_err(ProtocolError::InvalidInput)? {
+        names.push(output.name);
+        decorated.push((output.expression, output.recipient));
+        output_fractional_bits.push(output.fractional_bits);
+    }
 
     let mut variables = HashMap::new();
-    for (cir_id, var_id) in decorated.self_var_ids(Some(id)) {
-        variables.insert(cir_id, Elem::from(*our_variables.get(&var_id).expect("")));
+    for (cir_id, var_id) in decorated
+        .iter()
+        .flat_map(|(exp, _)| exp.self_var_ids(Some(id)))
+    {
+        let values = our_variables
+            .get(&var_id)
+            .ok_or_else(|| ProtocolError::InvalidInput(format!("no value of {}", var_id)))?;
+        if values.len() != batch_size {
+            return Err(ProtocolError::InvalidInput(format!(
+                "variable {} has {} values, batch has {} rows",
+                var_id,
+                values.len(),
+                batch_size
+            )));
+        }
+        let values = values
+            .iter()
+            .map(|value| shares::elem_from_i128(*value as i128))
+            .collect();
+        variables.insert(cir_id, values);
     }
 
-    let node = Node::new(id, alpha_rx, node_cmd_tx, node_events_rx, variables);
+    let owners = input_owners(&decorated, n_parties);
+    let node = Node::new(
+        id,
+        batch_size,
+        alpha_rx,
+        node_cmd_tx,
+        node_events_rx,
+        variables,
+    )
+    .with_timeouts(timeouts);
     let mut party = Party::new(
         id,
         dealer,
@@ -167,34 +450,125 @@ pub async fn run_node<N: Network + 'static + Send>(config: NodeConfig<N>) -> u64
         node_events_tx,
         network,
         n_parties,
-    );
+    )
+    .with_batch_size(batch_size)
+    .with_input_owners(owners);
 
-    let node_task = async move { node.run(decorated).await };
-    let party_task = async move {
-        party.run().await;
+    // party keeps serving other nodes until our node finishes, its errors abort the node
+    let mut party_handle = tokio::spawn(async move { party.run().await });
+    let res = tokio::select! {
+        res = node.run(decorated) => match res {
+            // party that failed dropped our channels, its error tells why
+            Err(ProtocolError::ChannelClosed(channel)) => match party_handle.await {
+                Ok(Err(e)) => Err(e),
+                _ => Err(ProtocolError::ChannelClosed(channel)),
+            },
+            res => res,
+        },
+        Ok(Err(e)) = &mut party_handle => Err(e),
     };
-
-    let node_handle = tokio::spawn(node_task);
-    tokio::spawn(party_task);
-
-    let res = node_handle.await;
     println!("node {} finished with {:?}", id, res);
     tide::log::debug!("node {} finished with {:?}", id, res);
-    res.expect("Rune node failed")
+    let elems = res?;
+
+    let mut decoded = HashMap::new();
+    for (name, (elems, fractional_bits)) in names
+        .into_iter()
+        .zip(elems.into_iter().zip(output_fractional_bits))
+    {
+        if let Some(elems) = elems {
+            let values = elems
+                .iter()
+                .map(|elem| T::decode(elem, fractional_bits))
+                .collect::<Result<_, _>>()
+                .map_err(|e| {
+                    ProtocolError::InvalidOutput(format!("Can't decode output {}: {}", name, e))
+                })?;
+            decoded.insert(name, values);
+        }
+    }
+
+    Ok(decoded)
+}
+
+/// Runs all nodes evaluating `outputs` with decimals encoded on `fractional_bits` bits
+/// and returns map from output name to value for each node,
+/// private outputs are only in the map of their recipient
+pub async fn run_nodes<T: Output + Send + 'static>(
+    n_parties: u32,
+    variable_values: Vec<HashMap<String, i64>>,
+    outputs: Outputs<u64>,
+    fractional_bits: u32,
+) -> Vec<Result<HashMap<String, T>, ProtocolError>> {
+    let variable_values = variable_values
+        .into_iter()
+        .map(|values| {
+            values
+                .into_iter()
+                .map(|(name, value)| (name, vec![value]))
+                .collect()
+        })
+        .collect();
+
+    run_nodes_batched(n_parties, 1, variable_values, outputs, fractional_bits)
+        .await
+        .into_iter()
+        .map(|result| {
+            result.map(|values| {
+                values
+                    .into_iter()
+                    .map(|(name, mut rows)| (name, rows.remove(0)))
+                    .collect()
+            })
+        })
+        .collect()
 }
 
-pub async fn run_nodes(
+/// Runs all nodes evaluating `outputs` over `batch_size` rows in one session,
+/// each variable has a value for every row. Every multiplication and opening handles all rows
+/// in a single message, so the number of messages does not depend on the batch size.
+/// Returns map from output name to values of all rows for each node,
+/// private outputs are only in the map of their recipient
+pub async fn run_nodes_batched<T: Output + Send + 'static>(
     n_parties: u32,
-    variable_values: Vec<HashMap<String, u64>>,
-    expression: Expression<u64>,
-) -> Vec<Result<u64, tokio::task::JoinError>> {
-    let networks = setup_network(n_parties);
+    batch_size: usize,
+    variable_values: Vec<HashMap<String, Vec<i64>>>,
+    outputs: Outputs<u64>,
+    fractional_bits: u32,
+) -> Vec<Result<HashMap<String, Vec<T>>, ProtocolError>> {
+    run_nodes_over(
+        setup_network(n_parties),
+        batch_size,
+        variable_values,
+        outputs,
+        fractional_bits,
+        Timeouts::default(),
+    )
+    .await
+}
+
+/// Runs nodes like `run_nodes_batched`, each node communicating over its own network
+pub async fn run_nodes_over<N: Network + Send + 'static, T: Output + Send + 'static>(
+    networks: Vec<N>,
+    batch_size: usize,
+    variable_values: Vec<HashMap<String, Vec<i64>>>,
+    outputs: Outputs<u64>,
+    fractional_bits: u32,
+    timeouts: Timeouts,
+) -> Vec<Result<HashMap<String, Vec<T>>, ProtocolError>> {
+    let n_parties = networks.len() as u32;
+    if let Err(e) = check_fractional_bits(fractional_bits) {
+        return (0..n_parties)
+            .map(|_| Err(ProtocolError::InvalidInput(e.clone())))
+            .collect();
+    }
     let (senders, receivers): (Vec<_>, Vec<_>) =
         (0..n_parties).map(|_| unbounded_channel()).unzip();
     let (cmd_tx, cmd_rx) = unbounded_channel();
 
     let dealer = TrustedDealer::new(
         n_parties as u8,
+        batch_size,
         senders
             .into_iter()
             .enumerate()
@@ -204,37 +578,37 @@ pub async fn run_nodes(
     );
 
     let mut handles = vec![];
-    let _hansu = tokio::spawn(dealer.run());
+    let _hansu = tokio::spawn(async move {
+        if let Err(e) = dealer.run().await {
+            log::warn!("dealer stopped: {}", e);
+        }
+    });
 
-    for ((id, n), r) in (0..n_parties)
-        .zip(networks.into_iter())
-        .zip(receivers.into_iter())
-    {
-        let variables = variable_values
-            .iter()
-            .enumerate()
-            .fold(HashMap::new(), |mut l, (i, r)| {
-                for k in r.keys() {
-                    l.insert(k.clone(), i as u64);
-                }
-                l
-            });
+    for ((id, n), r) in (0..n_parties).zip(networks).zip(receivers) {
+        let variables = variable_owners(&variable_values);
         let our_variables = variable_values[id as usize].clone();
         let config = NodeConfig {
             id: id as u64,
             n_parties: n_parties as u8,
             network: n,
             dealer: (cmd_tx.clone(), r),
-            expression: expression.clone(),
+            outputs: outputs.clone(),
             variables,
             our_variables,
+            batch_size,
+            fractional_bits,
+            timeouts,
         };
         handles.push(tokio::spawn(run_node(config)));
     }
 
     let mut results = vec![];
     for handle in handles {
-        results.push(handle.await);
+        results.push(
+            handle
+                .await
+                .unwrap_or_else(|e| Err(ProtocolError::Crashed(e.to_string()))),
+        );
     }
     results
 }
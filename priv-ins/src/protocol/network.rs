@@ -1,16 +1,19 @@
-use crate::crypto::shares::{Commitment, CommitmentProof, Elem, Share};
+use crate::crypto::shares::{Commitment, CommitmentProof, Elems, Hash, Shares};
 use crate::protocol::{CirId, NodeId};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Msg {
-    OpenShare(CirId, Share),
-    OpenVariable(CirId, Elem),
+    OpenShare(CirId, Shares),
+    OpenVariable(CirId, Elems),
     Commit(CirId, Commitment),
     Proof(CirId, CommitmentProof),
     ProofInvalid(CirId),
     ProofValid(CirId),
+    /// digests of shares received from each node, indexed by node id, before check of cir_id
+    Digests(CirId, Vec<Hash>),
 }
 
 pub type NetworkMessage = (NodeId, Msg);
@@ -51,9 +54,9 @@ pub fn setup_network(n: u32) -> Vec<ChannelNetwork> {
             let peers: HashMap<_, _> = senders
                 .iter()
                 .enumerate()
-                .map(|(i, s)| ((i + 1) as NodeId, s.clone()))
+                .map(|(i, s)| (i as NodeId, s.clone()))
                 .collect();
-            ChannelNetwork::new((id + 1) as NodeId, peers, receiver)
+            ChannelNetwork::new(id as NodeId, peers, receiver)
         })
         .collect()
 }
@@ -65,7 +68,10 @@ impl Network for ChannelNetwork {
             println!("Network::send_to {:?}", msg.0);
         }
         if let Some(sender) = self.peers.get(&msg.0) {
-            sender.send((self.id, msg.1)).expect("Should be open");
+            // peer that finished or aborted doesn't need our messages anymore
+            if sender.send((self.id, msg.1)).is_err() {
+                log::debug!("node {} is gone", msg.0);
+            }
         }
     }
 
@@ -76,8 +82,10 @@ impl Network for ChannelNetwork {
         if self.id == 0 {
             println!("Network::broadcast");
         }
-        for sender in self.peers.values() {
-            sender.send((self.id, msg.clone())).expect("Should be open");
+        for (id, sender) in self.peers.iter() {
+            if sender.send((self.id, msg.clone())).is_err() {
+                log::debug!("node {} is gone", id);
+            }
         }
     }
 }
@@ -1,97 +1,127 @@
 use crate::crypto::shares::{
-    sum_elems, BeaverShare, Commitment, CommitmentProof, Elem, Share, Shares,
+    self, sum_elems, BeaverShare, BitMaskShare, Commitment, CommitmentProof, Elem, Elems, Hash,
+    Share, Shares, TruncationMaskShare,
 };
-use crate::ff::PrimeField;
+use crate::ff::{Field, PrimeField};
 use std::collections::HashSet;
 use std::{collections::HashMap, fmt::Debug, ops::Sub};
 
-use crate::protocol::arithmetics::verify_commitments;
+use crate::protocol::arithmetics::{combine_openings, verify_commitments};
+use crate::protocol::error::{BlameReport, Offence, ProtocolError};
 use crate::protocol::{
     arithmetics::Calculator,
-    expression::{DecoratedExpression, MidEvalExpression},
+    expression::{into_layers, power_ids, DecoratedExpression, MidEvalExpression},
     node::NodeState::{
-        HaveBeaver, HaveShares, Proceed, WaitForBeaver, WaitForCommitments, WaitForShares,
-        WaitForVariable,
+        AssertBit, EqualsZero, LessThanZero, Multiply, Pow, Proceed, Truncate, WaitForVariable,
     },
-    sub_id, Alpha, CirId, NodeCommands, NodeEvents, NodeId,
+    sub_id,
+    subprotocols::{
+        zip_rows, BitCheck, Comparison, Exchanged, Power, Product, Round, Step, Subprotocol,
+        Truncation,
+    },
+    Alpha, CirId, NodeCommands, NodeEvents, NodeId, Phase, Timeouts,
 };
 use tokio::sync::mpsc::{UnboundedReceiver as Receiver, UnboundedSender as Sender};
+use tokio::time::{timeout, timeout_at, Instant};
 
 #[derive(Debug)]
-/// Internal state of node with following transitions:
-/// Proceed -> WaitForVariable | WaitForBeaver | Proceed
-/// WaitForVariable -> Proceed | WaitForVariable
-/// WaitForBeaver -> HaveBeaver | WaitForBeaver
-/// HaveBeaver -> WaitForShares
-/// WaitForShares -> HaveShares | WaitForShare
-/// HaveShares -> WaitForCommitments
-/// WaitForCommitments -> Proceed | WaitForCommitments
+/// State of circuit node after trying to evaluate it locally.
+/// Nodes of one layer (see `into_layers`) are evaluated in two steps:
+/// linear nodes (Proceed) and variables (WaitForVariable) go first, then subprotocols
+/// of all interactive nodes of the layer run together, sharing their rounds.
 ///
-/// In particular following path represents multiplication phases.
-/// WaitForBeaver -> HaveBeaver -> WaitForShares -> HaveShares -> WaitForCommitments
+/// Every state holds shares of all rows of the batch, so each step handles all rows at once.
 enum NodeState {
-    /// we can proceed with evaluating
+    /// node is evaluated
     Proceed,
     /// waiting for shares of variable in the cir_id
     WaitForVariable(CirId),
-    /// waiting for beaver shares in mul cir_id node
-    WaitForBeaver(CirId, Share, Share),
-    /// waiting for shares of (x - e) and (y - f) used in mul
-    WaitForShares(CirId, CirId, CirId, BeaverShare),
-    /// got beaver shares for mul cir_id node
-    HaveBeaver(CirId, Share, Share),
-    /// have all shares of (x - e) and (y - f) used in mul
-    HaveShares(CirId, CirId, CirId, BeaverShare),
-    /// wait for all commitments for e and f
-    WaitForCommitments(CirId, CirId),
+    /// have operands of mul cir_id node, multiply them together with the rest of the layer
+    Multiply(CirId, Shares, Shares),
+    /// have operand of comparison cir_id node, run the comparison subprotocol
+    LessThanZero(CirId, Shares),
+    /// have operand of equality test cir_id node, run the equality subprotocol
+    EqualsZero(CirId, Shares),
+    /// have value of cir_id node that should be a bit, run the bit check
+    AssertBit(CirId, Shares),
+    /// have value of cir_id node that should be truncated by given number of bits
+    Truncate(CirId, u32, Shares),
+    /// have base of power cir_id node with exponent and number of bits to truncate products by
+    Pow(CirId, u32, u32, Shares),
 }
 
+/// Evaluates circuit over a batch of rows, value of every circuit node is a vector of shares
+/// with one share per row
 pub struct Node {
     id: NodeId,
+    /// number of rows evaluated in the session
+    batch_size: usize,
     alpha_channel: Receiver<Alpha>,
     party_commands: Sender<NodeCommands>,
     party_events: Receiver<NodeEvents>,
-    evaluated: HashMap<CirId, Share>,
-    fully_open: HashMap<CirId, Shares>,
-    variables: HashMap<CirId, Elem>,
-    beavers: HashMap<CirId, BeaverShare>,
-    variable_shares: HashMap<CirId, Share>,
-    variable_salts: HashMap<CirId, Elem>,
-    my_proofs: HashMap<CirId, CommitmentProof>,
+    evaluated: HashMap<CirId, Shares>,
+    /// number of nodes yet to be evaluated that use value of cir_id node
+    uses: HashMap<CirId, usize>,
+    fully_open: HashMap<CirId, Vec<Shares>>,
+    variables: HashMap<CirId, Elems>,
+    beavers: HashMap<CirId, Vec<BeaverShare>>,
+    bit_masks: HashMap<CirId, Vec<BitMaskShare>>,
+    randoms: HashMap<CirId, Shares>,
+    truncation_masks: HashMap<CirId, Vec<TruncationMaskShare>>,
+    variable_shares: HashMap<CirId, Shares>,
+    variable_salts: HashMap<CirId, Elems>,
+    /// opened values with our shares of their macs, waiting for `check_macs`
+    unchecked: HashMap<CirId, Vec<(Elem, Elem)>>,
     proofs: HashMap<CirId, Vec<(NodeId, CommitmentProof)>>,
     commitments: HashMap<CirId, Vec<(NodeId, Commitment)>>,
     valid_proofs: HashSet<CirId>,
-    invalid_proofs: HashSet<CirId>,
-    original_shares: HashMap<CirId, Share>,
+    /// nodes caught lying about their shares, reported before check of cir_id
+    digest_blames: HashMap<CirId, BlameReport>,
+    original_shares: HashMap<CirId, Shares>,
+    timeouts: Timeouts,
 }
 
 impl Node {
     pub fn new(
         id: NodeId,
+        batch_size: usize,
         alpha_channel: Receiver<Alpha>,
         party_commands: Sender<NodeCommands>,
         party_events: Receiver<NodeEvents>,
-        variables: HashMap<CirId, Elem>,
+        variables: HashMap<CirId, Elems>,
     ) -> Self {
         Self {
             id,
+            batch_size,
             alpha_channel,
             party_commands,
             party_events,
             variables,
             evaluated: HashMap::new(),
+            uses: HashMap::new(),
             fully_open: HashMap::new(),
             beavers: HashMap::new(),
+            bit_masks: HashMap::new(),
+            randoms: HashMap::new(),
+            truncation_masks: HashMap::new(),
             variable_shares: HashMap::new(),
             variable_salts: HashMap::new(),
-            my_proofs: HashMap::new(),
+            unchecked: HashMap::new(),
             commitments: HashMap::new(),
             proofs: HashMap::new(),
             valid_proofs: HashSet::new(),
-            invalid_proofs: HashSet::new(),
+            digest_blames: HashMap::new(),
             original_shares: HashMap::new(),
+            timeouts: Timeouts::default(),
         }
     }
+
+    /// Sets time limits of waiting for messages in each phase
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
     /// checks if we have both x - r and [r] for variable under `var_node` if so put x - r + [r] under
     /// var_node in evaluated nodes.
     fn combine_variable_if_full(&mut self, var_node: CirId, calculator: &Calculator) {
@@ -107,29 +137,49 @@ impl Node {
         let s1 = self.variable_shares.remove(&var_node).expect("checked");
         let s2 = self.variable_salts.remove(&var_node).expect("checked");
 
-        self.evaluated
-            .insert(var_node, calculator.add_const(s1, s2));
+        self.evaluated.insert(
+            var_node,
+            zip_rows(&s1, &s2, |s1, s2| calculator.add_const(*s1, *s2)),
+        );
     }
 
-    async fn wait_for_calculator(&mut self) -> Calculator {
+    async fn wait_for_calculator(&mut self) -> Result<Calculator, ProtocolError> {
         if self.id == 0 {
             println!("wait_for_calculator");
         }
 
-        let Alpha(alpha) = self
-            .alpha_channel
-            .recv()
-            .await
-            .expect("Without alpha we are doomed anyway");
+        let alpha = timeout(self.timeouts.inputs, self.alpha_channel.recv()).await;
+        let Alpha(alpha) = match alpha {
+            Ok(alpha) => alpha.ok_or_else(|| ProtocolError::ChannelClosed("alpha".to_string()))?,
+            Err(_) => return Err(ProtocolError::Timeout(Phase::Inputs, vec![])),
+        };
 
-        Calculator::new(self.id, alpha)
+        Ok(Calculator::new(self.id, alpha))
     }
 
-    fn can_proceed(&self, state: &NodeState) -> bool {
-        match state {
-            Proceed => true,
-            _ => false,
+    /// Sends command to our party
+    fn send(&self, command: NodeCommands) -> Result<(), ProtocolError> {
+        self.party_commands
+            .send(command)
+            .map_err(|_| ProtocolError::ChannelClosed("party commands".to_string()))
+    }
+
+    /// Returns value of evaluated node, it is dropped after its last use
+    fn take_evaluated(&mut self, cir_id: &CirId) -> Shares {
+        let uses = self.uses.entry(cir_id.clone()).or_insert(1);
+        *uses -= 1;
+        if *uses > 0 {
+            return self
+                .evaluated
+                .get(cir_id)
+                .expect("we should have already evaluated it")
+                .clone();
         }
+
+        self.uses.remove(cir_id);
+        self.evaluated
+            .remove(cir_id)
+            .expect("we should have already evaluated it")
     }
 
     fn try_proceed(
@@ -143,50 +193,93 @@ impl Node {
 
         match evaluating {
             MidEvalExpression::AddConstant(s, evaluated_node, cir_id) => {
-                let evaluated = self
-                    .evaluated
-                    .remove(evaluated_node)
-                    .expect("we should have already evaluated it");
+                let evaluated = self.take_evaluated(evaluated_node);
 
-                let v = calculator.add_const(evaluated, Elem::from(s.clone()));
+                let v = evaluated
+                    .iter()
+                    .map(|x| calculator.add_const(*x, Elem::from(*s)))
+                    .collect();
 
                 self.evaluated.insert(cir_id.to_string(), v);
             }
             MidEvalExpression::Add(e1, e2, cir_id) => {
-                let ev1 = self
-                    .evaluated
-                    .remove(e1)
-                    .expect("we should have already evaluated it");
-                let ev2 = self
-                    .evaluated
-                    .remove(e2)
-                    .expect("we should have already evaluated it");
+                let ev1 = self.take_evaluated(e1);
+                let ev2 = self.take_evaluated(e2);
 
-                let v = calculator.add(ev1, ev2);
+                let v = zip_rows(&ev1, &ev2, |x, y| calculator.add(*x, *y));
+
+                self.evaluated.insert(cir_id.to_string(), v);
+            }
+            MidEvalExpression::Sub(e1, e2, cir_id) => {
+                let ev1 = self.take_evaluated(e1);
+                let ev2 = self.take_evaluated(e2);
+
+                let v = zip_rows(&ev1, &ev2, |x, y| calculator.sub(*x, *y));
+
+                self.evaluated.insert(cir_id.to_string(), v);
+            }
+            MidEvalExpression::Neg(evaluated_node, cir_id) => {
+                let evaluated = self.take_evaluated(evaluated_node);
+
+                let v = evaluated
+                    .iter()
+                    .map(|x| calculator.sub((Elem::zero(), Elem::zero()), *x))
+                    .collect();
 
                 self.evaluated.insert(cir_id.to_string(), v);
             }
             MidEvalExpression::MulConstant(s, evaluated_node, cir_id) => {
-                let evaluated = self
-                    .evaluated
-                    .remove(evaluated_node)
-                    .expect("we should have already evaluated it");
+                let evaluated = self.take_evaluated(evaluated_node);
 
-                let v = calculator.mul_by_const(evaluated, Elem::from(s.clone()));
+                let v = evaluated
+                    .iter()
+                    .map(|x| calculator.mul_by_const(*x, Elem::from(*s)))
+                    .collect();
+
+                self.evaluated.insert(cir_id.to_string(), v);
+            }
+            MidEvalExpression::DivConstant(s, evaluated_node, cir_id) => {
+                let evaluated = self.take_evaluated(evaluated_node);
+
+                // divisor is checked to be nonzero in `decorate_expression`
+                let inverse = s.invert().unwrap();
+                let v = evaluated
+                    .iter()
+                    .map(|x| calculator.mul_by_const(*x, inverse))
+                    .collect();
 
                 self.evaluated.insert(cir_id.to_string(), v);
             }
             MidEvalExpression::Mul(e1, e2, cir_id) => {
-                let ev1 = self
-                    .evaluated
-                    .remove(e1)
-                    .expect("we should have already evaluated it");
-                let ev2 = self
-                    .evaluated
-                    .remove(e2)
-                    .expect("we should have already evaluated it");
+                let ev1 = self.take_evaluated(e1);
+                let ev2 = self.take_evaluated(e2);
+
+                return Multiply(cir_id.to_string(), ev1, ev2);
+            }
+            MidEvalExpression::LessThanZero(evaluated_node, cir_id) => {
+                let evaluated = self.take_evaluated(evaluated_node);
+
+                return LessThanZero(cir_id.to_string(), evaluated);
+            }
+            MidEvalExpression::EqualsZero(evaluated_node, cir_id) => {
+                let evaluated = self.take_evaluated(evaluated_node);
+
+                return EqualsZero(cir_id.to_string(), evaluated);
+            }
+            MidEvalExpression::AssertBit(evaluated_node, cir_id) => {
+                let evaluated = self.take_evaluated(evaluated_node);
+
+                return AssertBit(cir_id.to_string(), evaluated);
+            }
+            MidEvalExpression::Truncate(evaluated_node, n_bits, cir_id) => {
+                let evaluated = self.take_evaluated(evaluated_node);
 
-                return WaitForBeaver(cir_id.to_string(), ev1, ev2);
+                return Truncate(cir_id.to_string(), *n_bits, evaluated);
+            }
+            MidEvalExpression::Pow(evaluated_node, exponent, n_bits, cir_id) => {
+                let evaluated = self.take_evaluated(evaluated_node);
+
+                return Pow(cir_id.to_string(), *exponent, *n_bits, evaluated);
             }
             MidEvalExpression::Var(cir_id) => {
                 if !self.evaluated.contains_key(cir_id) {
@@ -198,7 +291,11 @@ impl Node {
         Proceed
     }
 
-    fn handle_event(&mut self, event: NodeEvents, calculator: &Calculator) {
+    fn handle_event(
+        &mut self,
+        event: NodeEvents,
+        calculator: &Calculator,
+    ) -> Result<(), ProtocolError> {
         if self.id == 0 {
             println!("NodeEvents::{:?}", event);
         }
@@ -215,49 +312,74 @@ impl Node {
             NodeEvents::SelfVariableReady(c_id, r, r_share) => {
                 if !self.variables.contains_key(&c_id) {
                     log::debug!("got foreign variable");
-                    return;
+                    return Ok(());
                 }
                 let x = self.variables.get(&c_id).expect("checked");
 
-                let xr = x.sub(r);
+                let xr = zip_rows(x, &r, |x, r| x.sub(r));
                 // send to everyone x-r
-                self.party_commands
-                    .send(NodeCommands::OpenSelfShare(xr, c_id.clone()))
-                    .expect("send should succeed");
+                self.send(NodeCommands::OpenSelfShare(xr.clone(), c_id.clone()))?;
                 // evaluate our variable as (x-r) + r_share
-                self.evaluated
-                    .insert(c_id, calculator.add_const(r_share, xr));
+                self.evaluated.insert(
+                    c_id,
+                    zip_rows(&r_share, &xr, |r_share, xr| {
+                        calculator.add_const(*r_share, *xr)
+                    }),
+                );
             }
             NodeEvents::NodeVariableReady(c_id, s) => {
                 if self.variable_salts.contains_key(&c_id) {
                     log::debug!("got twice value for {}", c_id);
-                    return;
+                    return Ok(());
                 }
-
                 self.variable_salts.insert(c_id.clone(), s);
-                self.combine_variable_if_full(c_id, &calculator);
+                self.combine_variable_if_full(c_id, calculator);
             }
             NodeEvents::BeaverFor(c_id, beaver) => {
                 if self.beavers.contains_key(&c_id) {
                     log::debug!("got twice value for {}", c_id);
-                    return;
+                    return Ok(());
                 }
 
                 self.beavers.insert(c_id, beaver);
             }
+            NodeEvents::BitMaskFor(c_id, mask) => {
+                if self.bit_masks.contains_key(&c_id) {
+                    log::debug!("got twice value for {}", c_id);
+                    return Ok(());
+                }
+
+                self.bit_masks.insert(c_id, mask);
+            }
+            NodeEvents::RandomFor(c_id, random) => {
+                if self.randoms.contains_key(&c_id) {
+                    log::debug!("got twice value for {}", c_id);
+                    return Ok(());
+                }
+
+                self.randoms.insert(c_id, random);
+            }
+            NodeEvents::TruncationMaskFor(c_id, mask) => {
+                if self.truncation_masks.contains_key(&c_id) {
+                    log::debug!("got twice value for {}", c_id);
+                    return Ok(());
+                }
+
+                self.truncation_masks.insert(c_id, mask);
+            }
             NodeEvents::NodeVariableShareReady(c_id, s) => {
                 if self.variable_shares.contains_key(&c_id) {
                     log::debug!("got twice value for {}", c_id);
-                    return;
+                    return Ok(());
                 }
 
                 self.variable_shares.insert(c_id.clone(), s);
-                self.combine_variable_if_full(c_id, &calculator);
+                self.combine_variable_if_full(c_id, calculator);
             }
             NodeEvents::CommitmentsFor(cir_id, commitments) => {
                 if self.commitments.contains_key(&cir_id) {
                     log::debug!("got twice commitments for {}", cir_id);
-                    return;
+                    return Ok(());
                 }
 
                 self.commitments.insert(cir_id, commitments);
@@ -265,7 +387,7 @@ impl Node {
             NodeEvents::ProofsFor(cir_id, proofs) => {
                 if self.proofs.contains_key(&cir_id) {
                     log::debug!("got twice proofs for {}", cir_id);
-                    return;
+                    return Ok(());
                 }
 
                 self.proofs.insert(cir_id, proofs);
@@ -274,406 +396,769 @@ impl Node {
                 self.valid_proofs.insert(cir_id);
             }
             NodeEvents::ProofInvalid(cir_id) => {
-                self.invalid_proofs.insert(cir_id);
+                return Err(ProtocolError::AbortedByPeer(cir_id));
+            }
+            NodeEvents::DigestsCompared(cir_id, report) => {
+                self.digest_blames.insert(cir_id, report);
+            }
+            NodeEvents::Missing(missing) => {
+                log::debug!(
+                    "got report of missing messages {:?} without asking",
+                    missing
+                );
             }
         }
+
+        Ok(())
     }
 
-    /// we got beaver lets start evaluating mul node
-    async fn handle_beaver(
+    /// we got beaver lets start evaluating mul node, returns ids of opened e and f
+    fn handle_beaver(
         &mut self,
         calculator: &Calculator,
-        cir_id: CirId,
-        ev1: Share,
-        ev2: Share,
-    ) -> NodeState {
-        let beaver = self.beavers.remove(&cir_id).expect("checked");
-
-        let (e, f) = calculator.mul_prepare(
-            Share::from(ev1.clone()),
-            Share::from(ev2.clone()),
-            BeaverShare::from(beaver.clone()),
-        );
-
-        let e_id = sub_id(&cir_id, &"e".to_string());
-        let f_id = sub_id(&cir_id, &"f".to_string());
+        cir_id: &CirId,
+        ev1: Shares,
+        ev2: Shares,
+    ) -> Result<(CirId, CirId, Vec<BeaverShare>), ProtocolError> {
+        let beaver = self.beavers.remove(cir_id).expect("checked");
+
+        // e and f of all rows are opened in one message each
+        let (e, f): (Shares, Shares) = ev1
+            .iter()
+            .zip(ev2.iter())
+            .zip(beaver.iter())
+            .map(|((x, y), b)| calculator.mul_prepare(*x, *y, *b))
+            .unzip();
+
+        let e_id = sub_id(cir_id, &"e".to_string());
+        let f_id = sub_id(cir_id, &"f".to_string());
 
         self.original_shares.insert(e_id.clone(), e.clone());
         self.original_shares.insert(f_id.clone(), f.clone());
 
-        self.party_commands
-            .send(NodeCommands::OpenShare(e, e_id.to_string()))
-            .expect("Send should succeed");
+        self.send(NodeCommands::OpenShare(e, e_id.to_string()))?;
 
-        self.party_commands
-            .send(NodeCommands::OpenShare(f, f_id.to_string()))
-            .expect("Send should succeed");
+        self.send(NodeCommands::OpenShare(f, f_id.to_string()))?;
 
-        WaitForShares(cir_id, e_id, f_id, beaver)
+        Ok((e_id, f_id, beaver))
     }
 
-    /// we got all shares for mul nodes (of (x - e) and (y - f)
+    /// we got all shares for mul nodes (of (x - e) and (y - f), returns the product
     fn handle_shares(
         &mut self,
         calculator: &Calculator,
-        cir_id: CirId,
-        e_id: CirId,
-        f_id: CirId,
-        beaver: BeaverShare,
-    ) -> NodeState {
-        let e_shares = self.fully_open.remove(&e_id).expect("checked");
-        let f_shares = self.fully_open.remove(&f_id).expect("checked");
-
-        let e_elem = sum_elems(&e_shares.into_iter().map(|(e, _)| e).collect());
-        let f_elem = sum_elems(&f_shares.into_iter().map(|(e, _)| e).collect());
+        e_id: &CirId,
+        f_id: &CirId,
+        beaver: Vec<BeaverShare>,
+    ) -> Shares {
+        let e_shares = self.fully_open.remove(e_id).expect("checked");
+        let f_shares = self.fully_open.remove(f_id).expect("checked");
+
+        let e_elems = open_rows(&e_shares, self.batch_size);
+        let f_elems = open_rows(&f_shares, self.batch_size);
+
+        let e = self.original_shares.remove(e_id).expect("checked");
+        let f = self.original_shares.remove(f_id).expect("checked");
+        self.record_opening(e_id, &e_elems, &e);
+        self.record_opening(f_id, &f_elems, &f);
+
+        beaver
+            .iter()
+            .zip(e_elems.iter().zip(f_elems.iter()))
+            .map(|(beaver, (e, f))| calculator.mul(*beaver, *e, *f))
+            .collect()
+    }
 
-        let e_x = calculator.generate_commitment_share(
-            e_elem.clone(),
-            self.original_shares.remove(&e_id).expect("checked"),
-        );
-        let f_x = calculator.generate_commitment_share(
-            f_elem.clone(),
-            self.original_shares.remove(&f_id).expect("checked"),
+    /// Keeps values of all rows of `cir_id` opened from `shares` with our shares of their macs
+    /// until the next `check_macs`
+    fn record_opening(&mut self, cir_id: &CirId, opened: &[Elem], shares: &[Share]) {
+        self.unchecked.insert(
+            cir_id.clone(),
+            zip_rows(opened, shares, |opened, share| (*opened, share.1)),
         );
+    }
 
-        let (e_hash, e_salt) = Calculator::generate_commitment(&e_x);
-        let (f_hash, f_salt) = Calculator::generate_commitment(&f_x);
-
-        let e_proof = (e_hash, e_x.clone(), e_salt);
-        let f_proof = (f_hash, f_x.clone(), f_salt);
+    /// Tells other nodes that check of `cir_id` failed and aborts with `error`
+    fn abort_check<T>(&self, cir_id: &CirId, error: ProtocolError) -> Result<T, ProtocolError> {
+        // we abort anyway, even if others can't be told about it
+        let _ = self.send(NodeCommands::ProofInvalid(cir_id.clone()));
+        Err(error)
+    }
 
-        self.my_proofs.insert(e_id.clone(), e_proof);
-        self.my_proofs.insert(f_id.clone(), f_proof);
+    /// Commits to `elems` under `cir_id` and opens the commitment once all nodes committed.
+    /// Returns opened commitments of all nodes ordered by their ids.
+    async fn commit_and_reveal(
+        &mut self,
+        calculator: &Calculator,
+        cir_id: &CirId,
+        elems: Elems,
+    ) -> Result<Vec<(NodeId, CommitmentProof)>, ProtocolError> {
+        let (hash, salt) = Calculator::generate_commitment(&elems);
+        self.send(NodeCommands::CommitmentFor(cir_id.clone(), hash))?;
+        self.wait_until(calculator, Phase::MacCheck, |node| {
+            node.commitments.contains_key(cir_id)
+        })
+        .await?;
+
+        self.send(NodeCommands::ProofFor(cir_id.clone(), (hash, elems, salt)))?;
+        self.wait_until(calculator, Phase::MacCheck, |node| {
+            node.proofs.contains_key(cir_id)
+        })
+        .await?;
+
+        let mut proofs = self.proofs.remove(cir_id).expect("checked");
+        let mut commits = self.commitments.remove(cir_id).expect("checked");
+
+        proofs.sort_by_key(|a| a.0);
+        commits.sort_by_key(|a| a.0);
+
+        // party collects one commitment and proof from each node
+        let report = proofs
+            .iter()
+            .zip(commits)
+            .filter(|((a_id, proof), (b_id, comm))| {
+                a_id != b_id
+                    || proof.0 != *comm
+                    || proof.0 != shares::compute_commitment(&proof.1, &proof.2)
+            })
+            .map(|((a_id, _), _)| (*a_id, Offence::CommitmentMismatch(cir_id.clone())))
+            .collect::<Vec<_>>();
+        if !report.is_empty() {
+            return self.abort_check(cir_id, ProtocolError::Cheating(report));
+        }
 
-        self.party_commands
-            .send(NodeCommands::CommitmentFor(e_id.clone(), e_hash))
-            .expect("send should succeed");
-        self.party_commands
-            .send(NodeCommands::CommitmentFor(f_id.clone(), f_hash))
-            .expect("send should succeed");
+        Ok(proofs)
+    }
 
-        let v = calculator.mul(beaver, e_elem, f_elem);
-        self.evaluated.insert(cir_id, v);
+    /// Tosses a coin together with other nodes by committing to random elements and opening
+    /// them afterwards, returns seed that is random as long as one node is honest
+    async fn toss_coin(
+        &mut self,
+        calculator: &Calculator,
+        cir_id: &CirId,
+    ) -> Result<Hash, ProtocolError> {
+        let coin = Elem::random(rand::thread_rng());
+        let proofs = self
+            .commit_and_reveal(calculator, cir_id, vec![coin])
+            .await?;
+
+        if let Some((node_id, _)) = proofs.iter().find(|(_, proof)| proof.1.len() != 1) {
+            let error = ProtocolError::ProtocolViolation(
+                *node_id,
+                format!("coin of {} is not a single element", cir_id),
+            );
+            return self.abort_check(cir_id, error);
+        }
 
-        WaitForCommitments(e_id, f_id)
+        let coin = sum_elems(&proofs.iter().map(|(_, proof)| proof.1[0]).collect());
+        Ok(shares::hash(&coin.to_repr().0))
     }
 
-    /// check state of node and make transition if needed
-    fn state_transition(&self, state: NodeState, expr: Option<&MidEvalExpression>) -> NodeState {
-        match (state, expr) {
-            (WaitForVariable(cir_id), _) => {
-                if self.evaluated.contains_key(&cir_id) {
-                    Proceed
-                } else {
-                    WaitForVariable(cir_id)
-                }
-            }
-            (WaitForBeaver(cir_id, s1, s2), _) => {
-                if self.beavers.contains_key(&cir_id) {
-                    HaveBeaver(cir_id, s1, s2)
-                } else {
-                    WaitForBeaver(cir_id, s1, s2)
-                }
-            }
-            (WaitForShares(c, e, f, beaver), _) => {
-                if self.fully_open.contains_key(&e) && self.fully_open.contains_key(&f) {
-                    HaveShares(c, e, f, beaver)
-                } else {
-                    WaitForShares(c, e, f, beaver)
-                }
-            }
-            (WaitForCommitments(c1, c2), _) => {
-                if self.commitments.contains_key(&c1) && self.commitments.contains_key(&c2) {
-                    Proceed
-                } else {
-                    WaitForCommitments(c1, c2)
-                }
-            }
-            (Proceed, Some(MidEvalExpression::Var(cid))) if !self.evaluated.contains_key(cid) => {
-                WaitForVariable(cid.clone())
-            }
-            (state, _) => state,
+    /// Checks macs of all values opened since the previous check in one round of commitments.
+    /// First nodes compare digests of shares they received to catch nodes that sent different
+    /// shares to different nodes, as the mac check only tells that some opening was wrong.
+    /// Then nodes toss coefficients of a random linear combination of opened values, then every node
+    /// commits to its partial opening of the combination's mac, see `combine_openings`.
+    /// Partial openings sum to 0 unless some opened value differs from the authenticated one.
+    async fn check_macs(
+        &mut self,
+        calculator: &Calculator,
+        check_id: CirId,
+    ) -> Result<(), ProtocolError> {
+        // every node opened the same values, so all of them skip the check together
+        if self.unchecked.is_empty() {
+            return Ok(());
         }
-    }
 
-    async fn wait_for_proofs(&mut self, calculator: &Calculator) {
-        let mut need_proofs_for = self.my_proofs.keys().cloned().collect::<HashSet<_>>();
+        let mut cir_ids = self.unchecked.keys().cloned().collect::<Vec<_>>();
+        cir_ids.sort();
+
+        self.send(NodeCommands::CompareDigests(
+            check_id.clone(),
+            cir_ids.clone(),
+        ))?;
+        self.wait_until(calculator, Phase::MacCheck, |node| {
+            node.digest_blames.contains_key(&check_id)
+        })
+        .await?;
+        let report = self.digest_blames.remove(&check_id).expect("checked");
+        if !report.is_empty() {
+            return self.abort_check(&check_id, ProtocolError::Cheating(report));
+        }
 
-        for cir_id in self.proofs.keys() {
-            need_proofs_for.remove(cir_id);
+        let seed = self
+            .toss_coin(calculator, &sub_id(&check_id, &"coin".to_string()))
+            .await?;
+        let openings = cir_ids
+            .iter()
+            .flat_map(|cir_id| self.unchecked.remove(cir_id).expect("listed"))
+            .collect::<Vec<_>>();
+
+        let (value, mac) = combine_openings(&openings, seed);
+        let partial = calculator.generate_commitment_share(value, mac);
+        let proofs = self
+            .commit_and_reveal(calculator, &check_id, vec![partial])
+            .await?;
+
+        let proofs = proofs
+            .into_iter()
+            .map(|(_, proof)| proof)
+            .collect::<Vec<_>>();
+        if !verify_commitments(&proofs) {
+            return self.abort_check(&check_id, ProtocolError::MacCheckFailed(check_id.clone()));
         }
 
-        while !need_proofs_for.is_empty() {
-            let event = match self.party_events.recv().await {
-                Some(e) => e,
-                None => {
-                    log::debug!("party channel closed");
-                    return;
-                }
-            };
-            self.handle_event(event, &calculator);
+        self.send(NodeCommands::ProofVerified(check_id.clone()))?;
 
-            for cir_id in self.proofs.keys() {
-                need_proofs_for.remove(cir_id);
-            }
-        }
+        // wait for all nodes to conclude their checks
+        self.wait_until(calculator, Phase::MacCheck, |node| {
+            node.valid_proofs.contains(&check_id)
+        })
+        .await
     }
 
-    fn check_proofs(&mut self) -> HashSet<CirId> {
-        let cir_ids = self.proofs.keys().cloned().collect::<Vec<_>>();
-        for cir_id in cir_ids.iter() {
-            let mut proofs = self.proofs.remove(cir_id).expect("checked");
+    async fn next_event(&mut self) -> Result<NodeEvents, ProtocolError> {
+        self.party_events.recv().await.ok_or_else(|| {
+            log::debug!("party channel closed");
+            ProtocolError::ChannelClosed("party events".to_string())
+        })
+    }
 
-            let mut commits = self.commitments.remove(cir_id).expect("checked");
+    /// handles incoming events until `done` holds, fails if it takes longer than
+    /// time limit of `phase`
+    async fn wait_until(
+        &mut self,
+        calculator: &Calculator,
+        phase: Phase,
+        done: impl Fn(&Self) -> bool,
+    ) -> Result<(), ProtocolError> {
+        let deadline = Instant::now() + self.timeouts.of(phase);
+        while !done(self) {
+            let event = match timeout_at(deadline, self.next_event()).await {
+                Ok(event) => event?,
+                Err(_) => return Err(self.timed_out(phase).await),
+            };
+            self.handle_event(event, calculator)?;
+        }
 
-            proofs.sort_by(|a, b| a.0.cmp(&b.0));
-            commits.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(())
+    }
 
-            for ((a_id, proof), (b_id, comm)) in proofs.iter().zip(commits) {
-                if *a_id != b_id {
-                    panic!("this should be checked in party probably");
-                }
+    /// Asks party which nodes did not deliver their messages and returns timeout error with them
+    async fn timed_out(&mut self, phase: Phase) -> ProtocolError {
+        log::debug!("node {} timed out in {:?} phase", self.id, phase);
+        if let Err(e) = self.send(NodeCommands::ReportMissing) {
+            return e;
+        }
 
-                if proof.0 != comm {
-                    self.party_commands
-                        .send(NodeCommands::ProofInvalid(cir_id.clone()))
-                        .expect("Send should succeed");
-                    panic!("Abort");
-                }
+        loop {
+            match self.next_event().await {
+                Ok(NodeEvents::Missing(missing)) => return ProtocolError::Timeout(phase, missing),
+                // we are aborting, other events don't matter anymore
+                Ok(_) => continue,
+                Err(e) => return e,
             }
+        }
+    }
 
-            if !verify_commitments(&proofs.into_iter().map(|(_, c)| c).collect()) {
-                self.party_commands
-                    .send(NodeCommands::ProofInvalid(cir_id.clone()))
-                    .expect("Send should succeed");
-                panic!("Abort");
-            }
+    /// Computes products with beavers of their ids and opens values in one round of `phase`,
+    /// returns results in the same order
+    async fn exchange(
+        &mut self,
+        calculator: &Calculator,
+        phase: Phase,
+        round: Round,
+    ) -> Result<Exchanged, ProtocolError> {
+        let Round { products, openings } = round;
+        self.wait_until(calculator, phase, |node| {
+            products
+                .iter()
+                .all(|(cir_id, _, _)| node.beavers.contains_key(cir_id))
+        })
+        .await?;
+
+        let multiplying = products
+            .into_iter()
+            .map(|(cir_id, ev1, ev2)| self.handle_beaver(calculator, &cir_id, ev1, ev2))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (cir_id, shares) in openings.iter() {
+            self.send(NodeCommands::OpenShare(shares.clone(), cir_id.clone()))?;
+        }
 
-            self.party_commands
-                .send(NodeCommands::ProofVerified(cir_id.clone()))
-                .expect("Send should succeed");
+        self.wait_until(calculator, phase, |node| {
+            multiplying.iter().all(|(e_id, f_id, _)| {
+                node.fully_open.contains_key(e_id) && node.fully_open.contains_key(f_id)
+            }) && openings
+                .iter()
+                .all(|(cir_id, _)| node.fully_open.contains_key(cir_id))
+        })
+        .await?;
+
+        let products = multiplying
+            .into_iter()
+            .map(|(e_id, f_id, beaver)| self.handle_shares(calculator, &e_id, &f_id, beaver))
+            .collect();
+
+        let mut opened = vec![];
+        for (cir_id, shares) in openings.iter() {
+            let elems = open_rows(
+                &self.fully_open.remove(cir_id).expect("checked"),
+                self.batch_size,
+            );
+            self.record_opening(cir_id, &elems, shares);
+            opened.push(elems);
         }
 
-        cir_ids.into_iter().collect()
+        Ok(Exchanged {
+            products,
+            openings: opened,
+        })
     }
 
-    async fn wait_for_others(&mut self, mut cir_ids: HashSet<CirId>, calculator: &Calculator) {
-        while !cir_ids.is_empty() {
-            for id in self.valid_proofs.iter() {
-                cir_ids.remove(id);
-            }
-            if !self.invalid_proofs.is_empty() {
-                panic!("Abort");
-            }
-            if cir_ids.is_empty() {
-                return;
-            }
-            let event = match self.party_events.recv().await {
-                Some(e) => e,
-                None => {
-                    log::debug!("party channel closed");
-                    return;
-                }
-            };
-            self.handle_event(event, &calculator);
-        }
+    /// Multiplies pairs of shares using beavers for their ids in one round,
+    /// returns products in the same order
+    async fn multiply_all(
+        &mut self,
+        calculator: &Calculator,
+        products: Vec<(CirId, Shares, Shares)>,
+    ) -> Result<Vec<Shares>, ProtocolError> {
+        let round = Round {
+            products,
+            openings: vec![],
+        };
+        Ok(self
+            .exchange(calculator, Phase::Round, round)
+            .await?
+            .products)
     }
 
-    async fn try_proceed_with_mul(
+    /// Opens shares of all rows of many values in one round of given phase,
+    /// their macs are checked later by `check_macs`. Returns opened values in the same order.
+    async fn open_all(
         &mut self,
-        state: NodeState,
         calculator: &Calculator,
-    ) -> NodeState {
+        phase: Phase,
+        openings: Vec<(CirId, Shares)>,
+    ) -> Result<Vec<Elems>, ProtocolError> {
+        let round = Round {
+            products: vec![],
+            openings,
+        };
+        Ok(self.exchange(calculator, phase, round).await?.openings)
+    }
+
+    /// Checks if dealer sent values needed to start subprotocol of the node,
+    /// beavers are awaited by `exchange`
+    fn is_preprocessed(&self, state: &NodeState) -> bool {
         match state {
-            HaveBeaver(cir_id, ev1, ev2) => self.handle_beaver(&calculator, cir_id, ev1, ev2).await,
-            HaveShares(cir_id, e_id, f_id, beaver) => {
-                self.handle_shares(&calculator, cir_id, e_id, f_id, beaver)
+            LessThanZero(cir_id, _) | EqualsZero(cir_id, _) => self.bit_masks.contains_key(cir_id),
+            AssertBit(cir_id, _) => self.randoms.contains_key(cir_id),
+            Truncate(cir_id, _, _) => self.truncation_masks.contains_key(cir_id),
+            Pow(cir_id, exponent, n_bits, _) => {
+                *n_bits == 0
+                    || power_ids(cir_id, *exponent)
+                        .iter()
+                        .all(|mul_id| self.truncation_masks.contains_key(mul_id))
             }
-            s => s,
+            _ => true,
         }
     }
 
-    pub async fn run(mut self, exp: DecoratedExpression) -> u64 {
-        self.party_commands
-            .send(NodeCommands::NeedAlpha)
-            .expect("Send should succeed");
-
-        // announce need for beaver for this circuit nodes
-        for mul_id in exp.mul_ids() {
-            self.party_commands
-                .send(NodeCommands::NeedBeaver(mul_id))
-                .expect("send should succeed");
-        }
+    /// Starts subprotocol of interactive node with values preprocessed for it
+    fn start(
+        &mut self,
+        calculator: &Calculator,
+        state: NodeState,
+    ) -> (CirId, Box<dyn Subprotocol>) {
+        let subprotocol: Box<dyn Subprotocol> = match &state {
+            Multiply(cir_id, ev1, ev2) => {
+                Box::new(Product::new(cir_id.clone(), ev1.clone(), ev2.clone()))
+            }
+            LessThanZero(cir_id, x) | EqualsZero(cir_id, x) => {
+                let mask = self.bit_masks.remove(cir_id).expect("checked");
+                Box::new(Comparison::new(
+                    calculator,
+                    cir_id.clone(),
+                    matches!(state, EqualsZero(_, _)),
+                    x.clone(),
+                    mask.into_iter().unzip(),
+                ))
+            }
+            AssertBit(cir_id, x) => {
+                let rho = self.randoms.remove(cir_id).expect("checked");
+                Box::new(BitCheck::new(cir_id.clone(), x.clone(), rho))
+            }
+            Truncate(cir_id, n_bits, x) => {
+                let mask = self.truncation_masks.remove(cir_id).expect("checked");
+                Box::new(Truncation::new(cir_id.clone(), *n_bits, x.clone(), mask))
+            }
+            Pow(cir_id, exponent, n_bits, x) => {
+                let masks = match n_bits {
+                    0 => HashMap::new(),
+                    _ => power_ids(cir_id, *exponent)
+                        .into_iter()
+                        .map(|mul_id| {
+                            let mask = self.truncation_masks.remove(&mul_id).expect("checked");
+                            (mul_id, mask)
+                        })
+                        .collect(),
+                };
+                Box::new(Power::new(cir_id, *exponent, *n_bits, x.clone(), masks))
+            }
+            state => panic!("{:?} is not a subprotocol", state),
+        };
+
+        let cir_id = match state {
+            Multiply(cir_id, _, _)
+            | LessThanZero(cir_id, _)
+            | EqualsZero(cir_id, _)
+            | AssertBit(cir_id, _)
+            | Truncate(cir_id, _, _)
+            | Pow(cir_id, _, _, _) => cir_id,
+            _ => unreachable!("checked above"),
+        };
+        (cir_id, subprotocol)
+    }
 
-        // announce to dealer our variable
-        for (var_id, _) in exp.self_var_ids(Some(self.id)) {
-            self.party_commands
-                .send(NodeCommands::OpenSelfInput(var_id))
-                .expect("send should succeed");
+    /// Evaluates one layer of circuit nodes. Linear nodes are evaluated first as they depend
+    /// only on earlier layers and each other, then subprotocols of all interactive nodes run
+    /// in steps, values of each step of all of them are exchanged in one round.
+    async fn evaluate_layer(
+        &mut self,
+        calculator: &Calculator,
+        layer: &[MidEvalExpression],
+    ) -> Result<(), ProtocolError> {
+        let mut interactive = vec![];
+
+        for evaluating in layer {
+            match self.try_proceed(calculator, evaluating) {
+                Proceed => {}
+                WaitForVariable(cir_id) => {
+                    self.wait_until(calculator, Phase::Inputs, |node| {
+                        node.evaluated.contains_key(&cir_id)
+                    })
+                    .await?;
+                }
+                state => {
+                    if self.id == 0 {
+                        println!("NodeState: {:?}", state);
+                    }
+                    interactive.push(state);
+                }
+            }
         }
 
-        let calculator = self.wait_for_calculator().await;
+        self.wait_until(calculator, Phase::Round, |node| {
+            interactive.iter().all(|state| node.is_preprocessed(state))
+        })
+        .await?;
+
+        let mut running = interactive
+            .into_iter()
+            .map(|state| self.start(calculator, state))
+            .collect::<Vec<_>>();
+        let mut results = running
+            .iter()
+            .map(|_| Exchanged::default())
+            .collect::<Vec<_>>();
+        while !running.is_empty() {
+            let mut round = Round::default();
+            let mut sizes = vec![];
+            let mut waiting = vec![];
+            for ((cir_id, mut subprotocol), exchanged) in running.into_iter().zip(results) {
+                match subprotocol.step(calculator, exchanged)? {
+                    Step::Done(value) => {
+                        self.evaluated.insert(cir_id, value);
+                    }
+                    Step::Exchange(next) => {
+                        sizes.push(next.size());
+                        round.extend(next);
+                        waiting.push((cir_id, subprotocol));
+                    }
+                }
+            }
 
-        let mut state = Proceed;
+            results = self
+                .exchange(calculator, Phase::Round, round)
+                .await?
+                .split(&sizes);
+            running = waiting;
+        }
 
-        let circuit_nodes = exp.into_ordered();
-        let mut idx = 0;
+        Ok(())
+    }
 
-        loop {
-            // everything evaluated
-            state = self.state_transition(state, circuit_nodes.get(idx));
-            if idx == circuit_nodes.len() && self.can_proceed(&state) {
-                break;
+    /// Evaluates expressions in one session and returns their values of all rows in the same order.
+    /// Expression with a recipient is revealed only to that node, others get None.
+    /// Constant expressions are public so they are returned without opening.
+    pub async fn run(
+        mut self,
+        exps: Vec<(DecoratedExpression, Option<NodeId>)>,
+    ) -> Result<Vec<Option<Elems>>, ProtocolError> {
+        self.send(NodeCommands::NeedAlpha)?;
+
+        for (exp, recipient) in exps.iter() {
+            self.announce_needs(exp)?;
+            if let (Some(recipient), false) = (recipient, exp.is_constant()) {
+                self.announce_private_output(&exp.cir_id(), *recipient)?;
             }
+        }
 
-            if self.id == 0 {
-                println!("NodeState: {:?}", state);
-            }
+        let calculator = self.wait_for_calculator().await?;
 
-            if self.can_proceed(&state) {
-                let evaluating = circuit_nodes.get(idx).expect("we control it");
-                state = self.try_proceed(&calculator, evaluating);
-                idx += 1;
+        let mut circuit_nodes = vec![];
+        let mut outputs = vec![];
+        for (exp, recipient) in exps {
+            if let DecoratedExpression::Constant(s, _) = exp {
+                outputs.push((Err(s), recipient));
                 continue;
             }
 
-            state = self.try_proceed_with_mul(state, &calculator).await;
+            // output is used once more when it is opened
+            let output_id = exp.cir_id();
+            *self.uses.entry(output_id.clone()).or_default() += 1;
 
-            // guard to avoid deadlock in situation when we are not sending or expecting any message
-            // but can proceed without waiting for anything
-            if self.can_proceed(&state) {
-                idx += 1;
-                continue;
+            circuit_nodes.extend(exp.into_ordered());
+            outputs.push((Ok(output_id), recipient));
+        }
+        for node in circuit_nodes.iter() {
+            for operand_id in node.operand_ids() {
+                *self.uses.entry(operand_id.clone()).or_default() += 1;
             }
-
-            let event = match self.party_events.recv().await {
-                Some(e) => e,
-                None => {
-                    log::debug!("party channel closed");
-                    panic!("abort");
-                }
-            };
-
-            self.handle_event(event, &calculator);
         }
 
-        // we have final share lets open it now
-        let last_node_id = circuit_nodes
-            .last()
-            .expect("at least one should exist")
-            .cir_id();
-
-        // send all proofs
-        for (cir_id, proof) in self.my_proofs.iter() {
-            self.party_commands
-                .send(NodeCommands::ProofFor(cir_id.clone(), proof.clone()))
-                .expect("Send should succeed");
+        // nodes of all outputs are evaluated together, so independent ones share rounds
+        for layer in into_layers(circuit_nodes) {
+            self.evaluate_layer(&calculator, &layer).await?;
         }
 
-        // wait for all proofs
-        self.wait_for_proofs(&calculator).await;
-
-        // check proofs
-        let to_check = self.check_proofs();
-
-        // wait for all nodes to conclude their checks
-        self.wait_for_others(to_check, &calculator).await;
+        // authenticate private outputs with recipients' keys before checking openings
+        let private_ids = outputs
+            .iter()
+            .filter_map(|(output, recipient)| match (output, recipient) {
+                (Ok(output_id), Some(recipient)) => Some((output_id.clone(), *recipient)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let authenticated = self
+            .authenticate_private_outputs(&calculator, &private_ids)
+            .await?;
+        let private_outputs = private_ids
+            .into_iter()
+            .zip(authenticated)
+            .map(|((output_id, recipient), authenticated)| (output_id, recipient, authenticated))
+            .collect();
+
+        // check all values opened so far before revealing anything
+        self.check_macs(&calculator, mac_check_id("circuit"))
+            .await?;
+
+        // we have final shares lets open them now
+        let public_ids = outputs
+            .iter()
+            .filter_map(|(output, recipient)| match (output, recipient) {
+                (Ok(output_id), None) => Some(output_id.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut opened = self.evaluate_outputs(&public_ids, &calculator).await?;
+        opened.extend(
+            self.evaluate_private_outputs(private_outputs, &calculator)
+                .await?,
+        );
 
-        self.evaluate_last(last_node_id, &calculator).await
+        Ok(outputs
+            .into_iter()
+            .map(|(output, recipient)| match (output, recipient) {
+                (_, Some(recipient)) if recipient != self.id => None,
+                (Ok(cir_id), _) => Some(opened.remove(&cir_id).expect("we opened all outputs")),
+                (Err(constant), _) => Some(vec![constant; self.batch_size]),
+            })
+            .collect())
     }
 
-    async fn evaluate_last(&mut self, last_id: CirId, calculator: &Calculator) -> u64 {
-        let evaluated = self
-            .evaluated
-            .remove(&last_id)
-            .expect("we finished the evaluation");
-
-        self.original_shares
-            .insert(last_id.clone(), evaluated.clone());
+    /// Asks for beaver used to authenticate private output,
+    /// recipient secret shares its random key as its own input
+    fn announce_private_output(
+        &mut self,
+        output_id: &CirId,
+        recipient: NodeId,
+    ) -> Result<(), ProtocolError> {
+        self.send(NodeCommands::NeedBeaver(sub_id(
+            output_id,
+            &"key-mul".to_string(),
+        )))?;
+
+        if recipient == self.id {
+            let key_id = sub_id(output_id, &"key".to_string());
+            let keys = (0..self.batch_size)
+                .map(|_| Elem::random(rand::thread_rng()))
+                .collect();
+            self.variables.insert(key_id.clone(), keys);
+            self.send(NodeCommands::OpenSelfInput(key_id))?;
+        }
 
-        self.party_commands
-            .send(NodeCommands::OpenShare(evaluated, last_id.clone()))
-            .expect("should succeed");
+        Ok(())
+    }
 
-        loop {
-            let event = match self.party_events.recv().await {
-                Some(e) => e,
-                None => {
-                    log::debug!("party channel closed");
-                    panic!("abort");
-                }
-            };
+    /// Returns [key * x] for each private output x, where key is known only to its recipient.
+    /// Recipient checks that opened values satisfy it, so no node can change its share of x
+    /// without being caught. All products are computed in one round.
+    async fn authenticate_private_outputs(
+        &mut self,
+        calculator: &Calculator,
+        private_ids: &[(CirId, NodeId)],
+    ) -> Result<Vec<Shares>, ProtocolError> {
+        let key_ids = private_ids
+            .iter()
+            .map(|(output_id, _)| sub_id(output_id, &"key".to_string()))
+            .collect::<Vec<_>>();
+        self.wait_until(calculator, Phase::Inputs, |node| {
+            key_ids
+                .iter()
+                .all(|key_id| node.evaluated.contains_key(key_id))
+        })
+        .await?;
+
+        let mut products = vec![];
+        for ((output_id, _), key_id) in private_ids.iter().zip(key_ids) {
+            let key = self.evaluated.remove(&key_id).expect("checked");
+            let x = self
+                .evaluated
+                .get(output_id)
+                .expect("we finished the evaluation")
+                .clone();
+            products.push((sub_id(output_id, &"key-mul".to_string()), key, x));
+        }
 
-            self.handle_event(event, &calculator);
+        self.multiply_all(calculator, products).await
+    }
 
-            if self.fully_open.contains_key(&last_id) {
-                break;
+    /// Sends shares of x and [key * x] of private outputs only to their recipients,
+    /// which open and check them
+    async fn evaluate_private_outputs(
+        &mut self,
+        private_outputs: Vec<(CirId, NodeId, Shares)>,
+        calculator: &Calculator,
+    ) -> Result<HashMap<CirId, Elems>, ProtocolError> {
+        let mut ours = vec![];
+
+        for (output_id, recipient, authenticated) in private_outputs {
+            let evaluated = self
+                .evaluated
+                .remove(&output_id)
+                .expect("we finished the evaluation");
+
+            // mac shares are not needed, recipient checks values against its key instead
+            let private_id = sub_id(&output_id, &"private".to_string());
+            self.send(NodeCommands::OpenShareTo(
+                zip_rows(&evaluated, &authenticated, |x, y| (x.0, y.0)),
+                private_id.clone(),
+                recipient,
+            ))?;
+
+            if recipient == self.id {
+                ours.push((output_id, private_id));
             }
         }
 
-        let shares = self.fully_open.remove(&last_id).expect("checked");
-
-        let ev_elem = sum_elems(&shares.into_iter().map(|(e, _)| e).collect());
-
-        let n = <u64>::from_str_radix(
-            format!("{:?}", ev_elem.to_repr())
-                .strip_prefix("0x")
-                .unwrap(),
-            16,
-        )
-        .unwrap();
-        let ev_x = calculator.generate_commitment_share(
-            ev_elem.clone(),
-            self.original_shares.remove(&last_id).expect("checked"),
-        );
-
-        let (ev_hash, ev_salt) = Calculator::generate_commitment(&ev_x);
+        self.wait_until(calculator, Phase::Outputs, |node| {
+            ours.iter()
+                .all(|(_, private_id)| node.fully_open.contains_key(private_id))
+        })
+        .await?;
+
+        let mut opened = HashMap::new();
+        for (output_id, private_id) in ours {
+            let shares = self.fully_open.remove(&private_id).expect("checked");
+            let x = open_rows(&shares, self.batch_size);
+            let authenticated = (0..self.batch_size)
+                .map(|row| sum_elems(&shares.iter().map(|s| s[row].1).collect()))
+                .collect::<Vec<_>>();
+
+            let key = self
+                .variables
+                .get(&sub_id(&output_id, &"key".to_string()))
+                .expect("we generated it");
+            let valid = (0..self.batch_size).all(|row| authenticated[row] == key[row] * x[row]);
+            if !valid {
+                let error = ProtocolError::MacCheckFailed(private_id.clone());
+                return self.abort_check(&private_id, error);
+            }
 
-        let ev_proof = (ev_hash, ev_x.clone(), ev_salt);
+            opened.insert(output_id, x);
+        }
 
-        self.my_proofs.clear();
-        self.my_proofs.insert(last_id.clone(), ev_proof);
+        Ok(opened)
+    }
 
-        self.party_commands
-            .send(NodeCommands::CommitmentFor(last_id.clone(), ev_hash))
-            .expect("send should succeed");
+    /// Asks dealer for everything needed to evaluate expression
+    fn announce_needs(&self, exp: &DecoratedExpression) -> Result<(), ProtocolError> {
+        // announce need for beaver for this circuit nodes
+        for mul_id in exp.mul_ids() {
+            self.send(NodeCommands::NeedBeaver(mul_id))?;
+        }
 
-        loop {
-            let event = match self.party_events.recv().await {
-                Some(e) => e,
-                None => {
-                    log::debug!("party channel closed");
-                    panic!("abort");
-                }
-            };
+        // announce need for random bits for comparisons
+        for (cir_id, n_bits) in exp.bit_mask_ids() {
+            self.send(NodeCommands::NeedBitMask(cir_id, n_bits))?;
+        }
 
-            self.handle_event(event, &calculator);
+        // announce need for random values for bit checks
+        for cir_id in exp.random_ids() {
+            self.send(NodeCommands::NeedRandom(cir_id))?;
+        }
 
-            if self.commitments.contains_key(&last_id) {
-                break;
-            }
+        // announce need for masks for truncations of fixed point products
+        for (cir_id, n_bits) in exp.truncation_mask_ids() {
+            self.send(NodeCommands::NeedTruncationMask(cir_id, n_bits))?;
         }
 
-        for (cir_id, proof) in self.my_proofs.iter() {
-            self.party_commands
-                .send(NodeCommands::ProofFor(cir_id.clone(), proof.clone()))
-                .expect("Send should succeed");
+        // announce to dealer our variable
+        for (var_id, _) in exp.self_var_ids(Some(self.id)) {
+            self.send(NodeCommands::OpenSelfInput(var_id))?;
         }
 
-        // wait for all proofs
-        self.wait_for_proofs(&calculator).await;
+        Ok(())
+    }
 
-        // check proofs
-        self.check_proofs();
+    /// Opens evaluated outputs and checks their MACs
+    async fn evaluate_outputs(
+        &mut self,
+        output_ids: &[CirId],
+        calculator: &Calculator,
+    ) -> Result<HashMap<CirId, Elems>, ProtocolError> {
+        let openings = output_ids
+            .iter()
+            .map(|output_id| {
+                let evaluated = self
+                    .evaluated
+                    .remove(output_id)
+                    .expect("we finished the evaluation");
+                (output_id.clone(), evaluated)
+            })
+            .collect();
+        let opened = self.open_all(calculator, Phase::Outputs, openings).await?;
 
-        // wait for all nodes to conclude their checks
-        self.wait_for_others([last_id.clone()].into_iter().collect(), &calculator).await;
+        self.check_macs(calculator, mac_check_id("outputs")).await?;
 
         // yay
         if self.id == 0 {
-            println!("Got {:?}", n);
+            println!("Got {:?}", opened);
         }
 
-        n
+        Ok(output_ids.iter().cloned().zip(opened).collect())
     }
 }
+
+/// Returns id of mac check of values opened in given phase of the session
+fn mac_check_id(phase: &str) -> CirId {
+    sub_id(&"mac-check".to_string(), &phase.to_string())
+}
+
+/// Sums shares of each of `rows` rows opened by all nodes
+fn open_rows(shares: &[Shares], rows: usize) -> Elems {
+    (0..rows)
+        .map(|row| sum_elems(&shares.iter().map(|s| s[row].0).collect()))
+        .collect()
+}
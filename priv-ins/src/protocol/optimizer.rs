@@ -0,0 +1,440 @@
+use crate::crypto::shares::{self, Elem};
+use crate::protocol::{
+    expression::{fold_power, is_bit, DecoratedExpression},
+    CirId, Provider,
+};
+use ff::Field;
+use std::{collections::HashMap, fmt};
+
+/// Cost of evaluating expression before and after optimization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub triples_before: usize,
+    pub triples_after: usize,
+    pub rounds_before: usize,
+    pub rounds_after: usize,
+}
+
+impl Report {
+    pub fn triples_saved(&self) -> usize {
+        self.triples_before.saturating_sub(self.triples_after)
+    }
+
+    pub fn rounds_saved(&self) -> usize {
+        self.rounds_before.saturating_sub(self.rounds_after)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "saved {} of {} beaver triples and {} of {} rounds",
+            self.triples_saved(),
+            self.triples_before,
+            self.rounds_saved(),
+            self.rounds_before
+        )
+    }
+}
+
+/// Rewrites expression into equivalent one which needs no more beaver triples and rounds.
+/// Constants are folded, identities x * 1, x + 0 and x * 0 removed, chains of constant
+/// additions and multiplications merged, constant factors distributed over constant addends
+/// and products rebuilt as balanced trees of minimal multiplicative depth.
+/// Subexpressions multiplied by 0 are dropped together with checks of select conditions in them.
+/// Nodes are given new ids from `id_provider`, so every party has to optimize the same way.
+pub fn optimize(
+    expr: DecoratedExpression,
+    id_provider: &mut Provider,
+) -> Result<(DecoratedExpression, Report), String> {
+    let triples_before = expr.mul_ids().len();
+    let rounds_before = expr.rounds();
+
+    let mut optimizer = Optimizer {
+        id_provider,
+        bound: HashMap::new(),
+        rounds: HashMap::new(),
+    };
+    let optimized = optimizer.optimize(expr)?;
+
+    let report = Report {
+        triples_before,
+        triples_after: optimized.mul_ids().len(),
+        rounds_before,
+        rounds_after: optimized.rounds(),
+    };
+
+    Ok((optimized, report))
+}
+
+struct Optimizer<'a> {
+    id_provider: &'a mut Provider,
+    /// optimized values of `Let`s by their original ids, either constants or references
+    bound: HashMap<CirId, DecoratedExpression>,
+    /// rounds of values of kept `Let`s by their new ids
+    rounds: HashMap<CirId, usize>,
+}
+
+impl Optimizer<'_> {
+    fn optimize(&mut self, expr: DecoratedExpression) -> Result<DecoratedExpression, String> {
+        Ok(match expr {
+            DecoratedExpression::AddConstant(s, e, _) => {
+                let e = self.optimize(*e)?;
+                self.add_constant(s, e)
+            }
+            DecoratedExpression::Add(e1, e2, _) => {
+                let (e1, e2) = (self.optimize(*e1)?, self.optimize(*e2)?);
+                self.add(e1, e2)
+            }
+            DecoratedExpression::Sub(e1, e2, _) => {
+                let (e1, e2) = (self.optimize(*e1)?, self.optimize(*e2)?);
+                self.sub(e1, e2)
+            }
+            DecoratedExpression::Neg(e, _) => {
+                let e = self.optimize(*e)?;
+                self.neg(e)
+            }
+            DecoratedExpression::LessThanZero(e, _) => match self.optimize(*e)? {
+                DecoratedExpression::Constant(s, _) => {
+                    self.constant(Elem::from(shares::is_negative(&s) as u64))
+                }
+                e => DecoratedExpression::LessThanZero(Box::new(e), self.id_provider.next()),
+            },
+            DecoratedExpression::EqualsZero(e, _) => match self.optimize(*e)? {
+                DecoratedExpression::Constant(s, _) => {
+                    self.constant(Elem::from(bool::from(s.is_zero()) as u64))
+                }
+                e => DecoratedExpression::EqualsZero(Box::new(e), self.id_provider.next()),
+            },
+            DecoratedExpression::AssertBit(e, _) => match self.optimize(*e)? {
+                DecoratedExpression::Constant(s, id) => {
+                    if !is_bit(&DecoratedExpression::Constant(s, id.clone())) {
+                        return Err("condition of select is neither 0 nor 1".to_string());
+                    }
+                    DecoratedExpression::Constant(s, id)
+                }
+                e if is_bit(&e) => e,
+                e => DecoratedExpression::AssertBit(Box::new(e), self.id_provider.next()),
+            },
+            DecoratedExpression::Truncate(e, n_bits, _) => match self.optimize(*e)? {
+                DecoratedExpression::Constant(s, _) => self.constant(shares::truncate(&s, n_bits)),
+                e if n_bits == 0 => e,
+                e => DecoratedExpression::Truncate(Box::new(e), n_bits, self.id_provider.next()),
+            },
+            DecoratedExpression::Pow(e, exponent, n_bits, _) => match self.optimize(*e)? {
+                DecoratedExpression::Constant(s, _) => {
+                    self.constant(fold_power(s, exponent, n_bits))
+                }
+                e => {
+                    DecoratedExpression::Pow(Box::new(e), exponent, n_bits, self.id_provider.next())
+                }
+            },
+            DecoratedExpression::Mul(e1, e2, _) => {
+                let (e1, e2) = (self.optimize(*e1)?, self.optimize(*e2)?);
+                self.mul(e1, e2)
+            }
+            DecoratedExpression::MulConstant(s, e, _) => {
+                let e = self.optimize(*e)?;
+                self.mul_constant(s, e)
+            }
+            DecoratedExpression::DivConstant(s, e, _) => match self.optimize(*e)? {
+                DecoratedExpression::Constant(s2, _) => {
+                    let inverse =
+                        Option::<Elem>::from(s.invert()).ok_or("division by zero".to_string())?;
+                    self.constant(s2 * inverse)
+                }
+                e if s == Elem::one() => e,
+                e => DecoratedExpression::DivConstant(s, Box::new(e), self.id_provider.next()),
+            },
+            DecoratedExpression::Var(node_id, var_id, cir_id) => {
+                DecoratedExpression::Var(node_id, var_id, cir_id)
+            }
+            DecoratedExpression::Constant(s, cir_id) => DecoratedExpression::Constant(s, cir_id),
+            DecoratedExpression::Let(value, body) => self.bind(*value, *body)?,
+            DecoratedExpression::Ref(cir_id) => match self.bound.get(&cir_id) {
+                Some(DecoratedExpression::Constant(s, id)) => {
+                    DecoratedExpression::Constant(*s, id.clone())
+                }
+                Some(DecoratedExpression::Ref(id)) => DecoratedExpression::Ref(id.clone()),
+                _ => return Err(format!("reference to unbound node {}", cir_id)),
+            },
+        })
+    }
+
+    /// Optimizes value of `Let`, which is substituted into body if it became a constant
+    /// or a reference and dropped if body does not use it anymore
+    fn bind(
+        &mut self,
+        value: DecoratedExpression,
+        body: DecoratedExpression,
+    ) -> Result<DecoratedExpression, String> {
+        let old_id = value.cir_id();
+        let value = self.optimize(value)?;
+
+        if let DecoratedExpression::Constant(_, _) | DecoratedExpression::Ref(_) = value {
+            self.bound.insert(old_id, value);
+            return self.optimize(body);
+        }
+
+        let id = value.cir_id();
+        let rounds = value.rounds_with(&mut self.rounds.clone());
+        self.rounds.insert(id.clone(), rounds);
+        self.bound
+            .insert(old_id, DecoratedExpression::Ref(id.clone()));
+
+        let body = self.optimize(body)?;
+        if references(&body, &id) {
+            Ok(DecoratedExpression::Let(Box::new(value), Box::new(body)))
+        } else {
+            Ok(body)
+        }
+    }
+
+    fn constant(&mut self, s: Elem) -> DecoratedExpression {
+        DecoratedExpression::Constant(s, self.id_provider.next())
+    }
+
+    fn add_constant(&mut self, s: Elem, expr: DecoratedExpression) -> DecoratedExpression {
+        match expr {
+            x if s.is_zero().into() => x,
+            DecoratedExpression::Constant(s2, _) => self.constant(s + s2),
+            DecoratedExpression::AddConstant(s2, x, _) => self.add_constant(s + s2, *x),
+            x => DecoratedExpression::AddConstant(s, Box::new(x), self.id_provider.next()),
+        }
+    }
+
+    fn mul_constant(&mut self, s: Elem, expr: DecoratedExpression) -> DecoratedExpression {
+        match expr {
+            _ if s.is_zero().into() => self.constant(Elem::zero()),
+            x if s == Elem::one() => x,
+            DecoratedExpression::Constant(s2, _) => self.constant(s * s2),
+            DecoratedExpression::MulConstant(s2, x, _) => self.mul_constant(s * s2, *x),
+            DecoratedExpression::Neg(x, _) => self.mul_constant(-s, *x),
+            // s * (x + c) = s * x + s * c
+            DecoratedExpression::AddConstant(s2, x, _) => {
+                let x = self.mul_constant(s, *x);
+                self.add_constant(s * s2, x)
+            }
+            x => DecoratedExpression::MulConstant(s, Box::new(x), self.id_provider.next()),
+        }
+    }
+
+    fn neg(&mut self, expr: DecoratedExpression) -> DecoratedExpression {
+        match expr {
+            DecoratedExpression::Constant(s, _) => self.constant(-s),
+            DecoratedExpression::Neg(x, _) => *x,
+            DecoratedExpression::MulConstant(s, x, _) => self.mul_constant(-s, *x),
+            x => DecoratedExpression::Neg(Box::new(x), self.id_provider.next()),
+        }
+    }
+
+    /// constants of addends are moved out so that they can be merged
+    fn add(
+        &mut self,
+        left: DecoratedExpression,
+        right: DecoratedExpression,
+    ) -> DecoratedExpression {
+        match (left, right) {
+            (DecoratedExpression::Constant(s, _), x) | (x, DecoratedExpression::Constant(s, _)) => {
+                self.add_constant(s, x)
+            }
+            (DecoratedExpression::AddConstant(s, x, _), y)
+            | (y, DecoratedExpression::AddConstant(s, x, _)) => {
+                let sum = self.add(*x, y);
+                self.add_constant(s, sum)
+            }
+            (DecoratedExpression::Neg(x, _), y) | (y, DecoratedExpression::Neg(x, _)) => {
+                self.sub(y, *x)
+            }
+            (x, y) => DecoratedExpression::Add(Box::new(x), Box::new(y), self.id_provider.next()),
+        }
+    }
+
+    fn sub(
+        &mut self,
+        left: DecoratedExpression,
+        right: DecoratedExpression,
+    ) -> DecoratedExpression {
+        match (left, right) {
+            (x, DecoratedExpression::Constant(s, _)) => self.add_constant(-s, x),
+            (DecoratedExpression::Constant(s, _), y) => {
+                let negated = self.neg(y);
+                self.add_constant(s, negated)
+            }
+            (DecoratedExpression::AddConstant(s, x, _), y) => {
+                let difference = self.sub(*x, y);
+                self.add_constant(s, difference)
+            }
+            (x, DecoratedExpression::AddConstant(s, y, _)) => {
+                let difference = self.sub(x, *y);
+                self.add_constant(-s, difference)
+            }
+            (x, DecoratedExpression::Neg(y, _)) => self.add(x, *y),
+            (x, y) => DecoratedExpression::Sub(Box::new(x), Box::new(y), self.id_provider.next()),
+        }
+    }
+
+    /// Flattens chain of multiplications with constant factors pulled out
+    /// and multiplies the factors in order of least rounds
+    fn mul(
+        &mut self,
+        left: DecoratedExpression,
+        right: DecoratedExpression,
+    ) -> DecoratedExpression {
+        let mut coefficient = Elem::one();
+        let mut factors = vec![];
+        collect_factors(left, &mut coefficient, &mut factors);
+        collect_factors(right, &mut coefficient, &mut factors);
+
+        if bool::from(coefficient.is_zero()) || factors.is_empty() {
+            return self.constant(coefficient);
+        }
+
+        let mut factors = factors
+            .into_iter()
+            .map(|e| (e.rounds_with(&mut self.rounds.clone()), e))
+            .collect::<Vec<_>>();
+        while factors.len() > 1 {
+            factors.sort_by_key(|(rounds, _)| std::cmp::Reverse(*rounds));
+            let (a_rounds, a) = factors.pop().expect("at least two");
+            let (b_rounds, b) = factors.pop().expect("at least two");
+            let product =
+                DecoratedExpression::Mul(Box::new(a), Box::new(b), self.id_provider.next());
+            factors.push((a_rounds.max(b_rounds) + 1, product));
+        }
+
+        let (_, product) = factors.pop().expect("at least one");
+        self.mul_constant(coefficient, product)
+    }
+}
+
+fn collect_factors(
+    expr: DecoratedExpression,
+    coefficient: &mut Elem,
+    factors: &mut Vec<DecoratedExpression>,
+) {
+    match expr {
+        DecoratedExpression::Mul(e1, e2, _) => {
+            collect_factors(*e1, coefficient, factors);
+            collect_factors(*e2, coefficient, factors);
+        }
+        DecoratedExpression::MulConstant(s, e, _) => {
+            *coefficient *= s;
+            collect_factors(*e, coefficient, factors);
+        }
+        DecoratedExpression::Neg(e, _) => {
+            *coefficient = -*coefficient;
+            collect_factors(*e, coefficient, factors);
+        }
+        DecoratedExpression::Constant(s, _) => *coefficient *= s,
+        e => factors.push(e),
+    }
+}
+
+fn references(expr: &DecoratedExpression, cir_id: &CirId) -> bool {
+    match expr {
+        DecoratedExpression::Ref(id) => id == cir_id,
+        e => e.children().into_iter().any(|e| references(e, cir_id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expressions::parser::parse;
+    use crate::protocol::expression::decorate_expression;
+
+    fn optimized(formula: &str) -> Result<(DecoratedExpression, Report), String> {
+        let variables = ["x", "y", "z", "w"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.to_string(), i as u64))
+            .collect();
+        let mut provider = Provider::new(0, variables);
+        let (expr, _) = decorate_expression(parse(formula).unwrap(), &mut provider)?;
+
+        optimize(expr, &mut provider)
+    }
+
+    fn constant(expr: &DecoratedExpression) -> Option<Elem> {
+        match expr {
+            DecoratedExpression::Constant(s, _) => Some(*s),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn removes_identities() {
+        let (expr, _) = optimized("x * 1 + 0").unwrap();
+        assert!(matches!(expr, DecoratedExpression::Var(..)));
+
+        let (expr, report) = optimized("x * 0 * y + z").unwrap();
+        assert!(matches!(expr, DecoratedExpression::Var(..)));
+        assert_eq!(1, report.triples_saved());
+
+        let (expr, _) = optimized("let a = y * 0 in a + 2 * 3").unwrap();
+        assert_eq!(Some(Elem::from(6)), constant(&expr));
+    }
+
+    #[test]
+    fn merges_and_distributes_constants() {
+        let (expr, _) = optimized("((x + 1) * 2 - 4) * 3 + 5").unwrap();
+        match expr {
+            DecoratedExpression::AddConstant(s, e, _) => {
+                assert_eq!(-Elem::one(), s);
+                match *e {
+                    DecoratedExpression::MulConstant(s, e, _) => {
+                        assert_eq!(Elem::from(6), s);
+                        assert!(matches!(*e, DecoratedExpression::Var(..)));
+                    }
+                    _ => panic!("expected multiplication by constant"),
+                }
+            }
+            _ => panic!("expected addition of constant"),
+        }
+
+        let (expr, _) = optimized("-(-x) / 1 - (1 - y) + 1").unwrap();
+        assert!(matches!(expr, DecoratedExpression::Add(..)));
+    }
+
+    #[test]
+    fn rebalances_products() {
+        let (expr, report) = optimized("x * 2 * y * (z * -3) * w * x * y").unwrap();
+        assert!(matches!(expr, DecoratedExpression::MulConstant(..)));
+        assert_eq!(5, report.triples_after);
+        assert_eq!(6, report.rounds_before);
+        assert_eq!(4, report.rounds_after);
+        assert_eq!(2, report.rounds_saved());
+
+        // shared value with more rounds is multiplied last
+        let (expr, report) = optimized("let a = x * y in a * z * w").unwrap();
+        assert_eq!(0, report.triples_saved());
+        assert_eq!(1, report.rounds_saved());
+        assert_eq!(report.rounds_after, expr.rounds());
+    }
+
+    #[test]
+    fn removes_checks_of_constant_conditions() {
+        let (_, report) = optimized("if x * 0 + 1 then x else y").unwrap();
+        assert_eq!(3, report.triples_before);
+        assert_eq!(0, report.triples_after);
+
+        assert!(optimized("if x * 0 + 2 then x else y").is_err());
+    }
+
+    #[test]
+    fn reports_savings() {
+        let report = Report {
+            triples_before: 5,
+            triples_after: 3,
+            rounds_before: 4,
+            rounds_after: 4,
+        };
+
+        assert_eq!(
+            "saved 2 of 5 beaver triples and 0 of 4 rounds",
+            report.to_string()
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use crate::crypto::shares::{self, Elem};
+use crate::expressions::decimal::Decimal;
+use crate::ff::PrimeField;
+use num_bigint::BigUint;
+use std::convert::TryFrom;
+
+/// Type that the revealed result of computation is decoded into
+pub trait Output: Sized {
+    /// Decodes opened element with given number of fractional bits,
+    /// elements above (p - 1) / 2 are negative numbers
+    fn decode(elem: &Elem, fractional_bits: u32) -> Result<Self, String>;
+}
+
+fn integer(elem: &Elem, fractional_bits: u32) -> Result<i128, String> {
+    if fractional_bits != 0 {
+        return Err("result is a decimal number".to_string());
+    }
+
+    shares::elem_to_i128(elem).ok_or("result does not fit into i128".to_string())
+}
+
+impl Output for i64 {
+    fn decode(elem: &Elem, fractional_bits: u32) -> Result<Self, String> {
+        i64::try_from(integer(elem, fractional_bits)?)
+            .map_err(|_| "result does not fit into i64".to_string())
+    }
+}
+
+impl Output for i128 {
+    fn decode(elem: &Elem, fractional_bits: u32) -> Result<Self, String> {
+        integer(elem, fractional_bits)
+    }
+}
+
+impl Output for BigUint {
+    fn decode(elem: &Elem, fractional_bits: u32) -> Result<Self, String> {
+        if fractional_bits != 0 {
+            return Err("result is a decimal number".to_string());
+        }
+        if shares::is_negative(elem) {
+            return Err("result is negative".to_string());
+        }
+
+        Ok(BigUint::from_bytes_le(&elem.to_repr().0))
+    }
+}
+
+/// Opened element as it is, fixed point values stay encoded
+impl Output for Elem {
+    fn decode(elem: &Elem, _fractional_bits: u32) -> Result<Self, String> {
+        Ok(*elem)
+    }
+}
+
+impl Output for Decimal {
+    fn decode(elem: &Elem, fractional_bits: u32) -> Result<Self, String> {
+        shares::elem_to_i128(elem)
+            .and_then(|value| Decimal::from_fixed(value, fractional_bits))
+            .ok_or("result does not fit into decimal".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_signed_results() {
+        let refund = -Elem::from(120);
+
+        assert_eq!(Ok(-120), i64::decode(&refund, 0));
+        assert_eq!(Ok(-120), i128::decode(&refund, 0));
+        assert!(BigUint::decode(&refund, 0).is_err());
+        assert_eq!(Ok(Decimal::from(-120)), Decimal::decode(&refund, 0));
+        // -1.875 with precision of 2^-6 is rounded to one decimal digit
+        assert_eq!(Ok(Decimal::new(-19, 1)), Decimal::decode(&refund, 6));
+        assert_eq!(Ok(refund), Elem::decode(&refund, 6));
+        assert!(i64::decode(&refund, 6).is_err());
+    }
+
+    #[test]
+    fn decodes_big_results() {
+        let big = shares::pow2(100);
+
+        assert!(i64::decode(&big, 0).is_err());
+        assert_eq!(Ok(1 << 100), i128::decode(&big, 0));
+        assert_eq!(Ok(BigUint::from(1u8) << 100), BigUint::decode(&big, 0));
+
+        let huge = shares::pow2(200);
+        assert!(i128::decode(&huge, 0).is_err());
+        assert!(Decimal::decode(&huge, 0).is_err());
+        assert_eq!(Ok(BigUint::from(1u8) << 200), BigUint::decode(&huge, 0));
+    }
+}
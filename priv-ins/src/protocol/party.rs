@@ -1,5 +1,6 @@
-use crate::crypto::shares::{Commitment, CommitmentProof, Share, Shares};
+use crate::crypto::shares::{self, Commitment, CommitmentProof, Hash, Shares};
 use crate::protocol::{
+    error::{BlameReport, Offence, ProtocolError},
     network::{Msg, Network},
     Alpha, CirId, DealerCommands, DealerEvents, NodeCommands, NodeEvents, NodeId,
 };
@@ -16,7 +17,7 @@ pub struct Party<N: Network + Send> {
     node_commands: Receiver<NodeCommands>,
     node_events: Sender<NodeEvents>,
     network: N,
-    shares_per: HashMap<CirId, Shares>,
+    shares_per: HashMap<CirId, Vec<Shares>>,
     opened_shares: HashMap<NodeId, HashSet<CirId>>,
     commitments_per: HashMap<CirId, Vec<(NodeId, Commitment)>>,
     node_commitments: HashMap<NodeId, HashSet<CirId>>,
@@ -24,7 +25,16 @@ pub struct Party<N: Network + Send> {
     node_proofs: HashMap<NodeId, HashSet<CirId>>,
     status_per: HashMap<CirId, u8>,
     node_statuses: HashMap<NodeId, HashSet<CirId>>,
+    /// digests of shares each node opened to us, compared with other nodes before mac checks
+    share_digests: HashMap<NodeId, HashMap<CirId, Hash>>,
+    digests_per: HashMap<CirId, Vec<(NodeId, Vec<Hash>)>>,
+    node_digests: HashMap<NodeId, HashSet<CirId>>,
+    /// nodes allowed to open each secret shared input
+    input_owners: HashMap<CirId, NodeId>,
+    opened_variables: HashMap<NodeId, HashSet<CirId>>,
     n_parties: u8,
+    /// number of rows evaluated in the session
+    batch_size: usize,
 }
 
 impl<N: Network + Send> Party<N> {
@@ -45,6 +55,7 @@ impl<N: Network + Send> Party<N> {
             node_events,
             network,
             n_parties,
+            batch_size: 1,
             opened_shares: HashMap::new(),
             shares_per: HashMap::new(),
             commitments_per: HashMap::new(),
@@ -53,152 +64,294 @@ impl<N: Network + Send> Party<N> {
             node_proofs: HashMap::new(),
             status_per: HashMap::new(),
             node_statuses: HashMap::new(),
+            share_digests: HashMap::new(),
+            digests_per: HashMap::new(),
+            node_digests: HashMap::new(),
+            input_owners: HashMap::new(),
+            opened_variables: HashMap::new(),
         }
     }
-    /// collects share from given node for given circuit node.
+    /// Sets number of rows that each opened value must have
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Sets owners of inputs, only they may open their masked values
+    pub fn with_input_owners(mut self, input_owners: HashMap<CirId, NodeId>) -> Self {
+        self.input_owners = input_owners;
+        self
+    }
+
+    fn send_event(&self, event: NodeEvents) -> Result<(), ProtocolError> {
+        self.node_events
+            .send(event)
+            .map_err(|_| ProtocolError::ChannelClosed("node events".to_string()))
+    }
+
+    fn send_to_dealer(&self, command: DealerCommands) -> Result<(), ProtocolError> {
+        self.dealer
+            .0
+            .send((self.id, command))
+            .map_err(|_| ProtocolError::ChannelClosed("dealer commands".to_string()))
+    }
+
+    /// Checks that message comes from one of the parties and has values of all rows
+    fn validate(&self, from: NodeId, msg: &Msg) -> Result<(), ProtocolError> {
+        if from >= self.n_parties as NodeId {
+            return Err(ProtocolError::ProtocolViolation(
+                from,
+                "unknown node".to_string(),
+            ));
+        }
+
+        let (cid, rows) = match msg {
+            Msg::OpenShare(cid, shares) => (cid, shares.len()),
+            Msg::OpenVariable(cid, elems) => (cid, elems.len()),
+            Msg::Digests(cid, digests) if digests.len() != self.n_parties as usize => {
+                return Err(ProtocolError::ProtocolViolation(
+                    from,
+                    format!("sent {} digests for {}", digests.len(), cid),
+                ));
+            }
+            _ => return Ok(()),
+        };
+        if rows != self.batch_size {
+            return Err(ProtocolError::ProtocolViolation(
+                from,
+                format!("sent {} rows of {}", rows, cid),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// collects shares of all rows from given node for given circuit node.
     /// Checks for double sending
     /// If we have all shares returns true
-    fn collect_share(&mut self, from: NodeId, share: Share, cid: CirId) -> bool {
-        let opened_cirs = self.opened_shares.entry(from).or_insert(HashSet::new());
+    fn collect_share(
+        &mut self,
+        from: NodeId,
+        share: Shares,
+        cid: CirId,
+    ) -> Result<bool, ProtocolError> {
+        let opened_cirs = self.opened_shares.entry(from).or_default();
 
         if !opened_cirs.insert(cid.clone()) {
-            log::debug!(
-                "node {} tried to open more than once its share for circuit node {}",
-                from,
-                cid.clone()
-            );
-
-            // Return notready to not trigger twice ready logic.
-            return false;
+            return Err(double_send(from, cid));
         }
 
-        let shares = self.shares_per.entry(cid).or_insert(Shares::new());
+        self.share_digests
+            .entry(from)
+            .or_default()
+            .insert(cid.clone(), shares::hash_shares(&share));
+        let shares = self.shares_per.entry(cid).or_default();
         shares.push(share);
 
-        shares.len() == self.n_parties as usize
+        Ok(shares.len() == self.n_parties as usize)
+    }
+
+    /// checks that masked input is opened once and by its owner
+    fn collect_variable(&mut self, from: NodeId, cid: CirId) -> Result<(), ProtocolError> {
+        if self.input_owners.get(&cid) != Some(&from) {
+            return Err(ProtocolError::ProtocolViolation(
+                from,
+                format!("opened {} it does not own", cid),
+            ));
+        }
+
+        let opened = self.opened_variables.entry(from).or_default();
+        if !opened.insert(cid.clone()) {
+            return Err(double_send(from, cid));
+        }
+
+        Ok(())
     }
 
     /// similar to collect_share but collects commitments
-    fn collect_commitment(&mut self, from: NodeId, comm: Commitment, cid: CirId) -> bool {
-        let commited_to = self.node_commitments.entry(from).or_insert(HashSet::new());
+    fn collect_commitment(
+        &mut self,
+        from: NodeId,
+        comm: Commitment,
+        cid: CirId,
+    ) -> Result<bool, ProtocolError> {
+        let commited_to = self.node_commitments.entry(from).or_default();
 
         if !commited_to.insert(cid.clone()) {
-            log::debug!(
-                "node {} tried to submit more than once its commitment for circuit node {}",
-                from,
-                cid.clone()
-            );
-
-            // Return notready to not trigger twice ready logic.
-            return false;
+            return Err(double_send(from, cid));
         }
 
-        let comms = self.commitments_per.entry(cid).or_insert(Vec::new());
+        let comms = self.commitments_per.entry(cid).or_default();
         comms.push((from, comm));
 
-        comms.len() == self.n_parties as usize
+        Ok(comms.len() == self.n_parties as usize)
     }
 
-    fn collect_proof(&mut self, from: NodeId, proof: CommitmentProof, cid: CirId) -> bool {
-        let proofs = self.node_proofs.entry(from).or_insert(HashSet::new());
+    fn collect_proof(
+        &mut self,
+        from: NodeId,
+        proof: CommitmentProof,
+        cid: CirId,
+    ) -> Result<bool, ProtocolError> {
+        let proofs = self.node_proofs.entry(from).or_default();
 
         if !proofs.insert(cid.clone()) {
-            log::debug!(
-                "node {} tried to submit more than once its proof for circuit node {}",
-                from,
-                cid.clone()
-            );
-
-            // Return notready to not trigger twice ready logic.
-            return false;
+            return Err(double_send(from, cid));
         }
 
-        let ps = self.proofs_per.entry(cid).or_insert(Vec::new());
+        let ps = self.proofs_per.entry(cid).or_default();
         ps.push((from, proof));
 
-        ps.len() == self.n_parties as usize
+        Ok(ps.len() == self.n_parties as usize)
     }
 
-    fn collect_status(&mut self, from: NodeId, valid: bool, cid: CirId) -> bool {
-        let statuses = self.node_statuses.entry(from).or_insert(HashSet::new());
+    fn collect_status(
+        &mut self,
+        from: NodeId,
+        valid: bool,
+        cid: CirId,
+    ) -> Result<bool, ProtocolError> {
+        let statuses = self.node_statuses.entry(from).or_default();
 
         if !statuses.insert(cid.clone()) {
-            log::debug!(
-                "node {} tried to submit more than once its status for circuit node {}",
-                from,
-                cid.clone()
-            );
-
-            // Return notready to not trigger twice ready logic.
-            return false;
+            return Err(double_send(from, cid));
         }
 
         if !valid {
-            return true;
+            return Ok(true);
         }
 
         let count = self.status_per.entry(cid).or_insert(0);
         *count += 1;
 
-        *count == self.n_parties
+        Ok(*count == self.n_parties)
     }
 
-    fn handle_network_msg(&mut self, from: NodeId, msg: Msg) {
+    /// similar to collect_share but collects digests of shares other nodes received
+    fn collect_digests(
+        &mut self,
+        from: NodeId,
+        digests: Vec<Hash>,
+        cid: CirId,
+    ) -> Result<bool, ProtocolError> {
+        let compared = self.node_digests.entry(from).or_default();
+
+        if !compared.insert(cid.clone()) {
+            return Err(double_send(from, cid));
+        }
+
+        let ds = self.digests_per.entry(cid).or_default();
+        ds.push((from, digests));
+
+        Ok(ds.len() == self.n_parties as usize)
+    }
+
+    /// Digests of shares of given cir_ids that each node opened to us, removes them as they
+    /// are only compared once
+    fn digests_of(&mut self, cir_ids: &[CirId]) -> Vec<Hash> {
+        (0..self.n_parties as NodeId)
+            .map(|node_id| {
+                let received = self.share_digests.entry(node_id).or_default();
+                let bytes = cir_ids
+                    .iter()
+                    .flat_map(|cid| received.remove(cid).unwrap_or_default())
+                    .collect::<Vec<_>>();
+                shares::hash(&bytes)
+            })
+            .collect()
+    }
+
+    /// Lists nodes that did not deliver their messages for cir_ids already sent by others
+    fn missing(&self) -> Vec<(NodeId, CirId)> {
+        let collecting = [
+            (
+                self.shares_per.keys().collect::<Vec<_>>(),
+                &self.opened_shares,
+            ),
+            (
+                self.commitments_per.keys().collect(),
+                &self.node_commitments,
+            ),
+            (self.proofs_per.keys().collect(), &self.node_proofs),
+            (self.status_per.keys().collect(), &self.node_statuses),
+            (self.digests_per.keys().collect(), &self.node_digests),
+        ];
+
+        let mut missing = vec![];
+        for (cids, delivered) in collecting {
+            for cid in cids {
+                // our own messages are only missing when our node is stuck on others
+                let others = (0..self.n_parties as NodeId).filter(|node_id| *node_id != self.id);
+                for node_id in others {
+                    if !delivered
+                        .get(&node_id)
+                        .is_some_and(|cids| cids.contains(cid))
+                    {
+                        missing.push((node_id, cid.clone()));
+                    }
+                }
+            }
+        }
+        missing.sort();
+
+        missing
+    }
+
+    fn handle_network_msg(&mut self, from: NodeId, msg: Msg) -> Result<(), ProtocolError> {
         if self.id == 0 {
             println!("NetworkMsg from {:?} {:?}", from, msg);
         }
+        self.validate(from, &msg)?;
         match msg {
             Msg::OpenShare(cid, share) => {
-                if self.collect_share(from, share, cid.clone()) {
+                if self.collect_share(from, share, cid.clone())? {
                     let collected_shares =
                         self.shares_per.remove(&cid).expect("We have collected it");
-                    self.node_events
-                        .send(NodeEvents::CirReady(cid, collected_shares))
-                        .expect("Send should succeed");
+                    self.send_event(NodeEvents::CirReady(cid, collected_shares))?;
                 }
             }
             Msg::OpenVariable(cid, elem) => {
-                self.node_events
-                    .send(NodeEvents::NodeVariableReady(cid, elem))
-                    .expect("Send should succeed");
+                self.collect_variable(from, cid.clone())?;
+                self.send_event(NodeEvents::NodeVariableReady(cid, elem))?;
             }
             Msg::Commit(cid, comm) => {
-                if self.collect_commitment(from, comm, cid.clone()) {
+                if self.collect_commitment(from, comm, cid.clone())? {
                     let comms = self
                         .commitments_per
                         .remove(&cid)
                         .expect("We have collected it");
-                    self.node_events
-                        .send(NodeEvents::CommitmentsFor(cid, comms))
-                        .expect("Send should succeed");
+                    self.send_event(NodeEvents::CommitmentsFor(cid, comms))?;
                 }
             }
             Msg::Proof(cid, proof) => {
-                if self.collect_proof(from, proof, cid.clone()) {
+                if self.collect_proof(from, proof, cid.clone())? {
                     let proofs = self.proofs_per.remove(&cid).expect("We have collected it");
-                    self.node_events
-                        .send(NodeEvents::ProofsFor(cid, proofs))
-                        .expect("Send should succeed");
+                    self.send_event(NodeEvents::ProofsFor(cid, proofs))?;
                 }
             }
             Msg::ProofInvalid(cid) => {
-                if self.collect_status(from, false, cid.clone()) {
-                    self.node_events
-                        .send(NodeEvents::ProofInvalid(cid))
-                        .expect("Send should succeed");
+                if self.collect_status(from, false, cid.clone())? {
+                    self.send_event(NodeEvents::ProofInvalid(cid))?;
                 }
             }
             Msg::ProofValid(cid) => {
-                if self.collect_status(from, true, cid.clone()) {
-                    self.node_events
-                        .send(NodeEvents::ProofValid(cid))
-                        .expect("Send should succeed");
+                if self.collect_status(from, true, cid.clone())? {
+                    self.send_event(NodeEvents::ProofValid(cid))?;
+                }
+            }
+            Msg::Digests(cid, digests) => {
+                if self.collect_digests(from, digests, cid.clone())? {
+                    let digests = self.digests_per.remove(&cid).expect("We have collected it");
+                    let report = blame_digests(self.id, &cid, &digests);
+                    self.send_event(NodeEvents::DigestsCompared(cid, report))?;
                 }
             }
         }
+
+        Ok(())
     }
 
-    fn handle_node_command(&mut self, cmd: NodeCommands) {
+    fn handle_node_command(&mut self, cmd: NodeCommands) -> Result<(), ProtocolError> {
         if self.id == 0 {
             println!("NodeCmd {:?}", cmd);
         }
@@ -207,26 +360,30 @@ impl<N: Network + Send> Party<N> {
             NodeCommands::OpenShare(share, cir_id) => {
                 self.network.broadcast(Msg::OpenShare(cir_id, share));
             }
+            NodeCommands::OpenShareTo(share, cir_id, node_id) => {
+                self.network
+                    .send_to((node_id, Msg::OpenShare(cir_id, share)));
+            }
             NodeCommands::OpenSelfInput(v_id) => {
-                self.dealer
-                    .0
-                    .send((self.id, DealerCommands::NodeOpenSelfInput(v_id)))
-                    .expect("Send should succeed");
+                self.send_to_dealer(DealerCommands::NodeOpenSelfInput(v_id))?;
             }
             NodeCommands::NeedBeaver(cir_id) => {
-                self.dealer
-                    .0
-                    .send((self.id, DealerCommands::BeaverFor(cir_id)))
-                    .expect("Send should succeed");
+                self.send_to_dealer(DealerCommands::BeaverFor(cir_id))?;
+            }
+            NodeCommands::NeedBitMask(cir_id, n_bits) => {
+                self.send_to_dealer(DealerCommands::BitMaskFor(cir_id, n_bits))?;
+            }
+            NodeCommands::NeedRandom(cir_id) => {
+                self.send_to_dealer(DealerCommands::RandomFor(cir_id))?;
+            }
+            NodeCommands::NeedTruncationMask(cir_id, n_bits) => {
+                self.send_to_dealer(DealerCommands::TruncationMaskFor(cir_id, n_bits))?;
             }
             NodeCommands::OpenSelfShare(s, cir_id) => {
                 self.network.broadcast(Msg::OpenVariable(cir_id, s))
             }
             NodeCommands::NeedAlpha => {
-                self.dealer
-                    .0
-                    .send((self.id, DealerCommands::NeedAlpha))
-                    .expect("Send should succeed");
+                self.send_to_dealer(DealerCommands::NeedAlpha)?;
             }
             NodeCommands::CommitmentFor(cir_id, comm) => {
                 self.network.broadcast(Msg::Commit(cir_id, comm));
@@ -240,64 +397,220 @@ impl<N: Network + Send> Party<N> {
             NodeCommands::ProofInvalid(cir_id) => {
                 self.network.broadcast(Msg::ProofInvalid(cir_id));
             }
+            NodeCommands::ReportMissing => {
+                self.send_event(NodeEvents::Missing(self.missing()))?;
+            }
+            NodeCommands::CompareDigests(cir_id, cir_ids) => {
+                let digests = self.digests_of(&cir_ids);
+                self.network.broadcast(Msg::Digests(cir_id, digests));
+            }
         }
+
+        Ok(())
     }
 
-    fn handle_dealer_event(&mut self, dealer_event: DealerEvents) {
+    fn handle_dealer_event(&mut self, dealer_event: DealerEvents) -> Result<(), ProtocolError> {
         if self.id == 0 {
             println!("DealerEvent::{:?}", dealer_event);
         }
 
         match dealer_event {
             DealerEvents::NodeSelfVariable(var_id, r, r_share) => {
-                self.node_events
-                    .send(NodeEvents::SelfVariableReady(var_id, r, r_share))
-                    .expect("Send should succeed");
+                self.send_event(NodeEvents::SelfVariableReady(var_id, r, r_share))?;
             }
             DealerEvents::NodeVariableShared(var_id, r_share) => {
-                self.node_events
-                    .send(NodeEvents::NodeVariableShareReady(var_id, r_share))
-                    .expect("Send should succeed");
+                self.send_event(NodeEvents::NodeVariableShareReady(var_id, r_share))?;
             }
             DealerEvents::BeaverSharesFor(cir_id, beaver_shares) => {
-                self.node_events
-                    .send(NodeEvents::BeaverFor(cir_id, beaver_shares))
-                    .expect("Send should succeed");
+                self.send_event(NodeEvents::BeaverFor(cir_id, beaver_shares))?;
+            }
+            DealerEvents::BitMaskSharesFor(cir_id, mask) => {
+                self.send_event(NodeEvents::BitMaskFor(cir_id, mask))?;
+            }
+            DealerEvents::RandomShareFor(cir_id, share) => {
+                self.send_event(NodeEvents::RandomFor(cir_id, share))?;
+            }
+            DealerEvents::TruncationMaskSharesFor(cir_id, mask) => {
+                self.send_event(NodeEvents::TruncationMaskFor(cir_id, mask))?;
             }
             DealerEvents::Alpha(alpha) => {
                 self.alpha_channel
                     .send(Alpha(alpha))
-                    .expect("Send should succeed");
+                    .map_err(|_| ProtocolError::ChannelClosed("alpha".to_string()))?;
             }
         }
+
+        Ok(())
     }
 
-    pub async fn run(&mut self) {
+    /// Relays messages until our node finishes, returns error if a message can't be delivered
+    /// or some node breaks the protocol. It waits without deadlines, see `Timeouts`
+    pub async fn run(&mut self) -> Result<(), ProtocolError> {
         loop {
             select! {
-                Some((from, msg)) = self.network.receive() => self.handle_network_msg(from, msg),
-                node_command = self.node_commands.recv() => {
-                    let cmd = match node_command {
-                        None => {
-                            log::debug!("Cmd channel closed");
-                            continue;
-                        },
-                        Some(cmd) => cmd,
-                    };
-                    self.handle_node_command(cmd);
+                Some((from, msg)) = self.network.receive() => self.handle_network_msg(from, msg)?,
+                node_command = self.node_commands.recv() => match node_command {
+                    Some(cmd) => self.handle_node_command(cmd)?,
+                    None => {
+                        log::debug!("Cmd channel closed");
+                        return Ok(());
+                    }
                 },
                 dealer_msg = self.dealer.1.recv() => {
-                    let dealer_event = match dealer_msg {
-                        Some(e) => e,
-                        None => {
-                            log::debug!("dealer channel closed!");
-                            continue;
-                        }
-                    };
-
-                    self.handle_dealer_event(dealer_event);
+                    let dealer_event = dealer_msg
+                        .ok_or_else(|| ProtocolError::ChannelClosed("dealer".to_string()))?;
+
+                    self.handle_dealer_event(dealer_event)?;
                 }
             }
         }
     }
 }
+
+fn double_send(from: NodeId, cid: CirId) -> ProtocolError {
+    ProtocolError::Cheating(vec![(from, Offence::DoubleSend(cid))])
+}
+
+/// Compares reports of other nodes with our own one. A node that reports other digest of our
+/// shares than we sent forged its report, a node whose shares reached us with other digest than
+/// it reports for itself equivocated. Disagreements about third nodes are left to them, as we
+/// can't tell who lies, and to the mac check.
+fn blame_digests(id: NodeId, cid: &CirId, digests: &[(NodeId, Vec<Hash>)]) -> BlameReport {
+    let (_, ours) = digests
+        .iter()
+        .find(|(reporter, _)| *reporter == id)
+        .expect("we report to ourselves");
+    let mut report = digests
+        .iter()
+        .filter(|(reporter, _)| *reporter != id)
+        .filter_map(|(reporter, theirs)| {
+            if theirs[id as usize] != ours[id as usize] {
+                Some((*reporter, Offence::ForgedDigests(cid.clone())))
+            } else if theirs[*reporter as usize] != ours[*reporter as usize] {
+                Some((*reporter, Offence::Equivocation(cid.clone())))
+            } else {
+                None
+            }
+        })
+        .collect::<BlameReport>();
+    report.sort_by_key(|(reporter, _)| *reporter);
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::shares::Elem;
+    use crate::ff::Field;
+    use crate::protocol::network::{setup_network, ChannelNetwork};
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn party(n_parties: u8) -> Party<ChannelNetwork> {
+        party_with_events(n_parties).0
+    }
+
+    fn party_with_events(n_parties: u8) -> (Party<ChannelNetwork>, Receiver<NodeEvents>) {
+        let network = setup_network(n_parties as u32).remove(0);
+        let (dealer_tx, _) = unbounded_channel();
+        let (_, dealer_rx) = unbounded_channel();
+        let (alpha_tx, _) = unbounded_channel();
+        let (_, commands_rx) = unbounded_channel();
+        let (events_tx, events_rx) = unbounded_channel();
+        let party = Party::new(
+            0,
+            (dealer_tx, dealer_rx),
+            alpha_tx,
+            commands_rx,
+            events_tx,
+            network,
+            n_parties,
+        );
+
+        (party, events_rx)
+    }
+
+    #[test]
+    fn blames_double_send() {
+        let mut party = party(2);
+        let share = vec![(Elem::one(), Elem::one())];
+        let cid = "cir".to_string();
+
+        assert!(party
+            .handle_network_msg(1, Msg::OpenShare(cid.clone(), share.clone()))
+            .is_ok());
+        assert_eq!(
+            Err(ProtocolError::Cheating(vec![(
+                1,
+                Offence::DoubleSend(cid.clone())
+            )])),
+            party.handle_network_msg(1, Msg::OpenShare(cid, share))
+        );
+    }
+
+    #[test]
+    fn accepts_variable_only_from_owner() {
+        let cid = "x".to_string();
+        let (party, _events) = party_with_events(3);
+        let mut party = party.with_input_owners([(cid.clone(), 1)].into_iter().collect());
+        let elems = vec![Elem::one()];
+
+        assert_eq!(
+            Err(ProtocolError::ProtocolViolation(
+                2,
+                "opened x it does not own".to_string()
+            )),
+            party.handle_network_msg(2, Msg::OpenVariable(cid.clone(), elems.clone()))
+        );
+        assert!(party
+            .handle_network_msg(1, Msg::OpenVariable(cid.clone(), elems.clone()))
+            .is_ok());
+        assert_eq!(
+            Err(ProtocolError::Cheating(vec![(
+                1,
+                Offence::DoubleSend(cid.clone())
+            )])),
+            party.handle_network_msg(1, Msg::OpenVariable(cid, elems))
+        );
+    }
+
+    #[test]
+    fn blames_nodes_with_differing_digests() {
+        let cid = "check".to_string();
+        let honest = [[1; 32], [2; 32], [3; 32]];
+        let mut equivocated = honest;
+        equivocated[2] = [4; 32];
+        let digests = vec![
+            (0, honest.to_vec()),
+            (1, honest.to_vec()),
+            (2, equivocated.to_vec()),
+        ];
+
+        assert_eq!(
+            vec![(2, Offence::Equivocation(cid.clone()))],
+            blame_digests(0, &cid, &digests)
+        );
+        assert!(blame_digests(0, &cid, &digests[..1]).is_empty());
+    }
+
+    #[test]
+    fn blames_reporter_of_forged_digests() {
+        let cid = "check".to_string();
+        let honest = [[1; 32], [2; 32], [3; 32]];
+        let mut forged = honest;
+        forged[0] = [4; 32];
+        forged[1] = [5; 32];
+        let digests = vec![
+            (2, forged.to_vec()),
+            (0, honest.to_vec()),
+            (1, honest.to_vec()),
+        ];
+
+        for id in [0, 1] {
+            assert_eq!(
+                vec![(2, Offence::ForgedDigests(cid.clone()))],
+                blame_digests(id, &cid, &digests)
+            );
+        }
+    }
+}
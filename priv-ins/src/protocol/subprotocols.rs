@@ -0,0 +1,609 @@
+use crate::crypto::shares::{self, Elem, Elems, Share, Shares, TruncationMaskShare};
+use crate::ff::Field;
+use std::collections::HashMap;
+
+use crate::protocol::{
+    arithmetics::Calculator,
+    error::ProtocolError,
+    expression::{bit_check_ids, power_ids, power_steps, prefix_or_ids, prefix_or_steps},
+    sub_id, CirId, COMPARISON_BITS, TRUNCATION_BITS,
+};
+
+/// Values exchanged with other nodes in one round
+#[derive(Debug, Default)]
+pub struct Round {
+    /// pairs of shares multiplied with beavers of their ids
+    pub products: Vec<(CirId, Shares, Shares)>,
+    /// shares opened under their ids
+    pub openings: Vec<(CirId, Shares)>,
+}
+
+impl Round {
+    fn product(cir_id: CirId, ev1: Shares, ev2: Shares) -> Self {
+        Self {
+            products: vec![(cir_id, ev1, ev2)],
+            openings: vec![],
+        }
+    }
+
+    fn opening(cir_id: CirId, shares: Shares) -> Self {
+        Self {
+            products: vec![],
+            openings: vec![(cir_id, shares)],
+        }
+    }
+
+    /// number of products and openings of the round
+    pub fn size(&self) -> (usize, usize) {
+        (self.products.len(), self.openings.len())
+    }
+
+    /// Appends values of `other` round to this one
+    pub fn extend(&mut self, other: Round) {
+        self.products.extend(other.products);
+        self.openings.extend(other.openings);
+    }
+}
+
+/// Results of a round in order of its products and openings
+#[derive(Debug, Default)]
+pub struct Exchanged {
+    pub products: Vec<Shares>,
+    pub openings: Vec<Elems>,
+}
+
+impl Exchanged {
+    /// Splits results of merged rounds back into results of each round of given size
+    pub fn split(self, sizes: &[(usize, usize)]) -> Vec<Exchanged> {
+        let mut products = self.products.into_iter();
+        let mut openings = self.openings.into_iter();
+        sizes
+            .iter()
+            .map(|(n_products, n_openings)| Exchanged {
+                products: products.by_ref().take(*n_products).collect(),
+                openings: openings.by_ref().take(*n_openings).collect(),
+            })
+            .collect()
+    }
+}
+
+/// Next step of a subprotocol
+pub enum Step {
+    /// values to exchange before the subprotocol can continue
+    Exchange(Round),
+    /// value of the circuit node
+    Done(Shares),
+}
+
+/// Subprotocol of an interactive circuit node, run in steps so that subprotocols of one layer
+/// exchange their values in shared rounds
+pub trait Subprotocol: Send {
+    /// Continues with results of the previous round, the first step gets empty results
+    fn step(
+        &mut self,
+        calculator: &Calculator,
+        exchanged: Exchanged,
+    ) -> Result<Step, ProtocolError>;
+}
+
+/// Multiplication of two shares using beaver of `cir_id`
+pub struct Product {
+    cir_id: CirId,
+    operands: Option<(Shares, Shares)>,
+}
+
+impl Product {
+    pub fn new(cir_id: CirId, ev1: Shares, ev2: Shares) -> Self {
+        Self {
+            cir_id,
+            operands: Some((ev1, ev2)),
+        }
+    }
+}
+
+impl Subprotocol for Product {
+    fn step(&mut self, _: &Calculator, mut exchanged: Exchanged) -> Result<Step, ProtocolError> {
+        Ok(match self.operands.take() {
+            Some((ev1, ev2)) => Step::Exchange(Round::product(self.cir_id.clone(), ev1, ev2)),
+            None => Step::Done(exchanged.products.pop().expect("one product")),
+        })
+    }
+}
+
+/// Computes [x / 2^m] for x in [-2^(k-1), 2^(k-1)) where k = TRUNCATION_BITS, rounded up or down.
+/// For z = x + 2^(k-1) we open c = z + r for dealer's mask r with known shares of r mod 2^m,
+/// then x - (c mod 2^m) + (r mod 2^m) is x - (x mod 2^m) up to a carry of 2^m.
+pub struct Truncation {
+    cir_id: CirId,
+    n_bits: u32,
+    x: Shares,
+    mask: Vec<TruncationMaskShare>,
+    opened: bool,
+}
+
+impl Truncation {
+    pub fn new(cir_id: CirId, n_bits: u32, x: Shares, mask: Vec<TruncationMaskShare>) -> Self {
+        Self {
+            cir_id,
+            n_bits,
+            x,
+            mask,
+            opened: false,
+        }
+    }
+
+    /// Returns opening of c = z + r
+    fn opening(&self, calculator: &Calculator) -> Round {
+        let c = zip_rows(&self.x, &self.mask, |x, (_, r)| {
+            let z = calculator.add_const(*x, shares::pow2(TRUNCATION_BITS - 1));
+            calculator.add(z, *r)
+        });
+        Round::opening(sub_id(&self.cir_id, &"c".to_string()), c)
+    }
+
+    /// Returns [x / 2^m] given opened c
+    fn truncated(&self, calculator: &Calculator, c: &[Elem]) -> Shares {
+        self.mask
+            .iter()
+            .zip(self.x.iter().zip(c.iter()))
+            .map(|((r_low, _), (x, c))| {
+                // (c mod 2^m) - (r mod 2^m) is x mod 2^m or less by 2^m
+                let x_low = calculator.add_const(
+                    calculator.mul_by_const(*r_low, -Elem::one()),
+                    shares::low_bits(c, self.n_bits),
+                );
+                calculator.mul_by_const(
+                    calculator.sub(*x, x_low),
+                    shares::pow2(self.n_bits).invert().unwrap(),
+                )
+            })
+            .collect()
+    }
+}
+
+impl Subprotocol for Truncation {
+    fn step(
+        &mut self,
+        calculator: &Calculator,
+        mut exchanged: Exchanged,
+    ) -> Result<Step, ProtocolError> {
+        if !self.opened {
+            self.opened = true;
+            return Ok(Step::Exchange(self.opening(calculator)));
+        }
+
+        let c = exchanged.openings.pop().expect("one opening");
+        Ok(Step::Done(self.truncated(calculator, &c)))
+    }
+}
+
+/// Computes shares of f where f_i = d_i or d_{i+1} or ... or d_{n-1},
+/// ORs of each level of `prefix_or_steps` share one round
+struct PrefixOr {
+    /// shares of all rows of each f_i, equal to d_i before the first level
+    f: Vec<Shares>,
+    levels: std::vec::IntoIter<Vec<(usize, usize)>>,
+    mul_ids: std::vec::IntoIter<CirId>,
+    /// ORs whose products are computed in the current round
+    pending: Vec<(usize, usize)>,
+}
+
+impl PrefixOr {
+    fn new(cir_id: &CirId, d: Vec<Shares>) -> Self {
+        let n_bits = d.len() as u32;
+        Self {
+            f: d,
+            levels: prefix_or_steps(n_bits).into_iter(),
+            mul_ids: prefix_or_ids(cir_id, n_bits).into_iter(),
+            pending: vec![],
+        }
+    }
+
+    /// Finishes ORs of the current level with their products and returns products
+    /// of the next level, None once f is computed
+    fn step(&mut self, calculator: &Calculator, products: Vec<Shares>) -> Option<Round> {
+        for ((i, j), ab) in self.pending.drain(..).zip(products) {
+            // a or b = a + b - ab
+            let or = (0..ab.len())
+                .map(|row| calculator.sub(calculator.add(self.f[i][row], self.f[j][row]), ab[row]))
+                .collect();
+            self.f[i] = or;
+        }
+
+        let level = self.levels.next()?;
+        let mut round = Round::default();
+        for (i, j) in level.iter() {
+            let mul_id = self.mul_ids.next().expect("one id for each or");
+            round
+                .products
+                .push((mul_id, self.f[*i].clone(), self.f[*j].clone()));
+        }
+        self.pending = level;
+
+        Some(round)
+    }
+}
+
+/// Computes shared bit [x < 0] or [x == 0]
+///
+/// For x < 0 and x in [-2^k, 2^k) where k = COMPARISON_BITS: for z = x + 2^k we have x < 0 iff
+/// k-th bit of z is 0. To get it we open c = z + r for dealer's mask r and compute z mod 2^k
+/// from public bits of c and shared bits of r.
+///
+/// For x == 0 and x in (-2^k, 2^k): x == 0 iff z == 2^k. We open c = z + r for dealer's mask r
+/// of k + 1 bits, then z == 2^k iff c and r + 2^k agree on k + 1 least significant bits.
+pub struct Comparison {
+    cir_id: CirId,
+    /// tests x == 0 instead of x < 0
+    equality: bool,
+    z: Shares,
+    r: Shares,
+    r_bits: Vec<Shares>,
+    c_bits: Vec<Vec<bool>>,
+    prefix_or: Option<PrefixOr>,
+}
+
+impl Comparison {
+    /// Comparison of `x` with zero using bit mask of `cir_id`, given as bits and value of r
+    /// of each row
+    pub fn new(
+        calculator: &Calculator,
+        cir_id: CirId,
+        equality: bool,
+        x: Shares,
+        mask: (Vec<Shares>, Shares),
+    ) -> Self {
+        let z = x
+            .iter()
+            .map(|x| calculator.add_const(*x, shares::pow2(COMPARISON_BITS)))
+            .collect();
+        let (r_bits, r) = mask;
+
+        Self {
+            cir_id,
+            equality,
+            z,
+            r,
+            r_bits,
+            c_bits: vec![],
+            prefix_or: None,
+        }
+    }
+
+    /// Starts prefix OR over bits where opened c differs from r
+    fn start_prefix_or(&mut self, calculator: &Calculator, c: &[Elem]) {
+        self.c_bits = zip_rows(c, &self.r_bits, |c, r_bits| {
+            shares::elem_bits(c, r_bits.len() as u32)
+        });
+        if self.equality {
+            // adding 2^k modulo 2^(k+1) to r flips its k-th bit, flip it in c instead
+            for c_bits in self.c_bits.iter_mut() {
+                let top = c_bits.len() - 1;
+                c_bits[top] = !c_bits[top];
+            }
+        }
+
+        let d = zip_rows(&self.c_bits, &self.r_bits, |c_bits, r_bits| {
+            xor_public(calculator, c_bits, r_bits)
+        });
+        self.prefix_or = Some(PrefixOr::new(&self.cir_id, transpose(&d)));
+    }
+
+    fn result(&self, calculator: &Calculator, f: &[Shares]) -> Shares {
+        if self.equality {
+            return f[0].iter().map(|f_0| one_minus(calculator, *f_0)).collect();
+        }
+
+        let f = transpose(f);
+        (0..self.z.len())
+            .map(|row| {
+                less_than_zero_row(
+                    calculator,
+                    &self.c_bits[row],
+                    &self.r_bits[row],
+                    &f[row],
+                    self.z[row],
+                )
+            })
+            .collect()
+    }
+}
+
+impl Subprotocol for Comparison {
+    fn step(
+        &mut self,
+        calculator: &Calculator,
+        mut exchanged: Exchanged,
+    ) -> Result<Step, ProtocolError> {
+        if self.prefix_or.is_none() {
+            let c = match exchanged.openings.pop() {
+                Some(c) => c,
+                None => {
+                    let c = zip_rows(&self.z, &self.r, |z, r| calculator.add(*z, *r));
+                    let c_id = sub_id(&self.cir_id, &"c".to_string());
+                    return Ok(Step::Exchange(Round::opening(c_id, c)));
+                }
+            };
+            self.start_prefix_or(calculator, &c);
+        }
+
+        let prefix_or = self.prefix_or.as_mut().expect("started above");
+        if let Some(round) = prefix_or.step(calculator, exchanged.products) {
+            return Ok(Step::Exchange(round));
+        }
+
+        let f = &self.prefix_or.as_ref().expect("started above").f;
+        Ok(Step::Done(self.result(calculator, f)))
+    }
+}
+
+/// Checks that x is 0 or 1 by opening rho * x * (x - 1) for dealer's random rho,
+/// which is 0 for a bit and a random value otherwise.
+pub struct BitCheck {
+    cir_id: CirId,
+    x: Shares,
+    rho: Shares,
+    /// number of finished rounds
+    rounds: usize,
+}
+
+impl BitCheck {
+    pub fn new(cir_id: CirId, x: Shares, rho: Shares) -> Self {
+        Self {
+            cir_id,
+            x,
+            rho,
+            rounds: 0,
+        }
+    }
+}
+
+impl Subprotocol for BitCheck {
+    fn step(
+        &mut self,
+        calculator: &Calculator,
+        mut exchanged: Exchanged,
+    ) -> Result<Step, ProtocolError> {
+        let [square_id, randomize_id]: [CirId; 2] = bit_check_ids(&self.cir_id)
+            .try_into()
+            .expect("bit check has two multiplications");
+
+        self.rounds += 1;
+        Ok(match self.rounds {
+            1 => {
+                let x_minus_one = self
+                    .x
+                    .iter()
+                    .map(|x| calculator.add_const(*x, -Elem::one()))
+                    .collect();
+                Step::Exchange(Round::product(square_id, self.x.clone(), x_minus_one))
+            }
+            2 => {
+                let t = exchanged.products.pop().expect("one product");
+                Step::Exchange(Round::product(randomize_id, t, self.rho.clone()))
+            }
+            3 => {
+                let t = exchanged.products.pop().expect("one product");
+                Step::Exchange(Round::opening(
+                    sub_id(&self.cir_id, &"check".to_string()),
+                    t,
+                ))
+            }
+            _ => {
+                let opened = exchanged.openings.pop().expect("one opening");
+                if opened.iter().any(|t| !bool::from(t.is_zero())) {
+                    log::debug!("value of {} is not a bit", self.cir_id);
+                    return Err(ProtocolError::BitCheckFailed(self.cir_id.clone()));
+                }
+                Step::Done(self.x.clone())
+            }
+        })
+    }
+}
+
+/// Computes [x^exponent] with multiplications planned by `power_steps`,
+/// products are truncated by `n_bits` if it is nonzero.
+/// Multiplications of the same depth are done in one round.
+pub struct Power {
+    steps: Vec<(usize, usize)>,
+    mul_ids: Vec<CirId>,
+    /// depth of each factor, i-th step produces factor i + 1
+    depths: Vec<usize>,
+    factors: Vec<Option<Shares>>,
+    n_bits: u32,
+    /// truncation masks by ids of multiplications
+    masks: HashMap<CirId, Vec<TruncationMaskShare>>,
+    depth: usize,
+    /// steps of the current depth
+    level: Vec<usize>,
+    /// truncations of products of the current depth waiting for their openings
+    truncations: Vec<Truncation>,
+}
+
+impl Power {
+    pub fn new(
+        cir_id: &CirId,
+        exponent: u32,
+        n_bits: u32,
+        x: Shares,
+        masks: HashMap<CirId, Vec<TruncationMaskShare>>,
+    ) -> Self {
+        let steps = power_steps(exponent);
+        let mut depths = vec![0];
+        for (a, b) in steps.iter() {
+            depths.push(depths[*a].max(depths[*b]) + 1);
+        }
+        let mut factors = vec![None; steps.len() + 1];
+        factors[0] = Some(x);
+
+        Self {
+            mul_ids: power_ids(cir_id, exponent),
+            steps,
+            depths,
+            factors,
+            n_bits,
+            masks,
+            depth: 0,
+            level: vec![],
+            truncations: vec![],
+        }
+    }
+}
+
+impl Subprotocol for Power {
+    fn step(
+        &mut self,
+        calculator: &Calculator,
+        exchanged: Exchanged,
+    ) -> Result<Step, ProtocolError> {
+        if !self.truncations.is_empty() {
+            let truncated = self
+                .truncations
+                .drain(..)
+                .zip(exchanged.openings)
+                .map(|(truncation, c)| truncation.truncated(calculator, &c))
+                .collect::<Vec<_>>();
+            for (i, product) in self.level.iter().zip(truncated) {
+                self.factors[i + 1] = Some(product);
+            }
+        } else if self.n_bits > 0 && !exchanged.products.is_empty() {
+            let mut round = Round::default();
+            for (i, product) in self.level.iter().zip(exchanged.products) {
+                let mul_id = &self.mul_ids[*i];
+                let mask = self.masks.remove(mul_id).expect("preprocessed");
+                let truncation = Truncation::new(mul_id.clone(), self.n_bits, product, mask);
+                round.extend(truncation.opening(calculator));
+                self.truncations.push(truncation);
+            }
+            return Ok(Step::Exchange(round));
+        } else {
+            for (i, product) in self.level.iter().zip(exchanged.products) {
+                self.factors[i + 1] = Some(product);
+            }
+        }
+
+        self.depth += 1;
+        if self.depth > self.depths.iter().copied().max().unwrap_or(0) {
+            return Ok(Step::Done(
+                self.factors
+                    .pop()
+                    .flatten()
+                    .expect("the last factor is the power"),
+            ));
+        }
+
+        self.level = (0..self.steps.len())
+            .filter(|i| self.depths[i + 1] == self.depth)
+            .collect();
+        let factor = |i: usize| self.factors[i].clone().expect("computed in lower depth");
+        let products = self
+            .level
+            .iter()
+            .map(|i| {
+                let (a, b) = self.steps[*i];
+                (self.mul_ids[*i].clone(), factor(a), factor(b))
+            })
+            .collect();
+
+        Ok(Step::Exchange(Round {
+            products,
+            openings: vec![],
+        }))
+    }
+}
+
+/// Returns [1 - x], for bit x it is its negation
+pub fn one_minus(calculator: &Calculator, share: Share) -> Share {
+    calculator.add_const(calculator.mul_by_const(share, -Elem::one()), Elem::one())
+}
+
+/// Computes [x < 0] of one row from public bits of c = z + r, shared bits of r
+/// and their prefix or f, see `Comparison`
+fn less_than_zero_row(
+    calculator: &Calculator,
+    c_bits: &[bool],
+    r_bits: &[Share],
+    f: &[Share],
+    z: Share,
+) -> Share {
+    let zero = (Elem::zero(), Elem::zero());
+    let two_k = shares::pow2(COMPARISON_BITS);
+
+    // e_i = f_i - f_{i+1} is set only on the most significant bit where c and r differ,
+    // (c mod 2^k) < (r mod 2^k) iff c has 0 on that bit
+    let u = (0..c_bits.len())
+        .filter(|i| !c_bits[*i])
+        .map(|i| match f.get(i + 1) {
+            Some(next) => calculator.sub(f[i], *next),
+            None => f[i],
+        })
+        .fold(zero, |acc, e_i| calculator.add(acc, e_i));
+
+    // z mod 2^k = (c mod 2^k) - (r mod 2^k) + 2^k * u
+    let r_low = r_bits.iter().rev().fold(zero, |acc, r_i| {
+        calculator.add(calculator.mul_by_const(acc, Elem::from(2)), *r_i)
+    });
+    let c_low = c_bits.iter().rev().fold(Elem::zero(), |acc, c_i| {
+        acc.double() + Elem::from(*c_i as u64)
+    });
+    let z_low = calculator.add_const(
+        calculator.sub(calculator.mul_by_const(u, two_k), r_low),
+        c_low,
+    );
+
+    // z < 2^(k+1) so (z - z mod 2^k) / 2^k is its k-th bit
+    let bit = calculator.mul_by_const(calculator.sub(z, z_low), two_k.invert().unwrap());
+
+    one_minus(calculator, bit)
+}
+
+/// Applies `op` to values of the same row of two batches
+pub fn zip_rows<A, B, C>(xs: &[A], ys: &[B], op: impl Fn(&A, &B) -> C) -> Vec<C> {
+    xs.iter().zip(ys.iter()).map(|(x, y)| op(x, y)).collect()
+}
+
+/// Turns values of each row into rows of each value, e.g. bits of each row into rows of each bit
+fn transpose(rows: &[Shares]) -> Vec<Shares> {
+    let n_values = rows.first().map_or(0, |row| row.len());
+    (0..n_values)
+        .map(|i| rows.iter().map(|row| row[i]).collect())
+        .collect()
+}
+
+/// Returns shares of c_i xor r_i, it is linear as bits c_i are public
+fn xor_public(calculator: &Calculator, c_bits: &[bool], r_bits: &[Share]) -> Shares {
+    c_bits
+        .iter()
+        .zip(r_bits.iter())
+        .map(|(c_i, r_i)| {
+            if *c_i {
+                one_minus(calculator, *r_i)
+            } else {
+                *r_i
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_merged_round_results() {
+        let elems = |n: u64| vec![Elem::from(n)];
+        let shares = |n: u64| vec![(Elem::from(n), Elem::zero())];
+        let exchanged = Exchanged {
+            products: vec![shares(1), shares(2), shares(3)],
+            openings: vec![elems(4), elems(5)],
+        };
+
+        let split = exchanged.split(&[(1, 1), (0, 0), (2, 1)]);
+
+        assert_eq!(vec![shares(1)], split[0].products);
+        assert_eq!(vec![elems(4)], split[0].openings);
+        assert!(split[1].products.is_empty() && split[1].openings.is_empty());
+        assert_eq!(vec![shares(2), shares(3)], split[2].products);
+        assert_eq!(vec![elems(5)], split[2].openings);
+    }
+}
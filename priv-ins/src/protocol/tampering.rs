@@ -0,0 +1,128 @@
+use crate::protocol::{
+    network::{Msg, Network, NetworkMessage},
+    NodeId,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+
+/// What happens to a message of the tampered node picked by a rule
+pub enum Attack {
+    /// message is replaced before it's sent
+    Modify(Box<dyn FnMut(Msg) -> Msg + Send>),
+    Drop,
+    /// message is sent twice
+    Duplicate,
+    /// message is sent after given time
+    Delay(Duration),
+    /// message is sent after the next message to the same receiver
+    Reorder,
+}
+
+/// Picks messages by their receiver and content
+type Target = Box<dyn Fn(NodeId, &Msg) -> bool + Send>;
+
+/// Network of a malicious node, wraps network of an honest one and applies attacks to messages
+/// it sends. Broadcasts are split into messages to every node, so a node can equivocate.
+pub struct TamperingNetwork<N: Network + Send> {
+    inner: N,
+    n_parties: u8,
+    rules: Vec<(Target, Attack)>,
+    delayed: Vec<(Instant, NetworkMessage)>,
+    held: HashMap<NodeId, Msg>,
+}
+
+impl<N: Network + Send> TamperingNetwork<N> {
+    pub fn new(inner: N, n_parties: u8) -> Self {
+        Self {
+            inner,
+            n_parties,
+            rules: vec![],
+            delayed: vec![],
+            held: HashMap::new(),
+        }
+    }
+
+    /// Applies `attack` to messages picked by `target`, first matching rule wins
+    pub fn with_attack(
+        mut self,
+        target: impl Fn(NodeId, &Msg) -> bool + Send + 'static,
+        attack: Attack,
+    ) -> Self {
+        self.rules.push((Box::new(target), attack));
+        self
+    }
+
+    fn release_delayed(&mut self) {
+        let now = Instant::now();
+        let (due, delayed) = self.delayed.drain(..).partition(|(at, _)| *at <= now);
+        self.delayed = delayed;
+        for (_, msg) in due {
+            self.inner.send_to(msg);
+        }
+    }
+
+    fn tamper(&mut self, to: NodeId, msg: Msg) {
+        let held = self.held.remove(&to);
+        let attack = self
+            .rules
+            .iter_mut()
+            .find(|(target, _)| target(to, &msg))
+            .map(|(_, attack)| attack);
+
+        match attack {
+            None => self.inner.send_to((to, msg)),
+            Some(Attack::Modify(modify)) => {
+                let msg = modify(msg);
+                self.inner.send_to((to, msg));
+            }
+            Some(Attack::Drop) => {}
+            Some(Attack::Duplicate) => {
+                self.inner.send_to((to, msg.clone()));
+                self.inner.send_to((to, msg));
+            }
+            Some(Attack::Delay(delay)) => {
+                self.delayed.push((Instant::now() + *delay, (to, msg)));
+            }
+            // message after a held one is sent as is, so two messages swap places
+            Some(Attack::Reorder) if held.is_none() => {
+                self.held.insert(to, msg);
+            }
+            Some(Attack::Reorder) => self.inner.send_to((to, msg)),
+        }
+
+        if let Some(held) = held {
+            self.inner.send_to((to, held));
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<N: Network + Send> Network for TamperingNetwork<N> {
+    fn send_to(&mut self, msg: NetworkMessage) {
+        self.release_delayed();
+        self.tamper(msg.0, msg.1);
+    }
+
+    async fn receive(&mut self) -> Option<NetworkMessage> {
+        loop {
+            self.release_delayed();
+            match self.delayed.iter().map(|(at, _)| *at).min() {
+                Some(at) => {
+                    tokio::select! {
+                        msg = self.inner.receive() => return msg,
+                        _ = sleep_until(at) => {}
+                    }
+                }
+                None => return self.inner.receive().await,
+            }
+        }
+    }
+
+    fn broadcast(&mut self, msg: Msg) {
+        self.release_delayed();
+        for to in 0..self.n_parties as NodeId {
+            self.tamper(to, msg.clone());
+        }
+    }
+}
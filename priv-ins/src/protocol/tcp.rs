@@ -0,0 +1,515 @@
+use crate::protocol::{
+    network::{Msg, Network, NetworkMessage},
+    wire::{self, MAX_MESSAGE_BYTES},
+    NodeId,
+};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{sleep, timeout},
+};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+/// Addresses nodes listen on for messages of other nodes
+pub type AddressBook = HashMap<NodeId, SocketAddr>;
+
+/// How many times and how often a node tries to connect to a peer that is not listening yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retries {
+    pub attempts: usize,
+    pub delay: Duration,
+}
+
+impl Default for Retries {
+    fn default() -> Self {
+        Self {
+            attempts: 50,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// How long a node waits for the handshake of a connection before dropping it
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tells the peer who opened a connection and learns it on the other end, before any message
+/// is sent over it. This is the place to authenticate nodes, e.g. with a signature of a key
+/// shared by the nodes. TLS would also need the network to run over its streams.
+#[async_trait::async_trait]
+pub trait Handshake: Send + Sync {
+    /// Introduces node `id` that opened the connection
+    async fn hello(&self, id: NodeId, stream: &mut TcpStream) -> io::Result<()>;
+
+    /// Returns id of the node that opened the connection
+    async fn identify(&self, stream: &mut TcpStream) -> io::Result<NodeId>;
+}
+
+/// Handshake sending just the id of the node in one length prefixed frame.
+/// It doesn't authenticate the node: anyone who can reach our address can claim an id
+/// that hasn't connected yet and send messages in its name.
+pub struct ClaimedId;
+
+/// Longest hello of `ClaimedId`, the version byte and varint of the largest id
+const MAX_HELLO_BYTES: u32 = 10;
+
+#[async_trait::async_trait]
+impl Handshake for ClaimedId {
+    async fn hello(&self, id: NodeId, stream: &mut TcpStream) -> io::Result<()> {
+        let hello = wire::encode(&id).expect("ids are encodable");
+        stream.write_u32(hello.len() as u32).await?;
+        stream.write_all(&hello).await
+    }
+
+    async fn identify(&self, stream: &mut TcpStream) -> io::Result<NodeId> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let len = stream.read_u32().await?;
+        if len > MAX_HELLO_BYTES {
+            return Err(invalid(format!("hello of {} bytes", len)));
+        }
+        let mut hello = vec![0; len as usize];
+        stream.read_exact(&mut hello).await?;
+        wire::decode(&hello).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+/// Network over TCP connections to all peers, each peer gets its own connection for our messages.
+/// A connection starts with the `Handshake` identifying the node that opened it, followed by
+/// length prefixed frames of `Msg` in the wire format. Only the first connection of each peer
+/// is accepted for the lifetime of the network. Messages to ourselves don't leave the process.
+pub struct TcpNetwork {
+    id: NodeId,
+    peers: HashMap<NodeId, UnboundedSender<Msg>>,
+    loopback: UnboundedSender<NetworkMessage>,
+    receiver: UnboundedReceiver<NetworkMessage>,
+}
+
+impl TcpNetwork {
+    /// Listens on our address from `addresses` and connects to all other nodes in it,
+    /// nodes are identified by the ids they claim, see `ClaimedId`
+    pub async fn connect(
+        id: NodeId,
+        addresses: &AddressBook,
+        retries: Retries,
+    ) -> io::Result<Self> {
+        Self::connect_with(id, addresses, retries, Arc::new(ClaimedId)).await
+    }
+
+    /// Same as `connect`, nodes are identified by given handshake
+    pub async fn connect_with(
+        id: NodeId,
+        addresses: &AddressBook,
+        retries: Retries,
+        handshake: Arc<dyn Handshake>,
+    ) -> io::Result<Self> {
+        let address = addresses.get(&id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no address of node {}", id),
+            )
+        })?;
+        let listener = TcpListener::bind(address).await?;
+        let (loopback, receiver) = unbounded_channel();
+        let nodes = addresses.keys().copied().collect();
+        tokio::spawn(accept(
+            id,
+            nodes,
+            listener,
+            handshake.clone(),
+            loopback.clone(),
+        ));
+
+        let mut peers = HashMap::new();
+        for (&peer, address) in addresses.iter().filter(|(peer, _)| **peer != id) {
+            let mut stream = dial(*address, retries).await?;
+            stream.set_nodelay(true)?;
+            handshake.hello(id, &mut stream).await?;
+            let (sender, messages) = unbounded_channel();
+            tokio::spawn(write(peer, stream, messages));
+            peers.insert(peer, sender);
+        }
+
+        Ok(Self {
+            id,
+            peers,
+            loopback,
+            receiver,
+        })
+    }
+}
+
+/// Connects networks of all nodes, node i listens on `addresses[i]`
+pub async fn setup_tcp_network(
+    addresses: &[SocketAddr],
+    retries: Retries,
+) -> io::Result<Vec<TcpNetwork>> {
+    let book: AddressBook = addresses
+        .iter()
+        .enumerate()
+        .map(|(id, address)| (id as NodeId, *address))
+        .collect();
+
+    futures::future::try_join_all(
+        (0..addresses.len()).map(|id| TcpNetwork::connect(id as NodeId, &book, retries)),
+    )
+    .await
+}
+
+fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_MESSAGE_BYTES as usize)
+        .new_codec()
+}
+
+async fn dial(address: SocketAddr, retries: Retries) -> io::Result<TcpStream> {
+    let mut attempt = 1;
+    loop {
+        match TcpStream::connect(address).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempt < retries.attempts => {
+                log::debug!("can't connect to {} yet: {}", address, e);
+                attempt += 1;
+                sleep(retries.delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Accepts connections of other nodes until our network is dropped
+async fn accept(
+    id: NodeId,
+    nodes: Vec<NodeId>,
+    listener: TcpListener,
+    handshake: Arc<dyn Handshake>,
+    messages: UnboundedSender<NetworkMessage>,
+) {
+    let connected = Arc::new(Mutex::new(HashSet::new()));
+    loop {
+        select! {
+            connection = listener.accept() => match connection {
+                Ok((stream, _)) => {
+                    let peer = Peer {
+                        id,
+                        nodes: nodes.clone(),
+                        connected: connected.clone(),
+                    };
+                    tokio::spawn(read(peer, stream, handshake.clone(), messages.clone()));
+                }
+                Err(e) => log::debug!("node {} can't accept connection: {}", id, e),
+            },
+            _ = messages.closed() => return,
+        }
+    }
+}
+
+/// Sends our messages to the peer we already said hello to
+async fn write(peer: NodeId, stream: TcpStream, mut messages: UnboundedReceiver<Msg>) {
+    let mut frames = FramedWrite::new(stream, codec());
+    while let Some(msg) = messages.recv().await {
+        let frame = match wire::encode(&msg) {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::debug!("can't send message to node {}: {}", peer, e);
+                continue;
+            }
+        };
+        if let Err(e) = frames.send(Bytes::from(frame)).await {
+            log::debug!("node {} is gone: {}", peer, e);
+            return;
+        }
+    }
+}
+
+/// What we know about peers when one of them opens a connection
+struct Peer {
+    /// our id
+    id: NodeId,
+    nodes: Vec<NodeId>,
+    /// peers that already opened their connection
+    connected: Arc<Mutex<HashSet<NodeId>>>,
+}
+
+impl Peer {
+    /// Accepts the first connection of other known nodes
+    fn accepts(&self, from: NodeId) -> bool {
+        from != self.id
+            && self.nodes.contains(&from)
+            && self.connected.lock().expect("not poisoned").insert(from)
+    }
+}
+
+/// Relays messages of the node that opened the connection, drops connections of unknown nodes,
+/// second connections of a node and connections of nodes that send malformed frames
+async fn read(
+    peer: Peer,
+    mut stream: TcpStream,
+    handshake: Arc<dyn Handshake>,
+    messages: UnboundedSender<NetworkMessage>,
+) {
+    let from = match timeout(HANDSHAKE_TIMEOUT, handshake.identify(&mut stream)).await {
+        Ok(Ok(from)) if peer.accepts(from) => from,
+        Ok(Ok(from)) => {
+            log::debug!("node {} dropped connection claiming id {}", peer.id, from);
+            return;
+        }
+        Ok(Err(e)) => {
+            log::debug!("node {} dropped connection of unknown node: {}", peer.id, e);
+            return;
+        }
+        Err(_) => {
+            log::debug!("node {} dropped connection without handshake", peer.id);
+            return;
+        }
+    };
+
+    let mut frames = FramedRead::new(stream, codec());
+    while let Some(frame) = frames.next().await {
+        let msg = frame
+            .map_err(|e| e.to_string())
+            .and_then(|frame| wire::decode::<Msg>(&frame).map_err(|e| e.to_string()));
+        match msg {
+            Ok(msg) => {
+                if messages.send((from, msg)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                log::debug!("node {} sent malformed frame: {}", from, e);
+                return;
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Network for TcpNetwork {
+    fn send_to(&mut self, (to, msg): NetworkMessage) {
+        if to == self.id {
+            self.loopback
+                .send((self.id, msg))
+                .expect("we hold the receiver");
+        } else if let Some(peer) = self.peers.get(&to) {
+            // peer that finished or aborted doesn't need our messages anymore
+            if peer.send(msg).is_err() {
+                log::debug!("node {} is gone", to);
+            }
+        }
+    }
+
+    async fn receive(&mut self) -> Option<NetworkMessage> {
+        self.receiver.recv().await
+    }
+
+    fn broadcast(&mut self, msg: Msg) {
+        let mut nodes = self.peers.keys().copied().collect::<Vec<_>>();
+        nodes.push(self.id);
+        for to in nodes {
+            self.send_to((to, msg.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::shares::Elem;
+    use crate::expressions::parser::parse_outputs;
+    use crate::ff::Field;
+    use crate::protocol::{run_nodes_over, Timeouts, DEFAULT_FRACTIONAL_BITS};
+
+    /// Addresses on localhost with ports that were free a moment ago
+    fn free_addresses(n: usize) -> Vec<SocketAddr> {
+        let listeners = (0..n)
+            .map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect::<Vec<_>>();
+        listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect()
+    }
+
+    fn share_msg() -> Msg {
+        Msg::OpenShare("cir".to_string(), vec![(Elem::one(), Elem::zero())])
+    }
+
+    #[tokio::test]
+    async fn delivers_messages_to_all_nodes() {
+        let mut networks = setup_tcp_network(&free_addresses(3), Retries::default())
+            .await
+            .unwrap();
+
+        networks[1].broadcast(share_msg());
+        networks[2].send_to((0, Msg::ProofValid("check".to_string())));
+
+        for network in networks.iter_mut() {
+            match network.receive().await {
+                Some((1, Msg::OpenShare(cid, shares))) => {
+                    assert_eq!("cir", cid);
+                    assert_eq!(vec![(Elem::one(), Elem::zero())], shares);
+                }
+                msg => panic!("expected share of node 1, got {:?}", msg),
+            }
+        }
+        assert!(matches!(
+            networks[0].receive().await,
+            Some((2, Msg::ProofValid(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn retries_until_peer_listens() {
+        let addresses = free_addresses(2);
+        let book: AddressBook = addresses
+            .iter()
+            .enumerate()
+            .map(|(id, address)| (id as NodeId, *address))
+            .collect();
+
+        let early = tokio::spawn({
+            let book = book.clone();
+            async move { TcpNetwork::connect(0, &book, Retries::default()).await }
+        });
+        sleep(Duration::from_millis(300)).await;
+        let mut late = TcpNetwork::connect(1, &book, Retries::default())
+            .await
+            .unwrap();
+        let mut early = early.await.unwrap().unwrap();
+
+        early.send_to((1, share_msg()));
+        assert!(matches!(late.receive().await, Some((0, _))));
+    }
+
+    #[tokio::test]
+    async fn fails_when_peer_never_listens() {
+        let addresses = free_addresses(2);
+        let book = addresses
+            .iter()
+            .enumerate()
+            .map(|(id, address)| (id as NodeId, *address))
+            .collect();
+        let retries = Retries {
+            attempts: 3,
+            delay: Duration::from_millis(10),
+        };
+
+        assert!(TcpNetwork::connect(0, &book, retries).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn drops_connection_of_unknown_node() {
+        let addresses = free_addresses(2);
+        let mut networks = setup_tcp_network(&addresses, Retries::default())
+            .await
+            .unwrap();
+
+        // stranger claims to be node 7 and sends a valid message
+        impostor(addresses[0], 7).await;
+
+        networks[1].send_to((0, Msg::ProofValid("check".to_string())));
+        assert!(matches!(
+            networks[0].receive().await,
+            Some((1, Msg::ProofValid(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn drops_second_connection_of_node() {
+        let addresses = free_addresses(2);
+        let mut networks = setup_tcp_network(&addresses, Retries::default())
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        // node 0 is already connected, stranger claiming its id is refused
+        impostor(addresses[1], 0).await;
+
+        networks[0].send_to((1, Msg::ProofValid("check".to_string())));
+        assert!(matches!(
+            networks[1].receive().await,
+            Some((0, Msg::ProofValid(_)))
+        ));
+    }
+
+    #[test]
+    fn hello_of_any_id_fits_limit() {
+        let longest = wire::encode(&NodeId::MAX).unwrap();
+        assert_eq!(MAX_HELLO_BYTES as usize, longest.len());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_hello() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stranger = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        stranger.write_u32(MAX_HELLO_BYTES + 1).await.unwrap();
+        stranger.write_all(&[0; 64]).await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let err = ClaimedId.identify(&mut stream).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_connection_without_handshake() {
+        let addresses = free_addresses(2);
+        let _networks = setup_tcp_network(&addresses, Retries::default())
+            .await
+            .unwrap();
+
+        // stranger connects and never says hello
+        let mut stream = TcpStream::connect(addresses[0]).await.unwrap();
+        let closed = timeout(HANDSHAKE_TIMEOUT * 2, stream.read(&mut [0; 1])).await;
+        assert!(matches!(closed, Ok(Ok(0))), "{:?}", closed);
+    }
+
+    /// Connects to `address` as node `claimed` and sends a valid message
+    async fn impostor(address: SocketAddr, claimed: NodeId) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        ClaimedId.hello(claimed, &mut stream).await.unwrap();
+        let mut frames = FramedWrite::new(stream, codec());
+        let msg = wire::encode(&share_msg()).unwrap();
+        frames.send(Bytes::from(msg)).await.unwrap();
+        frames.into_inner().shutdown().await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    #[tokio::test]
+    async fn runs_nodes_on_localhost() {
+        let networks = setup_tcp_network(&free_addresses(3), Retries::default())
+            .await
+            .unwrap();
+        let variable_values = vec![
+            [("a".to_string(), vec![3, 1])].into_iter().collect(),
+            [("b".to_string(), vec![4, 2])].into_iter().collect(),
+            [("c".to_string(), vec![5, 0])].into_iter().collect(),
+        ];
+
+        let results = run_nodes_over::<_, i64>(
+            networks,
+            2,
+            variable_values,
+            parse_outputs("x = a * b + c; y = a < b").unwrap(),
+            DEFAULT_FRACTIONAL_BITS,
+            Timeouts::default(),
+        )
+        .await;
+
+        for result in results {
+            let values = result.unwrap();
+            assert_eq!(vec![17, 2], values["x"]);
+            assert_eq!(vec![1, 1], values["y"]);
+        }
+    }
+}
@@ -1,11 +1,37 @@
-use crate::expressions::BinaryOp::{Add, Mul};
-use crate::expressions::Expression;
-use crate::protocol::run_nodes;
-use serde::{Deserialize, Serialize};
+use crate::crypto::shares::Elem;
+use crate::expressions::BinaryOp::{Add, Mul, Sub};
+use crate::expressions::{
+    decimal::Decimal,
+    eval::evaluate_outputs,
+    parser::{parse, parse_outputs, RESULT},
+    Expression, OutputExpression, Outputs,
+};
+use crate::protocol::{
+    dealer::TrustedDealer,
+    error::ProtocolError,
+    floor_div,
+    network::{setup_network, ChannelNetwork, Msg, Network, NetworkMessage},
+    output::Output,
+    run_node, run_nodes, run_nodes_batched, variable_owners, DealerCommands, DealerEvents,
+    NodeConfig, Phase, Timeouts, DEFAULT_FRACTIONAL_BITS, MAX_FRACTIONAL_BITS,
+};
+use num_bigint::BigUint;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct SuccessMsg {
-    msg: String,
+/// Runs circuit with single output and returns its value for each node
+async fn run_expression<T: Output + Send + 'static>(
+    n_parties: u32,
+    variable_values: Vec<HashMap<String, i64>>,
+    expression: Expression<u64>,
+) -> Vec<Result<T, ProtocolError>> {
+    let outputs = Outputs::from([(RESULT.to_string(), OutputExpression::public(expression))]);
+    run_nodes(n_parties, variable_values, outputs, DEFAULT_FRACTIONAL_BITS)
+        .await
+        .into_iter()
+        .map(|r| r.map(|mut values| values.remove(RESULT).expect("single output")))
+        .collect()
 }
 
 #[tokio::test]
@@ -23,7 +49,7 @@ async fn test_expression_small() {
         .map(|i| [(i.to_string(), i + 5)].iter().cloned().collect())
         .collect();
     let expected_result = 5 * 6;
-    let results = run_nodes(2, variables, expression).await;
+    let results = run_expression::<i64>(2, variables, expression).await;
     assert_eq!(
         results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
         (0..2).map(|_| expected_result).collect::<Vec<_>>()
@@ -45,7 +71,7 @@ async fn test_more_parties_then_variables() {
         .map(|i| [(i.to_string(), i + 5)].iter().cloned().collect())
         .collect();
     let expected_result = 5 * 6;
-    let results = run_nodes(4, variables, expression).await;
+    let results = run_expression::<i64>(4, variables, expression).await;
     assert_eq!(
         results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
         (0..4).map(|_| expected_result).collect::<Vec<_>>()
@@ -89,9 +115,1227 @@ async fn test_expression_big() {
         .map(|i| [(i.to_string(), i + 5)].iter().cloned().collect())
         .collect();
     let expected_result = 10 * 5 * 6 * 7 * 8 + 9;
-    let results = run_nodes(5, variables, expression).await;
+    let results = run_expression::<i64>(5, variables, expression).await;
     assert_eq!(
         results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
         (0..5).map(|_| expected_result).collect::<Vec<_>>()
     );
 }
+
+#[tokio::test]
+async fn test_expression_sub_and_neg() {
+    // 100 - (-(x0 * x1) - x2) + (x3 - 7) = 100 + 5 * 6 + 7 + 8 - 7
+    let expression = Expression::<u64>::BinOp {
+        left: Box::new(Expression::<u64>::BinOp {
+            left: Box::new(Expression::Number { number: 100 }),
+            right: Box::new(Expression::<u64>::BinOp {
+                left: Box::new(Expression::Neg {
+                    expr: Box::new(Expression::<u64>::BinOp {
+                        left: Box::new(Expression::Variable {
+                            name: "0".to_string(),
+                        }),
+                        right: Box::new(Expression::Variable {
+                            name: "1".to_string(),
+                        }),
+                        op: Mul,
+                    }),
+                }),
+                right: Box::new(Expression::Variable {
+                    name: "2".to_string(),
+                }),
+                op: Sub,
+            }),
+            op: Sub,
+        }),
+        right: Box::new(Expression::<u64>::BinOp {
+            left: Box::new(Expression::Variable {
+                name: "3".to_string(),
+            }),
+            right: Box::new(Expression::Number { number: 7 }),
+            op: Sub,
+        }),
+        op: Add,
+    };
+    let variables = (0..4)
+        .map(|i| [(i.to_string(), i + 5)].iter().cloned().collect())
+        .collect();
+    let expected_result = 100 + 5 * 6 + 7 + 8 - 7;
+    let results = run_expression::<i64>(4, variables, expression).await;
+    assert_eq!(
+        results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+        (0..4).map(|_| expected_result).collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn test_parsed_expression() {
+    let expression = parse::<u64>("(age * 3 + claims * 120) * risk + 500").unwrap();
+    let variables = vec![
+        [("age".to_string(), 40)].iter().cloned().collect(),
+        [("claims".to_string(), 2)].iter().cloned().collect(),
+        [("risk".to_string(), 7)].iter().cloned().collect(),
+    ];
+    let expected_result = (40 * 3 + 2 * 120) * 7 + 500;
+    let results = run_expression::<i64>(3, variables, expression).await;
+    assert_eq!(
+        results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+        (0..3).map(|_| expected_result).collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn test_comparison() {
+    // premium with surcharge for elderly and discount for claim-free customers
+    let expression =
+        parse::<u64>("1000 + (age > 65) * 300 - (claims < 1) * 100 + (limit < claims * 1000)")
+            .unwrap();
+    let variables = vec![
+        [("age".to_string(), 70)].iter().cloned().collect(),
+        [("claims".to_string(), 0)].iter().cloned().collect(),
+        [("limit".to_string(), 20)].iter().cloned().collect(),
+    ];
+    let expected_result = 1000 + 300 - 100;
+    let results = run_expression::<i64>(3, variables, expression).await;
+    assert_eq!(
+        results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+        (0..3).map(|_| expected_result).collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn test_comparison_composes_with_mul() {
+    let expression = parse::<u64>("(a < b) * (b < a + 2) * a + (a > 5) + (7 > a)").unwrap();
+    for (a, b, expected_result) in [
+        (5, 5, 1),
+        (4, 5, 5),
+        (5, 6, 6),
+        (6, 7, 8),
+        (6, 0, 2),
+        (8, 9, 9),
+    ] {
+        let variables = vec![
+            [("a".to_string(), a)].iter().cloned().collect(),
+            [("b".to_string(), b)].iter().cloned().collect(),
+        ];
+        let results = run_expression::<i64>(2, variables, expression.clone()).await;
+        assert_eq!(
+            results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            (0..2).map(|_| expected_result).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_equality() {
+    let expression =
+        parse::<u64>("(category == 3) * 250 + (a == b) * 1000 + (a * b == 12) + (b == a + 1)")
+            .unwrap();
+    for (category, a, b, expected_result) in [
+        (3, 3, 4, 250 + 1 + 1),
+        (2, 5, 5, 1000),
+        (4, 4, 3, 1),
+        (3, 0, 0, 1250),
+    ] {
+        let variables = vec![
+            [("category".to_string(), category)]
+                .iter()
+                .cloned()
+                .collect(),
+            [("a".to_string(), a)].iter().cloned().collect(),
+            [("b".to_string(), b)].iter().cloned().collect(),
+        ];
+        let results = run_expression::<i64>(3, variables, expression.clone()).await;
+        assert_eq!(
+            results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            (0..3).map(|_| expected_result).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_select() {
+    let expression = parse::<u64>(
+        "if age > 65 then base * 3 else (if smoker then base * 2 else base - discount)",
+    )
+    .unwrap();
+    for (age, smoker, expected_result) in [(70, 0, 300), (70, 1, 300), (30, 1, 200), (30, 0, 90)] {
+        let variables = vec![
+            [("age".to_string(), age), ("smoker".to_string(), smoker)]
+                .iter()
+                .cloned()
+                .collect(),
+            [("base".to_string(), 100), ("discount".to_string(), 10)]
+                .iter()
+                .cloned()
+                .collect(),
+        ];
+        let results = run_expression::<i64>(2, variables, expression.clone()).await;
+        assert_eq!(
+            results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            (0..2).map(|_| expected_result).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_select_aborts_on_condition_not_being_bit() {
+    let expression = parse::<u64>("if smoker then 200 else 100").unwrap();
+    let variables = vec![
+        [("smoker".to_string(), 2)].iter().cloned().collect(),
+        [].iter().cloned().collect(),
+    ];
+    let results = run_expression::<i64>(2, variables, expression).await;
+    assert!(results
+        .iter()
+        .all(|r| matches!(r, Err(ProtocolError::BitCheckFailed(_)))));
+}
+
+#[tokio::test]
+async fn test_fixed_point() {
+    for (formula, a, b, expected_result) in [
+        ("a * 1.75 + 0.5", 100, 0, "175.5"),
+        ("(a - 2.5) * 1.5 * b + 10.25", 1, 4, "1.25"),
+        ("(a * 0.5) * (b * 0.25) + a", 3, 5, "4.875"),
+        // 0.1 is not exact so product is truncated either way, both round to 0.01
+        ("(a * 0.1) * (b * 0.1)", 1, 1, "0.01"),
+        ("if a * 1.1 > 100 then 1.5 else 2", 95, 0, "1.5"),
+        ("if a * 1.1 > 100 then 1.5 else 2", 90, 0, "2"),
+    ] {
+        let expression = parse::<u64>(formula).unwrap();
+        let variables = vec![
+            [("a".to_string(), a)].iter().cloned().collect(),
+            [("b".to_string(), b)].iter().cloned().collect(),
+        ];
+        let results = run_expression::<Decimal>(2, variables, expression).await;
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|r| r.unwrap().to_string())
+                .collect::<Vec<_>>(),
+            (0..2).map(|_| expected_result).collect::<Vec<_>>(),
+            "{}",
+            formula
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_signed_results() {
+    let expression = parse::<u64>("(claims - premium) * factor").unwrap();
+    let variables = vec![
+        [("claims".to_string(), 150), ("factor".to_string(), -3)]
+            .iter()
+            .cloned()
+            .collect(),
+        [("premium".to_string(), 200)].iter().cloned().collect(),
+    ];
+    let results = run_expression::<i64>(2, variables, expression.clone()).await;
+    assert_eq!(
+        results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+        vec![150, 150]
+    );
+
+    let variables = vec![
+        [("claims".to_string(), 100), ("factor".to_string(), 3)]
+            .iter()
+            .cloned()
+            .collect(),
+        [("premium".to_string(), 200)].iter().cloned().collect(),
+    ];
+    let results = run_expression::<Decimal>(2, variables, expression).await;
+    assert_eq!(
+        results
+            .into_iter()
+            .map(|r| r.unwrap().to_string())
+            .collect::<Vec<_>>(),
+        vec!["-300", "-300"]
+    );
+}
+
+#[tokio::test]
+async fn test_wide_results() {
+    let expression = parse::<u64>("a * a * a * a").unwrap();
+    let variables = vec![
+        [("a".to_string(), 1 << 40)].iter().cloned().collect(),
+        [].iter().cloned().collect(),
+    ];
+
+    let results = run_expression::<BigUint>(2, variables.clone(), expression.clone()).await;
+    assert!(results
+        .into_iter()
+        .all(|r| r.unwrap() == BigUint::from(1u8) << 160));
+
+    // doesn't fit into i128
+    let results = run_expression::<i128>(2, variables, expression).await;
+    assert!(results
+        .iter()
+        .all(|r| matches!(r, Err(ProtocolError::InvalidOutput(_)))));
+}
+
+#[tokio::test]
+async fn test_division() {
+    let expression = parse::<u64>("(a + b) / 2 - 100 / 4").unwrap();
+    let variables = vec![
+        [("a".to_string(), 30)].iter().cloned().collect(),
+        [("b".to_string(), -50)].iter().cloned().collect(),
+    ];
+    let results = run_expression::<i64>(2, variables, expression).await;
+    assert_eq!(
+        results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+        vec![-35, -35]
+    );
+
+    // exact division is local, it is correct as dividend is a multiple of divisor
+    let expression = parse::<u64>("(a + b) /! 4 * 3 + a /! (0 - 5)").unwrap();
+    let variables = vec![
+        [("a".to_string(), 30)].iter().cloned().collect(),
+        [("b".to_string(), -50)].iter().cloned().collect(),
+    ];
+    let results = run_expression::<i64>(2, variables, expression).await;
+    assert_eq!(
+        results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+        vec![-21, -21]
+    );
+
+    for (formula, expected_result) in [
+        ("yearly / 12.0", "100"),
+        ("yearly * 1.5 / 4", "450"),
+        ("yearly / 0.75 + 0.5", "1600.5"),
+        ("-yearly / 7.0", "-171.4286"),
+    ] {
+        let expression = parse::<u64>(formula).unwrap();
+        let variables = vec![
+            [("yearly".to_string(), 1200)].iter().cloned().collect(),
+            [].iter().cloned().collect(),
+        ];
+        let results = run_expression::<Decimal>(2, variables, expression).await;
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|r| r.unwrap().to_string())
+                .collect::<Vec<_>>(),
+            vec![expected_result, expected_result],
+            "{}",
+            formula
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_integer_division_rounds_down() {
+    let dividends = vec![7, -7, 8, 0, 1, -1, 1_000_000_007, -(1 << 62) - 3];
+    let outputs =
+        parse_outputs::<u64>("half = a / 2; third = a / (0 - 3); big = a / 1000").unwrap();
+    let variables = vec![
+        [("a".to_string(), dividends.clone())].into_iter().collect(),
+        HashMap::new(),
+    ];
+
+    let results = run_nodes_batched::<i64>(
+        2,
+        dividends.len(),
+        variables,
+        outputs,
+        DEFAULT_FRACTIONAL_BITS,
+    )
+    .await;
+    for result in results {
+        let result = result.unwrap();
+        for (name, divisor) in [("half", 2), ("third", -3), ("big", 1000)] {
+            let expected = dividends
+                .iter()
+                .map(|a| floor_div(*a as i128, divisor) as i64)
+                .collect::<Vec<_>>();
+            assert_eq!(expected, result[name], "{}", name);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_power() {
+    for (formula, expected_result) in [
+        ("risk ^ 3 + 2 ^ 4", "-11"),
+        ("(risk - 1) ^ 6", "4096"),
+        ("(risk * 0.5) ^ 3", "-3.375"),
+        ("1.5 ^ 2 * risk ^ 2 + risk ^ 0", "21.25"),
+    ] {
+        let expression = parse::<u64>(formula).unwrap();
+        let variables = vec![
+            [("risk".to_string(), -3)].iter().cloned().collect(),
+            [].iter().cloned().collect(),
+        ];
+        let results = run_expression::<Decimal>(2, variables, expression).await;
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|r| r.unwrap().to_string())
+                .collect::<Vec<_>>(),
+            vec![expected_result, expected_result],
+            "{}",
+            formula
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_multiple_outputs() {
+    let outputs = parse_outputs::<u64>(
+        "premium = (age * 3 + claims * 120) * 1.5 + 500; payout = claims * limit; fee = 10",
+    )
+    .unwrap();
+    let variables = vec![
+        [("age".to_string(), 40), ("claims".to_string(), 2)]
+            .iter()
+            .cloned()
+            .collect(),
+        [("limit".to_string(), 1000)].iter().cloned().collect(),
+    ];
+    let results = run_nodes::<Decimal>(2, variables, outputs, DEFAULT_FRACTIONAL_BITS).await;
+
+    let expected = [("fee", "10"), ("payout", "2000"), ("premium", "1040")]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect::<HashMap<_, _>>();
+    for result in results {
+        let values = result
+            .unwrap()
+            .into_iter()
+            .map(|(name, value)| (name, value.to_string()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(expected, values);
+    }
+}
+
+#[tokio::test]
+async fn test_configurable_fractional_bits() {
+    let outputs = parse_outputs::<u64>("a * 0.1").unwrap();
+    let variables = vec![[("a".to_string(), 10)].into_iter().collect()];
+    for (fractional_bits, expected) in [(4, "1.3"), (DEFAULT_FRACTIONAL_BITS, "1.0001")] {
+        let results = run_nodes::<Decimal>(1, variables.clone(), outputs.clone(), fractional_bits)
+            .await
+            .into_iter()
+            .map(|r| r.unwrap()[RESULT].to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![expected], results, "{} bits", fractional_bits);
+    }
+
+    let results = run_nodes::<Decimal>(1, variables, outputs, MAX_FRACTIONAL_BITS + 1).await;
+    assert!(matches!(results[0], Err(ProtocolError::InvalidInput(_))));
+}
+
+#[tokio::test]
+async fn test_private_outputs() {
+    let outputs = parse_outputs::<u64>("total = a + b + c; quote @ 1 = (a * b - c) * 1.5").unwrap();
+    let variables = (0..3)
+        .map(|i| {
+            [(["a", "b", "c"][i].to_string(), i as i64 + 2)]
+                .iter()
+                .cloned()
+                .collect()
+        })
+        .collect::<Vec<_>>();
+    let results =
+        run_nodes::<Decimal>(3, variables.clone(), outputs, DEFAULT_FRACTIONAL_BITS).await;
+
+    let names = results
+        .into_iter()
+        .map(|r| {
+            let values = r.unwrap();
+            assert_eq!("9", values["total"].to_string());
+            if let Some(quote) = values.get("quote") {
+                assert_eq!("3", quote.to_string());
+            }
+            let mut names = values.into_keys().collect::<Vec<_>>();
+            names.sort();
+            names
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![vec!["total"], vec!["quote", "total"], vec!["total"]],
+        names
+    );
+
+    // output depending only on recipient's input and a constant output
+    let outputs = Outputs::from([
+        (
+            "own".to_string(),
+            OutputExpression {
+                expression: parse("a * a").unwrap(),
+                recipient: Some(0),
+            },
+        ),
+        (
+            "fee".to_string(),
+            OutputExpression {
+                expression: parse("10").unwrap(),
+                recipient: Some(2),
+            },
+        ),
+    ]);
+    let results = run_nodes::<i64>(3, variables, outputs, DEFAULT_FRACTIONAL_BITS).await;
+    let values = results
+        .into_iter()
+        .map(|r| r.unwrap().into_iter().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            vec![("own".to_string(), 4)],
+            vec![],
+            vec![("fee".to_string(), 10)]
+        ],
+        values
+    );
+}
+
+#[tokio::test]
+async fn test_shared_subexpressions() {
+    for (formula, expected_result) in [
+        ("let r = risk * risk in r * 3 + r", "36"),
+        ("let r = risk * risk in let q = r * r in q + r - q", "9"),
+        ("let r = risk * risk in r", "9"),
+        ("let r = risk * 0.5 in if r < 0 then -r else r", "1.5"),
+    ] {
+        let expression = parse::<u64>(formula).unwrap();
+        let variables = vec![
+            [("risk".to_string(), -3)].iter().cloned().collect(),
+            [].iter().cloned().collect(),
+        ];
+        let results = run_expression::<Decimal>(2, variables, expression).await;
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|r| r.unwrap().to_string())
+                .collect::<Vec<_>>(),
+            vec![expected_result, expected_result],
+            "{}",
+            formula
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_optimized_expressions() {
+    for (formula, expected_result) in [
+        ("a * b * c * a * 2 + 3 * 4", "76"),
+        ("((a + 1) * 2 - 4) * 3 + b * 0 + c * 1", "-14"),
+        ("let d = a * b in d * c * 1 - -d / 2", "-18"),
+        ("if a * 0 + 1 then b else c", "2"),
+    ] {
+        let expression = parse::<u64>(formula).unwrap();
+        let variables = vec![
+            [("a".to_string(), -2), ("b".to_string(), 2)]
+                .iter()
+                .cloned()
+                .collect(),
+            [("c".to_string(), 4)].iter().cloned().collect(),
+        ];
+        let results = run_expression::<Decimal>(2, variables, expression).await;
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|r| r.unwrap().to_string())
+                .collect::<Vec<_>>(),
+            vec![expected_result, expected_result],
+            "{}",
+            formula
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_matches_plaintext_evaluation() {
+    let formula = "premium = (age * 3 + claims * 120) * risk + 500; \
+                   refund @ 0 = if claims > 2 then 0 else premium_rate * 0.25; \
+                   ratio = let p = risk ^ 3 in p / 4.0 - age";
+    let outputs = parse_outputs::<u64>(formula).unwrap();
+    let variables = vec![
+        [("age".to_string(), 41), ("claims".to_string(), 2)]
+            .iter()
+            .cloned()
+            .collect(),
+        [("risk".to_string(), -3), ("premium_rate".to_string(), 1210)]
+            .iter()
+            .cloned()
+            .collect(),
+    ];
+
+    let expected = evaluate_outputs::<Elem>(&outputs, &variables, DEFAULT_FRACTIONAL_BITS)
+        .unwrap()
+        .into_iter()
+        .map(|(name, (value, fractional_bits))| {
+            (name, Decimal::decode(&value, fractional_bits).unwrap())
+        })
+        .collect::<HashMap<_, _>>();
+    assert_eq!(Decimal::from(-589), expected["premium"]);
+
+    let results = run_nodes::<Decimal>(2, variables, outputs, DEFAULT_FRACTIONAL_BITS).await;
+    for (id, result) in results.into_iter().enumerate() {
+        for (name, value) in result.unwrap() {
+            assert_eq!(expected[&name], value, "{} of node {}", name, id);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_batched_rows_match_plaintext_evaluation() {
+    let formula = "premium = (age * 3 + claims * 120) * risk + 500; \
+                   capped @ 1 = let p = age * risk in if p > 100 then 100 else p; \
+                   same = age == claims * 20 + 1";
+    let outputs = parse_outputs::<u64>(formula).unwrap();
+    let ages = vec![41, 18, 77, 30, 65];
+    let claims = vec![2, 0, 5, 1, 3];
+    let risks = vec![-3, 1, 2, 0, 4];
+    let variables = vec![
+        [("age".to_string(), ages.clone())].into_iter().collect(),
+        [("claims".to_string(), claims.clone())]
+            .into_iter()
+            .collect(),
+        [("risk".to_string(), risks.clone())].into_iter().collect(),
+    ];
+
+    let expected = (0..ages.len())
+        .map(|row| {
+            let variables = vec![
+                [("age".to_string(), ages[row])].into_iter().collect(),
+                [("claims".to_string(), claims[row])].into_iter().collect(),
+                [("risk".to_string(), risks[row])].into_iter().collect(),
+            ];
+            evaluate_outputs::<Elem>(&outputs, &variables, DEFAULT_FRACTIONAL_BITS).unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(Elem::from(1), expected[0]["same"].0);
+
+    let results =
+        run_nodes_batched::<Elem>(3, ages.len(), variables, outputs, DEFAULT_FRACTIONAL_BITS).await;
+    for (id, result) in results.into_iter().enumerate() {
+        let result = result.unwrap();
+        assert_eq!(id == 1, result.contains_key("capped"), "node {}", id);
+        for (name, values) in result {
+            assert_eq!(ages.len(), values.len());
+            for (row, value) in values.into_iter().enumerate() {
+                assert_eq!(
+                    expected[row][&name].0, value,
+                    "{} in row {} of node {}",
+                    name, row, id
+                );
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_dealer_serves_remaining_nodes_and_rejects_malformed_commands() {
+    let (senders, mut receivers): (Vec<_>, Vec<_>) = (0..2).map(|_| unbounded_channel()).unzip();
+    let (cmd_tx, cmd_rx) = unbounded_channel();
+    let dealer = TrustedDealer::new(
+        2,
+        1,
+        senders
+            .into_iter()
+            .enumerate()
+            .map(|(i, s)| (i as u64, s))
+            .collect(),
+        cmd_rx,
+    );
+    let dealer = tokio::spawn(dealer.run());
+
+    // node 1 already left the session
+    drop(receivers.pop());
+    cmd_tx
+        .send((0, DealerCommands::NodeOpenSelfInput("0-a".to_string())))
+        .unwrap();
+    cmd_tx
+        .send((1, DealerCommands::BeaverFor("0-mul".to_string())))
+        .unwrap();
+    cmd_tx
+        .send((0, DealerCommands::BeaverFor("0-mul".to_string())))
+        .unwrap();
+    let mut node_0 = receivers.pop().unwrap();
+    assert!(matches!(
+        node_0.recv().await,
+        Some(DealerEvents::NodeSelfVariable(..))
+    ));
+    assert!(matches!(
+        node_0.recv().await,
+        Some(DealerEvents::BeaverSharesFor(..))
+    ));
+
+    cmd_tx
+        .send((
+            0,
+            DealerCommands::TruncationMaskFor("0-t".to_string(), 1000),
+        ))
+        .unwrap();
+    assert!(matches!(
+        dealer.await.unwrap(),
+        Err(ProtocolError::ProtocolViolation(0, _))
+    ));
+}
+
+#[tokio::test]
+async fn test_times_out_when_party_is_silent() {
+    let n_parties = 3;
+    let outputs = parse_outputs::<u64>("a * b").unwrap();
+    let variable_values: Vec<HashMap<String, Vec<i64>>> = vec![
+        [("a".to_string(), vec![3])].into_iter().collect(),
+        [("b".to_string(), vec![4])].into_iter().collect(),
+        HashMap::new(),
+    ];
+
+    let mut networks = setup_network(n_parties);
+    let (senders, mut receivers): (Vec<_>, Vec<_>) =
+        (0..n_parties).map(|_| unbounded_channel()).unzip();
+    let (cmd_tx, cmd_rx) = unbounded_channel();
+    let dealer = TrustedDealer::new(
+        n_parties as u8,
+        1,
+        senders
+            .into_iter()
+            .enumerate()
+            .map(|(i, s)| (i as u64, s))
+            .collect(),
+        cmd_rx,
+    );
+    tokio::spawn(dealer.run());
+
+    // node 2 never runs, but its channels stay open
+    let _silent = (networks.pop(), receivers.pop());
+
+    let mut handles = vec![];
+    for (id, (network, receiver)) in networks.into_iter().zip(receivers).enumerate() {
+        let config = NodeConfig {
+            id: id as u64,
+            n_parties: n_parties as u8,
+            network,
+            dealer: (cmd_tx.clone(), receiver),
+            outputs: outputs.clone(),
+            variables: variable_owners(&variable_values),
+            our_variables: variable_values[id].clone(),
+            batch_size: 1,
+            fractional_bits: DEFAULT_FRACTIONAL_BITS,
+            timeouts: Timeouts::uniform(Duration::from_millis(500)),
+        };
+        handles.push(tokio::spawn(run_node::<_, i64>(config)));
+    }
+
+    for handle in handles {
+        match handle.await.unwrap() {
+            Err(ProtocolError::Timeout(Phase::Round, missing)) => {
+                // e and f of the multiplication
+                assert_eq!(2, missing.len());
+                assert!(missing.iter().all(|(node_id, _)| *node_id == 2));
+            }
+            result => panic!("expected timeout, got {:?}", result),
+        }
+    }
+}
+
+/// Network that sends our messages but never delivers any to our party,
+/// `dropped` closes once the party stops and drops the network
+struct StalledNetwork {
+    network: ChannelNetwork,
+    _dropped: tokio::sync::mpsc::UnboundedSender<()>,
+}
+
+#[async_trait::async_trait]
+impl Network for StalledNetwork {
+    fn send_to(&mut self, msg: NetworkMessage) {
+        self.network.send_to(msg);
+    }
+
+    async fn receive(&mut self) -> Option<NetworkMessage> {
+        futures::future::pending().await
+    }
+
+    fn broadcast(&mut self, msg: Msg) {
+        self.network.broadcast(msg);
+    }
+}
+
+#[tokio::test]
+async fn test_node_times_out_when_its_party_stalls() {
+    let n_parties = 2;
+    let outputs = parse_outputs::<u64>("a * b").unwrap();
+    let variable_values: Vec<HashMap<String, Vec<i64>>> = vec![
+        [("a".to_string(), vec![3])].into_iter().collect(),
+        [("b".to_string(), vec![4])].into_iter().collect(),
+    ];
+
+    let (senders, receivers): (Vec<_>, Vec<_>) =
+        (0..n_parties).map(|_| unbounded_channel()).unzip();
+    let (cmd_tx, cmd_rx) = unbounded_channel();
+    let dealer = TrustedDealer::new(
+        n_parties as u8,
+        1,
+        senders
+            .into_iter()
+            .enumerate()
+            .map(|(i, s)| (i as u64, s))
+            .collect(),
+        cmd_rx,
+    );
+    tokio::spawn(dealer.run());
+
+    let mut networks = setup_network(n_parties);
+    let mut receivers = receivers.into_iter();
+    // party of node 0 never hears from the network, node 0 itself is honest
+    let (dropped, mut party_stopped) = unbounded_channel::<()>();
+    let stalled = StalledNetwork {
+        network: networks.remove(0),
+        _dropped: dropped,
+    };
+    let config = NodeConfig {
+        id: 0,
+        n_parties: n_parties as u8,
+        network: stalled,
+        dealer: (cmd_tx.clone(), receivers.next().unwrap()),
+        outputs: outputs.clone(),
+        variables: variable_owners(&variable_values),
+        our_variables: variable_values[0].clone(),
+        batch_size: 1,
+        fractional_bits: DEFAULT_FRACTIONAL_BITS,
+        timeouts: Timeouts::uniform(Duration::from_millis(500)),
+    };
+    let stalled = tokio::spawn(run_node::<_, i64>(config));
+    let config = NodeConfig {
+        id: 1,
+        n_parties: n_parties as u8,
+        network: networks.remove(0),
+        dealer: (cmd_tx.clone(), receivers.next().unwrap()),
+        outputs,
+        variables: variable_owners(&variable_values),
+        our_variables: variable_values[1].clone(),
+        batch_size: 1,
+        fractional_bits: DEFAULT_FRACTIONAL_BITS,
+        timeouts: Timeouts::uniform(Duration::from_millis(500)),
+    };
+    let other = tokio::spawn(run_node::<_, i64>(config));
+
+    // our own opening of `a` never comes back to node 0
+    match stalled.await.unwrap() {
+        Err(ProtocolError::Timeout(Phase::Inputs, _)) => {}
+        result => panic!("expected timeout, got {:?}", result),
+    }
+    // party has no deadline of its own, it stops once its node gave up
+    assert!(
+        tokio::time::timeout(Duration::from_secs(1), party_stopped.recv())
+            .await
+            .is_ok()
+    );
+    assert!(other.await.unwrap().is_err());
+}
+
+/// Node 2 runs honest code over a network that tampers with messages it sends,
+/// honest nodes 0 and 1 must either abort or compute the right result.
+mod attacks {
+    use super::*;
+    use crate::ff::Field;
+    use crate::protocol::{
+        error::Offence,
+        network::Msg,
+        run_nodes_over,
+        tampering::{Attack, TamperingNetwork},
+        NodeId,
+    };
+
+    const MALICIOUS: NodeId = 2;
+
+    /// Evaluates `a * b + c` with messages of node 2 picked by `target` attacked,
+    /// returns results of all nodes
+    async fn run_attacked(
+        target: impl Fn(NodeId, &Msg) -> bool + Send + 'static,
+        attack: Attack,
+        timeouts: Timeouts,
+    ) -> Vec<Result<i64, ProtocolError>> {
+        let variable_values: Vec<HashMap<String, Vec<i64>>> = vec![
+            [("a".to_string(), vec![3])].into_iter().collect(),
+            [("b".to_string(), vec![4])].into_iter().collect(),
+            [("c".to_string(), vec![5])].into_iter().collect(),
+        ];
+        let mut networks = setup_network(3)
+            .into_iter()
+            .map(|network| TamperingNetwork::new(network, 3))
+            .collect::<Vec<_>>();
+        let malicious = networks.pop().expect("three networks");
+        networks.push(malicious.with_attack(target, attack));
+
+        run_nodes_over(
+            networks,
+            1,
+            variable_values,
+            parse_outputs("a * b + c").unwrap(),
+            DEFAULT_FRACTIONAL_BITS,
+            timeouts,
+        )
+        .await
+        .into_iter()
+        .map(|r| r.map(|mut values| values.remove(RESULT).expect("single output")[0]))
+        .collect()
+    }
+
+    fn is_share(msg: &Msg) -> bool {
+        matches!(msg, Msg::OpenShare(..))
+    }
+
+    fn add_one(msg: Msg) -> Msg {
+        match msg {
+            Msg::OpenShare(cid, shares) => Msg::OpenShare(
+                cid,
+                shares
+                    .into_iter()
+                    .map(|(value, mac)| (value + Elem::one(), mac))
+                    .collect(),
+            ),
+            Msg::Proof(cid, (comm, elems, salt)) => Msg::Proof(
+                cid,
+                (comm, elems.iter().map(|e| *e + Elem::one()).collect(), salt),
+            ),
+            msg => msg,
+        }
+    }
+
+    /// Checks that honest nodes aborted, blaming node 2 for `offence` or after other node did
+    fn assert_blamed(results: &[Result<i64, ProtocolError>], offence: fn(&Offence) -> bool) {
+        for result in results[..2].iter() {
+            match result {
+                Err(ProtocolError::Cheating(report)) => {
+                    assert!(report
+                        .iter()
+                        .all(|(node_id, o)| *node_id == MALICIOUS && offence(o)));
+                }
+                Err(ProtocolError::AbortedByPeer(_)) => {}
+                result => panic!("expected node 2 to be blamed, got {:?}", result),
+            }
+        }
+        // the first node to abort found out about cheating by itself
+        assert!(results
+            .iter()
+            .any(|result| matches!(result, Err(ProtocolError::Cheating(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_finishes_when_messages_are_delayed() {
+        let results = run_attacked(
+            |_, _| true,
+            Attack::Delay(Duration::from_millis(50)),
+            Timeouts::uniform(Duration::from_secs(5)),
+        )
+        .await;
+
+        assert!(
+            results.iter().all(|result| *result == Ok(17)),
+            "{:?}",
+            results
+        );
+    }
+
+    #[tokio::test]
+    async fn test_finishes_when_messages_are_reordered() {
+        // shares of e are sent after shares of f of the same multiplication
+        let results = run_attacked(
+            |_, msg| matches!(msg, Msg::OpenShare(cid, _) if cid.ends_with("-e")),
+            Attack::Reorder,
+            Timeouts::uniform(Duration::from_secs(5)),
+        )
+        .await;
+
+        assert!(
+            results.iter().all(|result| *result == Ok(17)),
+            "{:?}",
+            results
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aborts_on_modified_shares() {
+        let results = run_attacked(
+            |_, msg| is_share(msg),
+            Attack::Modify(Box::new(add_one)),
+            Timeouts::uniform(Duration::from_secs(5)),
+        )
+        .await;
+
+        for result in results[..2].iter() {
+            assert!(
+                matches!(
+                    result,
+                    Err(ProtocolError::MacCheckFailed(_) | ProtocolError::AbortedByPeer(_))
+                ),
+                "expected failed mac check, got {:?}",
+                result
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blames_equivocation() {
+        let results = run_attacked(
+            |to, msg| to == 0 && is_share(msg),
+            Attack::Modify(Box::new(add_one)),
+            Timeouts::uniform(Duration::from_secs(5)),
+        )
+        .await;
+
+        assert_blamed(&results, |offence| {
+            matches!(offence, Offence::Equivocation(_))
+        });
+    }
+
+    #[tokio::test]
+    async fn test_blames_forged_digests() {
+        let results = run_attacked(
+            |_, msg| matches!(msg, Msg::Digests(..)),
+            Attack::Modify(Box::new(|msg| match msg {
+                Msg::Digests(cid, digests) => {
+                    Msg::Digests(cid, digests.iter().map(|_| [7; 32]).collect())
+                }
+                msg => msg,
+            })),
+            Timeouts::uniform(Duration::from_secs(5)),
+        )
+        .await;
+
+        assert_blamed(&results, |offence| {
+            matches!(offence, Offence::ForgedDigests(_))
+        });
+    }
+
+    #[tokio::test]
+    async fn test_blames_commitment_mismatch() {
+        let results = run_attacked(
+            |_, msg| matches!(msg, Msg::Proof(..)),
+            Attack::Modify(Box::new(add_one)),
+            Timeouts::uniform(Duration::from_secs(5)),
+        )
+        .await;
+
+        assert_blamed(&results, |offence| {
+            matches!(offence, Offence::CommitmentMismatch(_))
+        });
+    }
+
+    #[tokio::test]
+    async fn test_blames_double_send() {
+        let results = run_attacked(
+            |_, msg| is_share(msg),
+            Attack::Duplicate,
+            Timeouts::uniform(Duration::from_secs(5)),
+        )
+        .await;
+
+        for result in results[..2].iter() {
+            match result {
+                Err(ProtocolError::Cheating(report)) => {
+                    assert!(matches!(report[..], [(MALICIOUS, Offence::DoubleSend(_))]));
+                }
+                result => panic!("expected double send, got {:?}", result),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_times_out_on_dropped_messages() {
+        let results = run_attacked(
+            |to, msg| to == 0 && is_share(msg),
+            Attack::Drop,
+            Timeouts::uniform(Duration::from_millis(500)),
+        )
+        .await;
+
+        match &results[0] {
+            Err(ProtocolError::Timeout(Phase::Round, missing)) => {
+                assert!(!missing.is_empty());
+                assert!(
+                    missing.iter().all(|(node_id, _)| *node_id == MALICIOUS),
+                    "{:?}",
+                    missing
+                );
+            }
+            result => panic!("expected timeout, got {:?}", result),
+        }
+        assert!(results[1].is_err());
+    }
+}
+
+/// Every message takes the same time to arrive on paused clock, so the time a session takes
+/// counts communication rounds on its critical path.
+mod rounds {
+    use super::*;
+    use crate::protocol::{
+        run_nodes_over,
+        tampering::{Attack, TamperingNetwork},
+    };
+    use tokio::time::Instant;
+
+    const DELAY: Duration = Duration::from_secs(1);
+
+    /// Number of rounds of evaluating `formula` by two nodes
+    async fn rounds(formula: &str) -> u64 {
+        let variable_values: Vec<HashMap<String, Vec<i64>>> = vec![
+            [("a".to_string(), vec![3]), ("c".to_string(), vec![-2])]
+                .into_iter()
+                .collect(),
+            [("b".to_string(), vec![4]), ("d".to_string(), vec![5])]
+                .into_iter()
+                .collect(),
+        ];
+        let networks = setup_network(2)
+            .into_iter()
+            .map(|network| {
+                TamperingNetwork::new(network, 2).with_attack(|_, _| true, Attack::Delay(DELAY))
+            })
+            .collect();
+
+        let start = Instant::now();
+        let results = run_nodes_over::<_, i64>(
+            networks,
+            1,
+            variable_values,
+            parse_outputs(formula).unwrap(),
+            DEFAULT_FRACTIONAL_BITS,
+            Timeouts::default(),
+        )
+        .await;
+        assert!(results.iter().all(|result| result.is_ok()), "{:?}", results);
+
+        start.elapsed().as_secs()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_independent_comparisons_share_rounds() {
+        let one = rounds("a < b").await;
+        let two = rounds("(a < b) + (c < d)").await;
+        let products = rounds("a * b + c * d").await;
+
+        assert_eq!(one, two);
+        // opening of masked value and prefix OR of 64 bits instead of single multiplication
+        assert_eq!(products + 6, two);
+    }
+}
+
+/// Random integer circuits evaluated by random number of parties are compared with
+/// plaintext evaluation in the field. Decimals are left out as truncation rounds at random.
+mod properties {
+    use super::*;
+    use crate::expressions::{eval::evaluate, BinaryOp};
+    use crate::protocol::{expression::decorate_expression, variable_owners, Provider};
+    use proptest::prelude::*;
+
+    const VARIABLES: usize = 4;
+
+    fn variable(i: usize) -> Expression<u64> {
+        Expression::Variable {
+            name: format!("v{}", i),
+        }
+    }
+
+    fn bin_op(left: Expression<u64>, right: Expression<u64>, op: BinaryOp) -> Expression<u64> {
+        Expression::BinOp {
+            left: Box::new(left),
+            right: Box::new(right),
+            op,
+        }
+    }
+
+    fn leaf() -> impl Strategy<Value = Expression<u64>> {
+        prop_oneof![
+            (0u64..100).prop_map(|number| Expression::Number { number }),
+            (0..VARIABLES).prop_map(variable),
+        ]
+    }
+
+    /// Comparison of leaves, so that operands are in range where comparisons are correct
+    fn comparison() -> impl Strategy<Value = Expression<u64>> {
+        (
+            leaf(),
+            leaf(),
+            prop_oneof![
+                Just(BinaryOp::LessThan),
+                Just(BinaryOp::GreaterThan),
+                Just(BinaryOp::Eq)
+            ],
+        )
+            .prop_map(|(left, right, op)| bin_op(left, right, op))
+    }
+
+    fn expression() -> impl Strategy<Value = Expression<u64>> {
+        let arithmetic = prop_oneof![
+            Just(BinaryOp::Add),
+            Just(BinaryOp::Sub),
+            Just(BinaryOp::Mul)
+        ];
+
+        leaf().prop_recursive(4, 24, 2, move |inner| {
+            prop_oneof![
+                4 => (inner.clone(), inner.clone(), arithmetic.clone())
+                    .prop_map(|(left, right, op)| bin_op(left, right, op)),
+                1 => inner.clone().prop_map(|expr| Expression::Neg { expr: Box::new(expr) }),
+                1 => (inner.clone(), 2u32..4).prop_map(|(base, exponent)| Expression::Pow {
+                    base: Box::new(base),
+                    exponent,
+                }),
+                // dividends are leaves, so that they are in range where division is correct
+                1 => (leaf(), 1u64..10).prop_map(|(left, number)| {
+                    bin_op(left, Expression::Number { number }, BinaryOp::Div)
+                }),
+                1 => comparison(),
+                1 => (comparison(), inner.clone(), inner.clone()).prop_map(
+                    |(cond, then, otherwise)| Expression::Select {
+                        cond: Box::new(cond),
+                        then: Box::new(then),
+                        otherwise: Box::new(otherwise),
+                    }
+                ),
+                1 => (inner.clone(), inner).prop_map(|(value, rest)| {
+                    let t = || Expression::Variable { name: "t".to_string() };
+                    Expression::Let {
+                        name: "t".to_string(),
+                        value: Box::new(value),
+                        body: Box::new(bin_op(bin_op(t(), t(), BinaryOp::Mul), rest, BinaryOp::Add)),
+                    }
+                }),
+            ]
+        })
+    }
+
+    /// Values of variables split between random number of parties
+    fn variables() -> impl Strategy<Value = Vec<HashMap<String, i64>>> {
+        (1usize..=4).prop_flat_map(|n_parties| {
+            (
+                proptest::collection::vec(0..n_parties, VARIABLES),
+                proptest::collection::vec(-1000i64..1000, VARIABLES),
+            )
+                .prop_map(move |(owners, values)| {
+                    let mut variables = vec![HashMap::new(); n_parties];
+                    for (i, (owner, value)) in owners.into_iter().zip(values).enumerate() {
+                        variables[owner].insert(format!("v{}", i), value);
+                    }
+                    variables
+                })
+        })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn results_match_plaintext(expression in expression(), variables in variables()) {
+            // circuits rejected before running are not compared
+            let mut provider = Provider::new(0, variable_owners(&variables));
+            prop_assume!(decorate_expression(expression.clone(), &mut provider).is_ok());
+            let (expected, _) =
+                evaluate::<Elem>(&expression, &variables, DEFAULT_FRACTIONAL_BITS).unwrap();
+
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let results = runtime.block_on(run_expression::<Elem>(
+                variables.len() as u32,
+                variables,
+                expression,
+            ));
+
+            for result in results {
+                prop_assert_eq!(expected, result.unwrap());
+            }
+        }
+    }
+}
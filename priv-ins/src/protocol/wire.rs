@@ -0,0 +1,169 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+
+/// Version of the encoding, first byte of every encoded message
+pub const WIRE_VERSION: u8 = 1;
+
+/// Largest encoded message, bounds allocations caused by forged lengths
+pub const MAX_MESSAGE_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// message has no bytes at all
+    Empty,
+    /// message is encoded with another version of the format
+    UnsupportedVersion(u8),
+    /// value can't be encoded or bytes don't encode a valid value,
+    /// e.g. have a non-canonical field element or trailing bytes
+    Invalid(String),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Empty => write!(f, "empty message"),
+            WireError::UnsupportedVersion(version) => {
+                write!(f, "unsupported wire version {}", version)
+            }
+            WireError::Invalid(reason) => write!(f, "invalid message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// Little endian bincode with variable length integers, rejecting trailing bytes
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_BYTES)
+}
+
+/// Encodes value as the version byte followed by its bincode, field elements are encoded
+/// canonically as 32 bytes of their representation
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, WireError> {
+    let mut bytes = vec![WIRE_VERSION];
+    options()
+        .serialize_into(&mut bytes, value)
+        .map_err(|e| WireError::Invalid(e.to_string()))?;
+
+    Ok(bytes)
+}
+
+/// Decodes value encoded by `encode` with the same version
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, WireError> {
+    match bytes.split_first() {
+        None => Err(WireError::Empty),
+        Some((&WIRE_VERSION, body)) => options()
+            .deserialize(body)
+            .map_err(|e| WireError::Invalid(e.to_string())),
+        Some((version, _)) => Err(WireError::UnsupportedVersion(*version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::shares::Elem;
+    use crate::ff::{Field, PrimeField};
+    use crate::protocol::{network::Msg, DealerCommands, DealerEvents};
+    use std::fmt::Debug;
+
+    fn assert_round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: T) {
+        let bytes = encode(&value).unwrap();
+        assert_eq!(Ok(value), decode(&bytes));
+    }
+
+    fn elem(n: u64) -> Elem {
+        Elem::from(n)
+    }
+
+    #[test]
+    fn round_trips_network_messages() {
+        let cid = "3-e".to_string();
+        let share = (elem(1), -elem(2));
+
+        assert_round_trip(Msg::OpenShare(cid.clone(), vec![share, share]));
+        assert_round_trip(Msg::OpenVariable(cid.clone(), vec![elem(7)]));
+        assert_round_trip(Msg::Commit(cid.clone(), [5; 32]));
+        assert_round_trip(Msg::Proof(
+            cid.clone(),
+            ([5; 32], vec![elem(3)], vec![1, 2]),
+        ));
+        assert_round_trip(Msg::ProofInvalid(cid.clone()));
+        assert_round_trip(Msg::ProofValid(cid.clone()));
+        assert_round_trip(Msg::Digests(cid, vec![[1; 32], [2; 32]]));
+    }
+
+    #[test]
+    fn round_trips_dealer_messages() {
+        let cid = "x".to_string();
+        let share = (elem(4), elem(5));
+
+        assert_round_trip(DealerCommands::NodeOpenSelfInput(cid.clone()));
+        assert_round_trip(DealerCommands::BeaverFor(cid.clone()));
+        assert_round_trip(DealerCommands::BitMaskFor(cid.clone(), 64));
+        assert_round_trip(DealerCommands::RandomFor(cid.clone()));
+        assert_round_trip(DealerCommands::TruncationMaskFor(cid.clone(), 16));
+        assert_round_trip(DealerCommands::NeedAlpha);
+
+        assert_round_trip(DealerEvents::NodeSelfVariable(
+            cid.clone(),
+            vec![elem(1)],
+            vec![share],
+        ));
+        assert_round_trip(DealerEvents::NodeVariableShared(cid.clone(), vec![share]));
+        assert_round_trip(DealerEvents::BeaverSharesFor(
+            cid.clone(),
+            vec![(share, share, share)],
+        ));
+        assert_round_trip(DealerEvents::BitMaskSharesFor(
+            cid.clone(),
+            vec![(vec![share, share], share)],
+        ));
+        assert_round_trip(DealerEvents::RandomShareFor(cid.clone(), vec![share]));
+        assert_round_trip(DealerEvents::TruncationMaskSharesFor(
+            cid,
+            vec![(share, share)],
+        ));
+        assert_round_trip(DealerEvents::Alpha(-elem(1)));
+    }
+
+    #[test]
+    fn encodes_share_compactly() {
+        let msg = Msg::OpenShare("3-e".to_string(), vec![(elem(1), elem(2))]);
+
+        // version, variant, length and bytes of id, number of rows and two elements
+        assert_eq!(1 + 1 + 1 + 3 + 1 + 2 * 32, encode(&msg).unwrap().len());
+    }
+
+    #[test]
+    fn rejects_non_canonical_elements() {
+        let mut bytes = encode(&DealerEvents::Alpha(Elem::zero())).unwrap();
+        // p - 1 ends with byte 0, so adding one there gives the modulus itself
+        let mut modulus = (-Elem::one()).to_repr().0;
+        modulus[0] += 1;
+        let start = bytes.len() - 32;
+        bytes[start..].copy_from_slice(&modulus);
+
+        match decode::<DealerEvents>(&bytes) {
+            Err(WireError::Invalid(reason)) => assert!(reason.contains("non-canonical")),
+            result => panic!("expected non-canonical element, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_other_versions_and_trailing_bytes() {
+        let mut bytes = encode(&Msg::ProofValid("check".to_string())).unwrap();
+
+        assert_eq!(Err(WireError::Empty), decode::<Msg>(&[]));
+
+        bytes.push(0);
+        assert!(matches!(decode::<Msg>(&bytes), Err(WireError::Invalid(_))));
+
+        bytes[0] = WIRE_VERSION + 1;
+        assert_eq!(
+            Err(WireError::UnsupportedVersion(WIRE_VERSION + 1)),
+            decode::<Msg>(&bytes)
+        );
+    }
+}
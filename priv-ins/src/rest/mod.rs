@@ -1,10 +1,21 @@
-use crate::expressions::{BinaryOp, Expression};
-use crate::protocol::run_nodes;
+use crate::crypto::shares::Elem;
+use crate::expressions::{
+    decimal::Decimal, eval::evaluate_outputs, parser, BinaryOp, Expression, OutputExpression,
+    Outputs,
+};
+use crate::protocol::{
+    check_fractional_bits,
+    cost::estimate_outputs,
+    output::Output,
+    run_nodes, run_nodes_over,
+    tcp::{setup_tcp_network, Retries},
+    variable_owners, Timeouts, DEFAULT_FRACTIONAL_BITS,
+};
 use num_traits::Num;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, net::SocketAddr};
 use tide::log::{log, Level};
-use tide::{Body, Request};
+use tide::{Body, Request, StatusCode};
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct SuccessMsg {
@@ -14,7 +25,17 @@ pub struct SuccessMsg {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct VariableConfig {
-    nodes: Vec<HashMap<String, u64>>,
+    nodes: Vec<HashMap<String, i64>>,
+    /// addresses parties listen on, without them parties talk over in-process channels
+    #[serde(default)]
+    addresses: Vec<SocketAddr>,
+    /// fractional bits of decimals in formulas, at most `MAX_FRACTIONAL_BITS`
+    #[serde(default = "default_fractional_bits")]
+    fractional_bits: u32,
+}
+
+fn default_fractional_bits() -> u32 {
+    DEFAULT_FRACTIONAL_BITS
 }
 
 pub(crate) fn translate_string_to_map(input: String) -> HashMap<String, String> {
@@ -31,13 +52,10 @@ pub(crate) fn translate_string_to_map(input: String) -> HashMap<String, String>
         result.insert(name, value);
     }
 
-    return result;
+    result
 }
 
-pub(crate) fn get_expression<T>(
-    map: HashMap<String, String>,
-    key: String,
-) -> Expression<T>
+pub(crate) fn get_expression<T>(map: HashMap<String, String>, key: String) -> Expression<T>
 where
     T: Num + std::str::FromStr,
     T::Err: std::fmt::Debug,
@@ -45,29 +63,35 @@ where
     log!(Level::Debug, "Key {:?}", key);
 
     if map[&key] == "Number" {
-        return Expression::Number {
+        Expression::Number {
             number: map[&(key + "/number")].parse::<T>().unwrap(),
-        };
+        }
     } else if map[&key] == "Variable" {
         let name = map[&(key.clone() + "/variable/var")].clone();
 
-        return Expression::Variable { name: name };
+        Expression::Variable { name }
     } else {
-        let op = if map[&(key.clone() + "/op")] == "Sum" {
-            BinaryOp::Add
-        } else {
-            BinaryOp::Mul
+        let op = match map[&(key.clone() + "/op")].as_str() {
+            "Sum" => BinaryOp::Add,
+            "Sub" => BinaryOp::Sub,
+            "Div" => BinaryOp::Div,
+            "ExactDiv" => BinaryOp::ExactDiv,
+            "LessThan" => BinaryOp::LessThan,
+            "GreaterThan" => BinaryOp::GreaterThan,
+            "Eq" => BinaryOp::Eq,
+            _ => BinaryOp::Mul,
         };
 
-        return Expression::BinOp {
+        Expression::BinOp {
             left: Box::new(get_expression(map.clone(), key.clone() + "/left")),
             right: Box::new(get_expression(map, key + "/right")),
-            op: op,
-        };
+            op,
+        }
     }
 }
 
-pub(crate) async fn expression(mut req: Request<()>) -> tide::Result<Body> {
+/// Reads number of parties and outputs to compute from the form
+async fn read_outputs(req: &mut Request<()>) -> tide::Result<(u32, Outputs<u64>)> {
     let form_data = req.body_string().await?;
     log!(Level::Debug, "got {:?}", form_data);
     let map = translate_string_to_map(form_data);
@@ -76,18 +100,121 @@ pub(crate) async fn expression(mut req: Request<()>) -> tide::Result<Body> {
         .unwrap()
         .parse()
         .unwrap();
-    let expr = get_expression::<u64>(map, "expression".to_string());
+    let outputs = match map.get("formula") {
+        Some(formula) => parser::parse_outputs::<u64>(formula)
+            .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, e.to_string()))?,
+        None => {
+            let expr = get_expression::<u64>(map, "expression".to_string());
+            Outputs::from([(parser::RESULT.to_string(), OutputExpression::public(expr))])
+        }
+    };
+
+    Ok((n_parties, outputs))
+}
 
+fn read_variable_config() -> tide::Result<VariableConfig> {
     let variable_config: VariableConfig = serde_json::from_str(
         &fs::read_to_string("variables_config.json")
             .expect("Unable to read config file containing peer addresses"),
     )
     .expect("JSON was not well-formatted");
     println!("{:?}", variable_config);
+    check_fractional_bits(variable_config.fractional_bits)
+        .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, e))?;
+
+    Ok(variable_config)
+}
 
-    run_nodes(n_parties, variable_config.nodes, expr).await;
+pub(crate) async fn expression(mut req: Request<()>) -> tide::Result<Body> {
+    let (n_parties, outputs) = read_outputs(&mut req).await?;
+    let variable_config = read_variable_config()?;
+
+    let results = if variable_config.addresses.is_empty() {
+        run_nodes::<Decimal>(
+            n_parties,
+            variable_config.nodes,
+            outputs,
+            variable_config.fractional_bits,
+        )
+        .await
+        .into_iter()
+        .map(|result| result.map(|_| ()))
+        .collect::<Vec<_>>()
+    } else {
+        if variable_config.addresses.len() != n_parties as usize {
+            return Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                "every party needs an address",
+            ));
+        }
+        let networks = setup_tcp_network(&variable_config.addresses, Retries::default()).await?;
+        let variable_values = variable_config
+            .nodes
+            .into_iter()
+            .map(|values| {
+                values
+                    .into_iter()
+                    .map(|(name, value)| (name, vec![value]))
+                    .collect()
+            })
+            .collect();
+        run_nodes_over::<_, Decimal>(
+            networks,
+            1,
+            variable_values,
+            outputs,
+            variable_config.fractional_bits,
+            Timeouts::default(),
+        )
+        .await
+        .into_iter()
+        .map(|result| result.map(|_| ()))
+        .collect()
+    };
+    for result in results {
+        result
+            .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, e.to_string()))?;
+    }
 
     Body::from_json(&SuccessMsg {
         msg: String::from("Nice"),
     })
 }
+
+/// Estimates cost of computing the same form as `expression` without running the protocol
+pub(crate) async fn cost(mut req: Request<()>) -> tide::Result<Body> {
+    let (n_parties, outputs) = read_outputs(&mut req).await?;
+    let variable_config = read_variable_config()?;
+
+    let cost = estimate_outputs(
+        outputs,
+        variable_owners(&variable_config.nodes),
+        n_parties as u64,
+        variable_config.fractional_bits,
+    )
+    .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, e))?;
+
+    Body::from_json(&cost)
+}
+
+/// Computes outputs of the same form as `expression` in plaintext, without running the protocol
+pub(crate) async fn dry_run(mut req: Request<()>) -> tide::Result<Body> {
+    let (_, outputs) = read_outputs(&mut req).await?;
+    let variable_config = read_variable_config()?;
+
+    let bad_request = |e| tide::Error::from_str(StatusCode::BadRequest, e);
+    let values = evaluate_outputs::<Elem>(
+        &outputs,
+        &variable_config.nodes,
+        variable_config.fractional_bits,
+    )
+    .map_err(bad_request)?
+    .into_iter()
+    .map(|(name, (value, fractional_bits))| {
+        Decimal::decode(&value, fractional_bits).map(|value| (name, value))
+    })
+    .collect::<Result<HashMap<_, _>, _>>()
+    .map_err(bad_request)?;
+
+    Body::from_json(&values)
+}